@@ -1,18 +1,31 @@
 use crate::Config;
 use crate::db;
 use crate::fr;
+use crate::media;
 use crate::site;
 use crate::site::Post;
 use crate::util;
 use crate::util::{ErrOrigin, PlainchantErr, URL, unwrap_or_return};
+use crate::zip;
 use rand::Rng;
 use std::collections::{HashMap, HashSet};
 use std::iter;
-use std::sync::RwLock;
+use std::mem;
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard, RwLock};
 
 const TRIPCODE_LEN: usize = 10;
 const ORIG_COOLDOWN: u64 = 600;
 const REPLY_COOLDOWN: u64 = 15;
+const ZIP_COOLDOWN: u64 = 60;
+// Filtered catalogs are rendered on every request rather than cached
+const FILTER_COOLDOWN: u64 = 3;
+// Threads whose files add up to more than 256 MiB cannot be downloaded together
+const ZIP_MAX_SIZE: u64 = 268_435_456;
+// Uploading a banned file may get the uploader banned for as long as a console ban
+const AUTO_BAN_LENGTH: u64 = 300_000_000;
+// Rack files younger than this may belong to a post that is still being submitted
+const FSCK_GRACE_PERIOD: u64 = 3600;
 
 fn compute_tripcode(trip: String) -> String {
     (sha256::digest(trip)[..TRIPCODE_LEN]).to_string()
@@ -28,11 +41,30 @@ fn actions_err(msg: &str) -> PlainchantErr {
 
 pub struct Actions {
     ban_cache:        RwLock<HashMap<String, site::Ban>>,
+    banned_files:     RwLock<Vec<site::BannedFile>>,
     orig_cooldown:    RwLock<HashMap<String, u64>>,
     reply_cooldown:   RwLock<HashMap<String, u64>>,
+    zip_cooldown:     RwLock<HashMap<String, u64>>,
+    filter_cooldown:  RwLock<HashMap<String, u64>>,
     board_urls:       HashMap<String, u64>,
     board_ids:        HashMap<u64, String>,
     domain_whitelist: HashSet<String>,
+    // Held while files are committed to or deleted from the rack alongside the database,
+    // and while a backup copies the database. Files are shared between posts, so this
+    // stops one post's file from being deleted just as another post referencing it is
+    // committed, and ensures that every file referenced by a backed up database is in
+    // the rack.
+    rack_lock:        Mutex<()>,
+    // Held for the whole of a backup, so that only one runs at a time
+    backup_lock:      Mutex<()>,
+    backup_files:     Mutex<BackupFiles>,
+}
+
+// The files a running backup is copying, and the deletions of them waiting for it to finish
+#[derive(Default)]
+struct BackupFiles {
+    file_ids: HashSet<String>,
+    deferred: HashSet<String>,
 }
 
 pub enum SubmissionResult {
@@ -41,9 +73,126 @@ pub enum SubmissionResult {
     Cooldown,
     MayNotBeEmpty,
     BadContent,
+    BadFile,
+    BannedFile,
+    ImageTooLarge,
+    TooManyFiles,
     NotAcceptingReplies,
 }
 
+// How fsck should deal with the problems it finds
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum FsckMode {
+    // Only report problems
+    Dry,
+    // Fix reply and file reference counts, delete orphaned files, and unlink missing
+    // files from their posts
+    Repair,
+    // As Repair, but delete the posts whose files are missing instead
+    Delete,
+}
+
+pub struct ThumbnailReport {
+    pub regenerated: usize,
+    pub failed:      Vec<String>,
+}
+
+// A file to go in a thread's zip, under the name it is given there
+pub struct ZipFile {
+    pub name:     String,
+    pub file_id:  String,
+    pub modified: u64,
+}
+
+pub enum ThreadZipResult {
+    Ready(Vec<ZipFile>),
+    Cooldown,
+    TooLarge,
+}
+
+// Files are named after their post, and keep their uploaded names where they have them
+fn zip_file_name(post_num: u64, position: usize, count: usize, file: &site::PostFile) -> String {
+    let name = match &file.file_name {
+        Some(file_name) => file_name
+            .chars()
+            .map(|c| {
+                if c.is_control() || "/\\:*?\"<>|".contains(c) {
+                    '_'
+                } else {
+                    c
+                }
+            })
+            .collect(),
+        None => {
+            let file_id = &file.file_id[..file.file_id.len().min(12)];
+            match &file.file_info {
+                Some(info) => format!("{}.{}", file_id, info.format.to_lowercase()),
+                None => file_id.to_string(),
+            }
+        },
+    };
+
+    if count > 1 {
+        format!("{}-{}_{}", post_num, position + 1, name)
+    } else {
+        format!("{}_{}", post_num, name)
+    }
+}
+
+pub struct LayoutReport {
+    pub migrated: usize,
+    pub failed:   Vec<String>,
+}
+
+pub struct FsckReport {
+    pub missing_files:  Vec<db::FileRef>,
+    pub miscounted:     Vec<db::ReplyCounts>,
+    pub misreferenced:  Vec<db::FileRefCount>,
+    pub orphaned_files: Vec<String>,
+}
+
+// A file as it was uploaded with a post
+pub struct Upload {
+    pub file:      bytes::Bytes,
+    pub file_name: Option<String>,
+    pub spoiler:   bool,
+    pub alt_text:  Option<String>,
+}
+
+// An upload held in the rack's staging area until its post is accepted
+struct StagedFile {
+    stage_id:  String,
+    file_id:   String,
+    file_name: Option<String>,
+    spoiler:   bool,
+    alt_text:  Option<String>,
+    info:      site::FileInfo,
+}
+
+impl StagedFile {
+    fn post_file(&self, config: &media::ThumbnailConfig, kind: media::ThumbKind) -> site::PostFile {
+        site::PostFile {
+            file_id:   self.file_id.clone(),
+            file_name: self.file_name.clone(),
+            file_info: Some(post_file_info(&self.info, config, kind)),
+            spoiler:   self.spoiler,
+            alt_text:  self.alt_text.clone(),
+        }
+    }
+}
+
+// Either the staged files of a post, or the reason they were rejected
+type Staging = Result<Vec<StagedFile>, SubmissionResult>;
+
+// Errors in the 4xx range mean that an upload itself is unacceptable
+fn reject_upload(err: PlainchantErr) -> Result<Staging, PlainchantErr> {
+    match err.code {
+        422 => Ok(Err(SubmissionResult::ImageTooLarge)),
+        400..500 => Ok(Err(SubmissionResult::BadFile)),
+        _ => Err(err),
+    }
+}
+
 fn is_within_cooldown(
     cooldown: &RwLock<HashMap<String, u64>>,
     ip: &str,
@@ -74,6 +223,21 @@ fn set_cooldown_time(
     Ok(())
 }
 
+// A file's info as recorded on a post, with the size of that post's kind of thumbnail
+fn post_file_info(
+    info: &site::FileInfo,
+    config: &media::ThumbnailConfig,
+    kind: media::ThumbKind,
+) -> site::FileInfo {
+    let (thumb_width, thumb_height) =
+        media::thumb_dimensions(info.width, info.height, config.size(kind));
+    site::FileInfo {
+        thumb_width,
+        thumb_height,
+        ..info.clone()
+    }
+}
+
 fn none_or_empty(s: &Option<String>) -> bool {
     match s {
         Some(str) => str.trim().is_empty(),
@@ -94,6 +258,8 @@ impl Actions {
             }
         }
 
+        let banned_files = database.get_banned_files()?;
+
         let mut board_urls = HashMap::new();
         let mut board_ids = HashMap::new();
         for board in database.get_boards()? {
@@ -110,11 +276,17 @@ impl Actions {
 
         Ok(Actions {
             ban_cache: RwLock::new(ban_cache),
+            banned_files: RwLock::new(banned_files),
             orig_cooldown: RwLock::new(HashMap::new()),
             reply_cooldown: RwLock::new(HashMap::new()),
+            zip_cooldown: RwLock::new(HashMap::new()),
+            filter_cooldown: RwLock::new(HashMap::new()),
             board_urls,
             board_ids,
             domain_whitelist,
+            rack_lock: Mutex::new(()),
+            backup_lock: Mutex::new(()),
+            backup_files: Mutex::new(BackupFiles::default()),
         })
     }
 
@@ -172,21 +344,216 @@ impl Actions {
         Ok(())
     }
 
-    pub fn upload_file<FR: fr::FileRack>(
+    // Files match a ban if they are identical, or if both are images which look alike
+    pub fn is_banned_file(
         &self,
+        file: &bytes::Bytes,
+        uploads: &media::UploadConfig,
+    ) -> Result<bool, PlainchantErr> {
+        let rg = unwrap_or_return!(
+            self.banned_files.read(),
+            Err(actions_err("Failed to read from Banned Files"))
+        );
+
+        if rg.is_empty() {
+            return Ok(false);
+        }
+
+        let file_hash = fr::content_id(file);
+        if rg.iter().any(|banned| banned.file_hash == file_hash) {
+            return Ok(true);
+        }
+
+        // Decoding is comparatively expensive, so only do it when it could matter
+        if !rg.iter().any(|banned| banned.perceptual_hash.is_some()) {
+            return Ok(false);
+        }
+
+        Ok(
+            match media::perceptual_hash(file, uploads.decode_limits()) {
+                Some(hash) => rg.iter().any(|banned| {
+                    banned.perceptual_hash.is_some_and(|banned| {
+                        media::hash_distance(banned, hash) <= uploads.ban_distance
+                    })
+                }),
+                None => false,
+            },
+        )
+    }
+
+    // Ban the files attached to a post, so that they cannot be uploaded again
+    pub fn ban_file<DB: db::Database, FR: fr::FileRack>(
+        &self,
+        database: &DB,
         file_rack: &FR,
-        file: bytes::Bytes,
-    ) -> Result<String, util::PlainchantErr> {
+        config: &Config,
+        board_id: u64,
+        post_num: u64,
+    ) -> Result<Vec<site::BannedFile>, PlainchantErr> {
+        let post = database.get_post(board_id, post_num)?;
+        if post.files().is_empty() {
+            return Err(PlainchantErr {
+                origin: ErrOrigin::Actions,
+                code:   404,
+                msg:    String::from("Post has no file"),
+            });
+        }
+
+        let mut banned_files = vec![];
+        for post_file in post.files() {
+            // Files stored before content addressing are not named by their hash
+            let file = file_rack.get_file(&post_file.file_id)?;
+
+            banned_files.push(site::BannedFile {
+                file_hash:       fr::content_id(&file),
+                perceptual_hash: media::perceptual_hash(&file, config.uploads.decode_limits()),
+                time_banned:     util::timestamp(),
+            });
+        }
+
+        let mut wg = unwrap_or_return!(
+            self.banned_files.write(),
+            Err(actions_err("Failed to write to Banned Files"))
+        );
+
+        for banned in &banned_files {
+            database.create_banned_file(banned.clone())?;
+
+            wg.retain(|b| b.file_hash != banned.file_hash);
+            wg.push(banned.clone());
+        }
+
+        Ok(banned_files)
+    }
+
+    pub fn unban_file<DB: db::Database>(
+        &self,
+        database: &DB,
+        file_hash: &str,
+    ) -> Result<(), PlainchantErr> {
+        database.delete_banned_file(file_hash)?;
+
+        let mut wg = unwrap_or_return!(
+            self.banned_files.write(),
+            Err(actions_err("Failed to write to Banned Files"))
+        );
+
+        wg.retain(|banned| banned.file_hash != file_hash);
+
+        Ok(())
+    }
+
+    fn reject_banned_file<DB: db::Database>(
+        &self,
+        database: &DB,
+        config: &Config,
+        ip: &str,
+    ) -> Result<SubmissionResult, PlainchantErr> {
+        if config.uploads.auto_ban_ip {
+            self.ban_ip(database, ip, AUTO_BAN_LENGTH)?;
+        }
+
+        Ok(SubmissionResult::BannedFile)
+    }
+
+    fn rack_guard(&self) -> Result<MutexGuard<'_, ()>, util::PlainchantErr> {
+        self.rack_lock
+            .lock()
+            .map_err(|_| actions_err("Failed to acquire Rack Lock"))
+    }
+
+    fn backup_files(&self) -> Result<MutexGuard<'_, BackupFiles>, util::PlainchantErr> {
+        self.backup_files
+            .lock()
+            .map_err(|_| actions_err("Failed to acquire Backup Files Lock"))
+    }
+
+    // Delete a file from the rack, unless a backup is still copying it, in which case the
+    // backup deletes it once done. Callers hold the rack guard.
+    fn delete_rack_file<FR: fr::FileRack>(
+        &self,
+        file_rack: &FR,
+        file_id: &str,
+    ) -> Result<(), util::PlainchantErr> {
+        let mut backup_files = self.backup_files()?;
+        if backup_files.file_ids.contains(file_id) {
+            backup_files.deferred.insert(file_id.to_string());
+            return Ok(());
+        }
+
+        file_rack.delete_file(file_id)
+    }
+
+    // Store an uploaded file provisionally. Each upload is staged under a fresh ID, as
+    // identical files may be uploaded at the same time, but is committed under its hash.
+    // Errors in the 4xx range mean the file itself is unacceptable.
+    fn stage_file<FR: fr::FileRack>(
+        &self,
+        file_rack: &FR,
+        upload: Upload,
+    ) -> Result<StagedFile, util::PlainchantErr> {
         let mut rng = rand::thread_rng();
-        let file_id: String = iter::repeat(())
+        let stage_id: String = iter::repeat(())
             .map(|()| rng.sample(rand::distributions::Alphanumeric) as char)
             .take(12)
             .collect();
 
-        file_rack.store_file(&file_id, file)?;
-        Ok(file_id)
+        let file_id = fr::content_id(&upload.file);
+
+        let info = file_rack.stage_file(&stage_id, upload.file)?;
+        Ok(StagedFile {
+            stage_id,
+            file_id,
+            file_name: upload.file_name,
+            spoiler: upload.spoiler,
+            alt_text: upload.alt_text,
+            info,
+        })
+    }
+
+    // Check every file of a post before staging any of them, so that a rejected post
+    // leaves nothing behind in the rack
+    fn stage_uploads<DB: db::Database, FR: fr::FileRack>(
+        &self,
+        database: &DB,
+        file_rack: &FR,
+        config: &Config,
+        ip: &str,
+        uploads: Vec<Upload>,
+    ) -> Result<Staging, util::PlainchantErr> {
+        // Files are sanitised first, so that bans and deduplication see what is stored
+        let mut checked = vec![];
+        for upload in uploads {
+            let file_name = upload.file_name.as_deref().unwrap_or("");
+            let file = match media::sanitise(&config.uploads, upload.file.clone(), file_name) {
+                Ok(file) => file,
+                Err(err) => return reject_upload(err),
+            };
+
+            if self.is_banned_file(&file, &config.uploads)? {
+                return self.reject_banned_file(database, config, ip).map(Err);
+            }
+
+            checked.push(Upload { file, ..upload });
+        }
+
+        let mut staged = vec![];
+        for upload in checked {
+            match self.stage_file(file_rack, upload) {
+                Ok(file) => staged.push(file),
+                Err(err) => {
+                    for file in &staged {
+                        let _ = file_rack.discard_file(&file.stage_id);
+                    }
+                    return reject_upload(err);
+                },
+            }
+        }
+
+        Ok(Ok(staged))
     }
 
+    #[allow(clippy::ptr_arg, clippy::collapsible_if)]
     fn contains_disallowed_domains(&self, body: &String) -> bool {
         let urls = URL.captures_iter(body);
         for url in urls {
@@ -201,17 +568,18 @@ impl Actions {
         false
     }
 
-    pub fn submit_original<DB: db::Database>(
+    #[allow(clippy::too_many_arguments)]
+    pub fn submit_original<DB: db::Database, FR: fr::FileRack>(
         &self,
         database: &DB,
+        file_rack: &FR,
         config: &Config,
         board_id: u64,
         ip: String,
         body: String,
         poster: Option<String>,
         trip: Option<String>,
-        file_id: String,
-        file_name: String,
+        uploads: Vec<Upload>,
         title: Option<String>,
     ) -> Result<SubmissionResult, util::PlainchantErr> {
         let cur_time = util::timestamp();
@@ -232,6 +600,16 @@ impl Actions {
             return Ok(SubmissionResult::MayNotBeEmpty);
         }
 
+        if uploads.len() > database.get_board(board_id)?.max_files as usize {
+            return Ok(SubmissionResult::TooManyFiles);
+        }
+
+        // The files are only stored once the post is known to be acceptable
+        let staged = match self.stage_uploads(database, file_rack, config, &ip, uploads)? {
+            Ok(staged) => staged,
+            Err(result) => return Ok(result),
+        };
+
         let feather = match trip {
             None => site::Feather::None,
             Some(t) => site::Feather::Trip(compute_tripcode(t)),
@@ -245,8 +623,10 @@ impl Actions {
             body,
             poster,
             feather,
-            file_id: Some(file_id),
-            file_name: Some(file_name),
+            files: staged
+                .iter()
+                .map(|staged| staged.post_file(&config.thumbnails, media::ThumbKind::Op))
+                .collect(),
             approval: if config.approve_threads_by_default {
                 site::Approval::Approved
             } else {
@@ -260,25 +640,25 @@ impl Actions {
             archived: false,
         };
 
-        let orig = database
-            .create_original(original)
-            .map(SubmissionResult::Success)?;
+        let post_num =
+            self.commit_post(file_rack, &staged, || database.create_original(original))?;
 
         set_cooldown_time(&self.orig_cooldown, ip, cur_time + ORIG_COOLDOWN)?;
-        Ok(orig)
+        Ok(SubmissionResult::Success(post_num))
     }
 
-    pub fn submit_reply<DB: db::Database>(
+    #[allow(clippy::too_many_arguments)]
+    pub fn submit_reply<DB: db::Database, FR: fr::FileRack>(
         &self,
         database: &DB,
+        file_rack: &FR,
         config: &Config,
         board_id: u64,
         ip: String,
         body: String,
         poster: Option<String>,
         trip: Option<String>,
-        file_id: Option<String>,
-        file_name: Option<String>,
+        uploads: Vec<Upload>,
         orig_num: u64,
     ) -> Result<SubmissionResult, util::PlainchantErr> {
         let cur_time = util::timestamp();
@@ -304,10 +684,20 @@ impl Actions {
             return Ok(SubmissionResult::Cooldown);
         }
 
-        if file_id.is_none() && body.trim().is_empty() {
+        if uploads.is_empty() && body.trim().is_empty() {
             return Ok(SubmissionResult::MayNotBeEmpty);
         }
 
+        if uploads.len() > database.get_board(board_id)?.max_files as usize {
+            return Ok(SubmissionResult::TooManyFiles);
+        }
+
+        // The files are only stored once the post is known to be acceptable
+        let staged = match self.stage_uploads(database, file_rack, config, &ip, uploads)? {
+            Ok(staged) => staged,
+            Err(result) => return Ok(result),
+        };
+
         let feather = match trip {
             None => site::Feather::None,
             Some(t) => site::Feather::Trip(compute_tripcode(t)),
@@ -321,8 +711,10 @@ impl Actions {
             body,
             poster,
             feather,
-            file_id: file_id.clone(),
-            file_name,
+            files: staged
+                .iter()
+                .map(|staged| staged.post_file(&config.thumbnails, media::ThumbKind::Reply))
+                .collect(),
             approval: if config.approve_replies_by_default {
                 site::Approval::Approved
             } else {
@@ -331,12 +723,46 @@ impl Actions {
             orig_num,
         };
 
-        let reply = database
-            .create_reply(reply)
-            .map(SubmissionResult::Success)?;
+        let post_num = self.commit_post(file_rack, &staged, || database.create_reply(reply))?;
 
         set_cooldown_time(&self.reply_cooldown, ip, cur_time + REPLY_COOLDOWN)?;
-        Ok(reply)
+        Ok(SubmissionResult::Success(post_num))
+    }
+
+    // Commit a post's staged files, then create the post. If either step fails, the files
+    // this added to the rack are removed again, so a post never refers to a missing file.
+    // A crash between the two steps can still leave files without a post, which fsck finds.
+    fn commit_post<FR: fr::FileRack>(
+        &self,
+        file_rack: &FR,
+        staged: &[StagedFile],
+        create: impl FnOnce() -> Result<u64, util::PlainchantErr>,
+    ) -> Result<u64, util::PlainchantErr> {
+        let _guard = self.rack_guard()?;
+
+        // Files already in the rack belong to other posts, so are never rolled back
+        let mut added = vec![];
+        let roll_back = |added: &[&str]| {
+            for file_id in added {
+                let _ = self.delete_rack_file(file_rack, file_id);
+            }
+            for file in staged {
+                let _ = file_rack.discard_file(&file.stage_id);
+            }
+        };
+
+        for file in staged {
+            let existed = file_rack.stat_file(&file.file_id).is_ok();
+            if let Err(err) = file_rack.commit_file(&file.stage_id, &file.file_id) {
+                roll_back(&added);
+                return Err(err);
+            }
+            if !existed {
+                added.push(file.file_id.as_str());
+            }
+        }
+
+        create().inspect_err(|_| roll_back(&added))
     }
 
     pub fn delete_thread<DB: db::Database, FR: fr::FileRack>(
@@ -346,19 +772,14 @@ impl Actions {
         board_id: u64,
         post_num: u64,
     ) -> Result<(), util::PlainchantErr> {
-        let thread = database.get_thread(board_id, post_num)?;
+        let _guard = self.rack_guard()?;
 
-        // This transaction also deletes replies
-        database.delete_original(board_id, thread.original.post_num())?;
+        let orig = database.get_original(board_id, post_num)?;
 
-        if let Some(id) = thread.original.file_id() {
-            file_rack.delete_file(id)?;
-        }
-
-        for reply in thread.replies {
-            if let Some(id) = reply.file_id() {
-                file_rack.delete_file(id)?;
-            }
+        // This transaction also deletes replies
+        // Files are only deleted once no other post references them
+        for file_id in database.delete_original(board_id, orig.post_num())? {
+            self.delete_rack_file(file_rack, &file_id)?;
         }
 
         Ok(())
@@ -371,12 +792,10 @@ impl Actions {
         board_id: u64,
         post_num: u64,
     ) -> Result<(), util::PlainchantErr> {
-        let reply = database.get_reply(board_id, post_num)?;
-
-        database.delete_reply(board_id, post_num)?;
+        let _guard = self.rack_guard()?;
 
-        if let Some(id) = reply.file_id() {
-            file_rack.delete_file(id)?;
+        for file_id in database.delete_reply(board_id, post_num)? {
+            self.delete_rack_file(file_rack, &file_id)?;
         }
 
         Ok(())
@@ -435,31 +854,343 @@ impl Actions {
         board_id: u64,
     ) -> Result<(), util::PlainchantErr> {
         let board = database.get_board(board_id)?;
-        let catalog = database.get_catalog(board_id)?;
 
-        let post_cap: usize = board.post_cap.into();
-        let archive_cap: usize = board.archive_cap.into();
+        // The first `post_cap` threads in the catalog are live, the next `archive_cap`
+        // are archived, and everything beyond that should be deleted
+        let expired = database.update_archive(board_id, board.post_cap, board.archive_cap)?;
 
-        for (idx, mut orig) in catalog.originals.into_iter().enumerate() {
-            if idx < post_cap {
-                // The first `post_cap` threads in the catalog should not be archived
-                if orig.archived {
-                    orig.set_archived(false);
-                    database.update_original(orig)?;
+        for post_num in expired {
+            self.delete_thread(database, file_rack, board_id, post_num)?;
+        }
+
+        Ok(())
+    }
+
+    // Check the database and file rack against each other
+    pub fn fsck<DB: db::Database, FR: fr::FileRack>(
+        &self,
+        database: &DB,
+        file_rack: &FR,
+        mode: FsckMode,
+    ) -> Result<FsckReport, util::PlainchantErr> {
+        let _guard = self.rack_guard()?;
+
+        let missing_files = database
+            .get_file_refs()?
+            .into_iter()
+            .filter(|file_ref| file_rack.stat_file(&file_ref.file_id).is_err())
+            .collect::<Vec<db::FileRef>>();
+
+        if mode != FsckMode::Dry {
+            for file_ref in &missing_files {
+                let (board_id, post_num) = (file_ref.board_id, file_ref.post_num);
+
+                // The post may have already been deleted along with its thread
+                let post = match database.get_differentiated_post(board_id, post_num) {
+                    Ok(post) => post,
+                    Err(_) => continue,
+                };
+
+                match (mode, post) {
+                    // Any other files these posts referenced are swept up with the orphans
+                    (FsckMode::Delete, site::DifferentiatedPost::Original(_)) => {
+                        database.delete_original(board_id, post_num)?;
+                    },
+                    (FsckMode::Delete, site::DifferentiatedPost::Reply(_)) => {
+                        database.delete_reply(board_id, post_num)?;
+                    },
+                    (_, post) => {
+                        let mut post: Box<dyn site::Post> = match post {
+                            site::DifferentiatedPost::Original(orig) => Box::new(orig),
+                            site::DifferentiatedPost::Reply(reply) => Box::new(reply),
+                        };
+                        post.files_mut()
+                            .retain(|file| file.file_id != file_ref.file_id);
+                        database.update_post(post)?;
+                    },
                 }
-            } else if post_cap <= idx && idx < (post_cap + archive_cap) {
-                // Allow up to `archive_cap` threads in the catalog to be archived
-                if !orig.archived {
-                    orig.set_archived(true);
-                    database.update_original(orig)?;
+            }
+        }
+
+        // Counts are checked after posts are repaired, as repairs may change them
+        let miscounted = database
+            .get_reply_counts()?
+            .into_iter()
+            .filter(|counts| {
+                counts.replies != counts.actual_replies
+                    || counts.img_replies != counts.actual_img_replies
+            })
+            .collect::<Vec<db::ReplyCounts>>();
+
+        if mode != FsckMode::Dry {
+            for counts in &miscounted {
+                let mut orig = database.get_original(counts.board_id, counts.post_num)?;
+                orig.set_replies(counts.actual_replies);
+                orig.set_img_replies(counts.actual_img_replies);
+                database.update_original(orig)?;
+            }
+        }
+
+        // A drifted reference count would stop a file being released with its last post,
+        // or release it while posts still use it
+        let misreferenced = database
+            .get_file_ref_counts()?
+            .into_iter()
+            .filter(|counts| counts.ref_count != counts.actual_refs)
+            .collect::<Vec<db::FileRefCount>>();
+
+        if mode != FsckMode::Dry {
+            for counts in &misreferenced {
+                database.set_file_ref_count(&counts.file_id, counts.actual_refs)?;
+            }
+        }
+
+        let referenced = database
+            .get_file_refs()?
+            .into_iter()
+            .map(|file_ref| file_ref.file_id)
+            .collect::<HashSet<String>>();
+
+        let mut orphaned_files = vec![];
+        for file_id in file_rack.list_files()? {
+            if referenced.contains(&file_id) {
+                continue;
+            }
+
+            let is_recent = match file_rack.stat_file(&file_id) {
+                Ok(stat) => stat
+                    .modified
+                    .elapsed()
+                    .map(|age| age.as_secs() < FSCK_GRACE_PERIOD)
+                    .unwrap_or(true),
+                Err(_) => true,
+            };
+
+            if !is_recent {
+                orphaned_files.push(file_id);
+            }
+        }
+
+        if mode != FsckMode::Dry {
+            for file_id in &orphaned_files {
+                self.delete_rack_file(file_rack, file_id)?;
+            }
+        }
+
+        Ok(FsckReport {
+            missing_files,
+            miscounted,
+            misreferenced,
+            orphaned_files,
+        })
+    }
+
+    // Whether an IP may filter a catalog now, which starts its cooldown if so
+    pub fn start_catalog_filter(&self, ip: String) -> Result<bool, PlainchantErr> {
+        let cur_time = util::timestamp();
+
+        if is_within_cooldown(&self.filter_cooldown, &ip, cur_time)? {
+            return Ok(false);
+        }
+
+        set_cooldown_time(&self.filter_cooldown, ip, cur_time + FILTER_COOLDOWN)?;
+        Ok(true)
+    }
+
+    // The files of every visible post in a thread, for downloading together
+    // Each IP may only start one download a minute, as a zip can be large to send
+    pub fn thread_zip<DB: db::Database, FR: fr::FileRack>(
+        &self,
+        database: &DB,
+        file_rack: &FR,
+        ip: String,
+        board_id: u64,
+        post_num: u64,
+    ) -> Result<ThreadZipResult, util::PlainchantErr> {
+        let cur_time = util::timestamp();
+
+        if is_within_cooldown(&self.zip_cooldown, &ip, cur_time)? {
+            return Ok(ThreadZipResult::Cooldown);
+        }
+
+        let thread = database.get_thread(board_id, post_num)?;
+        if !matches!(thread.original.approval, site::Approval::Approved) {
+            return Err(PlainchantErr {
+                origin: ErrOrigin::Actions,
+                code:   404,
+                msg:    String::from("Unapproved Post"),
+            });
+        }
+
+        let posts = iter::once(&thread.original as &dyn Post).chain(
+            thread
+                .replies
+                .iter()
+                .filter(|reply| matches!(reply.approval, site::Approval::Approved))
+                .map(|reply| reply as &dyn Post),
+        );
+
+        let mut files = vec![];
+        let mut size = 0;
+        for post in posts {
+            let post_files = post.files();
+            for (position, file) in post_files.iter().enumerate() {
+                // Files from before sizes were recorded are measured in the rack, and
+                // files which cannot be found there will be left out of the zip
+                size += match &file.file_info {
+                    Some(info) => info.size,
+                    None => file_rack
+                        .stat_file(&file.file_id)
+                        .map(|stat| stat.len)
+                        .unwrap_or(0),
+                };
+                files.push(ZipFile {
+                    name:     zip_file_name(post.post_num(), position, post_files.len(), file),
+                    file_id:  file.file_id.clone(),
+                    modified: post.time(),
+                });
+            }
+        }
+
+        if size > ZIP_MAX_SIZE || files.len() > zip::MAX_ENTRIES {
+            return Ok(ThreadZipResult::TooLarge);
+        }
+
+        set_cooldown_time(&self.zip_cooldown, ip, cur_time + ZIP_COOLDOWN)?;
+        Ok(ThreadZipResult::Ready(files))
+    }
+
+    // Move every file stored under an older layout of the rack into the current one
+    // Files are moved one at a time, so the site stays up throughout
+    pub fn migrate_rack_layout<FR: fr::FileRack>(
+        &self,
+        file_rack: &FR,
+    ) -> Result<LayoutReport, util::PlainchantErr> {
+        let mut report = LayoutReport {
+            migrated: 0,
+            failed:   vec![],
+        };
+
+        for file_id in file_rack.list_files()? {
+            // Held per file, so that it cannot be committed or deleted while being moved
+            let _guard = self.rack_guard()?;
+
+            match file_rack.migrate_layout(&file_id) {
+                Ok(true) => report.migrated += 1,
+                Ok(false) => (),
+                Err(_) => report.failed.push(file_id),
+            }
+        }
+
+        Ok(report)
+    }
+
+    // Remake every file's thumbnails with the current settings, and record the new
+    // thumbnail sizes on the posts using them. This also fills in the file info of posts
+    // made before it was recorded.
+    pub fn regenerate_thumbnails<DB: db::Database, FR: fr::FileRack>(
+        &self,
+        database: &DB,
+        file_rack: &FR,
+        config: &Config,
+    ) -> Result<ThumbnailReport, util::PlainchantErr> {
+        let mut refs: HashMap<String, Vec<(u64, u64)>> = HashMap::new();
+        for file_ref in database.get_file_refs()? {
+            refs.entry(file_ref.file_id)
+                .or_default()
+                .push((file_ref.board_id, file_ref.post_num));
+        }
+
+        let mut report = ThumbnailReport {
+            regenerated: 0,
+            failed:      vec![],
+        };
+
+        for (file_id, posts) in refs {
+            // Held per file, so posting is only blocked for as long as one file takes
+            let _guard = self.rack_guard()?;
+
+            let info = match file_rack.regenerate_thumbnails(&file_id) {
+                Ok(info) => info,
+                Err(_) => {
+                    report.failed.push(file_id);
+                    continue;
+                },
+            };
+
+            for (board_id, post_num) in posts {
+                // The post may have been deleted since the references were read
+                let (mut post, kind): (Box<dyn site::Post>, _) =
+                    match database.get_differentiated_post(board_id, post_num) {
+                        Ok(site::DifferentiatedPost::Original(orig)) => {
+                            (Box::new(orig), media::ThumbKind::Op)
+                        },
+                        Ok(site::DifferentiatedPost::Reply(reply)) => {
+                            (Box::new(reply), media::ThumbKind::Reply)
+                        },
+                        Err(_) => continue,
+                    };
+
+                // A post may have the same file more than once
+                for file in post.files_mut() {
+                    if file.file_id == file_id {
+                        file.file_info = Some(post_file_info(&info, &config.thumbnails, kind));
+                    }
                 }
-            } else {
-                // Everything beyond that should be deleted
-                self.delete_thread(database, file_rack, board_id, orig.post_num)?;
+                database.update_post(post)?;
             }
+
+            report.regenerated += 1;
         }
 
-        Ok(())
+        Ok(report)
+    }
+
+    // Back up the database, then snapshot every file it references
+    // The rack is only locked while the database is copied, so posts can be made during
+    // the snapshot. Rack files never change once committed, so only deleting one of the
+    // snapshotted files has to wait, and that is done once the snapshot is finished.
+    pub fn backup<DB: db::Database, FR: fr::FileRack>(
+        &self,
+        database: &DB,
+        file_rack: &FR,
+        dest: &Path,
+    ) -> Result<(), util::PlainchantErr> {
+        let _backup_guard = self
+            .backup_lock
+            .lock()
+            .map_err(|_| actions_err("Failed to acquire Backup Lock"))?;
+
+        let file_ids = {
+            let _guard = self.rack_guard()?;
+
+            database.backup(&dest.join("db.sqlite3"))?;
+            let file_ids = database
+                .get_file_refs()?
+                .into_iter()
+                .map(|file_ref| file_ref.file_id)
+                .collect::<Vec<String>>();
+            self.backup_files()?.file_ids = file_ids.iter().cloned().collect();
+            file_ids
+        };
+
+        let result = file_rack.snapshot_files(&file_ids, &dest.join("fsfr"));
+
+        // A deferred file may have been posted again since, in which case it stays
+        let _guard = self.rack_guard()?;
+        let deferred = mem::take(&mut *self.backup_files()?).deferred;
+        if !deferred.is_empty() {
+            let referenced = database
+                .get_file_refs()?
+                .into_iter()
+                .map(|file_ref| file_ref.file_id)
+                .collect::<HashSet<String>>();
+            for file_id in deferred.difference(&referenced) {
+                file_rack.delete_file(file_id)?;
+            }
+        }
+
+        result
     }
 
     pub fn board_url_to_id(&self, url: &str) -> Result<u64, util::PlainchantErr> {
@@ -473,6 +1204,7 @@ impl Actions {
         }
     }
 
+    #[allow(clippy::extra_unused_lifetimes)]
     pub fn board_id_to_url<'a>(&self, id: u64) -> Result<String, util::PlainchantErr> {
         match self.board_ids.get(&id) {
             Some(url) => Ok(url.to_string()),
@@ -484,3 +1216,102 @@ impl Actions {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sqlite3db::Sqlite3Database;
+    use bytes::Bytes;
+    use std::fs;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    // A rack whose snapshots wait until the test lets them finish
+    struct SlowRack {
+        started:  Mutex<mpsc::Sender<()>>,
+        finished: Mutex<mpsc::Receiver<()>>,
+    }
+
+    impl fr::FileRack for SlowRack {
+        fn stage_file(&self, _: &str, _: Bytes) -> Result<site::FileInfo, PlainchantErr> {
+            unimplemented!()
+        }
+        fn commit_file(&self, _: &str, _: &str) -> Result<(), PlainchantErr> {
+            unimplemented!()
+        }
+        fn discard_file(&self, _: &str) -> Result<(), PlainchantErr> {
+            unimplemented!()
+        }
+        fn get_file(&self, _: &str) -> Result<Bytes, PlainchantErr> {
+            unimplemented!()
+        }
+        fn get_file_thumbnail(&self, _: &str, _: media::ThumbKind) -> Result<Bytes, PlainchantErr> {
+            unimplemented!()
+        }
+        fn regenerate_thumbnails(&self, _: &str) -> Result<site::FileInfo, PlainchantErr> {
+            unimplemented!()
+        }
+        fn delete_file(&self, _: &str) -> Result<(), PlainchantErr> {
+            unimplemented!()
+        }
+        fn list_files(&self) -> Result<Vec<String>, PlainchantErr> {
+            unimplemented!()
+        }
+        fn stat_file(&self, _: &str) -> Result<fr::FileStat, PlainchantErr> {
+            unimplemented!()
+        }
+        fn read_file_range(
+            &self,
+            _: &str,
+            _: u64,
+            _: u64,
+        ) -> Result<fr::FileReader, PlainchantErr> {
+            unimplemented!()
+        }
+
+        fn snapshot_files(&self, _: &[String], _: &Path) -> Result<(), PlainchantErr> {
+            self.started.lock().unwrap().send(()).unwrap();
+            self.finished.lock().unwrap().recv().unwrap();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn commits_posts_during_backups() {
+        let dir = std::env::temp_dir().join(format!("plainchant-backup-{}", std::process::id()));
+        let dest = dir.join("backup");
+        fs::create_dir_all(&dest).unwrap();
+
+        let database = Sqlite3Database::from_path(dir.join("db.sqlite3")).unwrap();
+        let actions = Actions::new(&database).unwrap();
+
+        let (started_tx, started_rx) = mpsc::channel();
+        let (finished_tx, finished_rx) = mpsc::channel();
+        let rack = SlowRack {
+            started:  Mutex::new(started_tx),
+            finished: Mutex::new(finished_rx),
+        };
+
+        thread::scope(|scope| {
+            let backup = scope.spawn(|| actions.backup(&database, &rack, &dest));
+            started_rx.recv().unwrap();
+
+            // Committing takes the rack lock, so would wait for the whole snapshot if the
+            // backup still held it
+            let (posted_tx, posted_rx) = mpsc::channel();
+            let (actions, rack) = (&actions, &rack);
+            scope.spawn(move || {
+                let post_num = actions.commit_post(rack, &[], || Ok(1));
+                posted_tx.send(post_num.is_ok()).unwrap();
+            });
+            let posted = posted_rx.recv_timeout(Duration::from_secs(10));
+
+            finished_tx.send(()).unwrap();
+            assert!(backup.join().unwrap().is_ok());
+            assert_eq!(posted, Ok(true));
+        });
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
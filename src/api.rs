@@ -1,16 +1,21 @@
 use crate::actions::Actions;
 use crate::db;
 use crate::fr;
+use crate::headers;
 use crate::site;
 use crate::state::{DbState, PlainchantState};
+use crate::util;
 use crate::util::PlainchantErr;
 
 use axum::Json;
 use axum::extract;
 use axum::extract::State;
-use axum::http::StatusCode;
+use axum::http::{HeaderValue, StatusCode, header};
 use axum::{Router, routing};
 
+use tower_http::compression::CompressionLayer;
+use tower_http::set_header::SetResponseHeaderLayer;
+
 use std::sync::Arc;
 
 use serde::Serialize;
@@ -54,7 +59,7 @@ struct ApiSite {
 }
 
 async fn site<DB: db::Database>(State(DbState { db }): State<DbState<DB>>) -> ApiResult<ApiSite> {
-    let site = db.get_site()?;
+    let site = util::blocking(move || db.get_site()).await?;
     let api_site = ApiSite {
         name:        site.name,
         description: site.description,
@@ -72,6 +77,7 @@ struct ApiBoard {
     pub archive_cap:   u16,
     pub bump_limit:    u16,
     pub next_post_num: u64,
+    pub max_files:     u16,
 }
 
 impl From<site::Board> for ApiBoard {
@@ -83,6 +89,7 @@ impl From<site::Board> for ApiBoard {
             archive_cap:   board.archive_cap,
             bump_limit:    board.bump_limit,
             next_post_num: board.next_post_num,
+            max_files:     board.max_files,
         }
     }
 }
@@ -90,8 +97,8 @@ impl From<site::Board> for ApiBoard {
 async fn boards<DB: db::Database>(
     State(DbState { db }): State<DbState<DB>>,
 ) -> ApiResult<Vec<ApiBoard>> {
-    let api_boards = db
-        .get_boards()?
+    let api_boards = util::blocking(move || db.get_boards())
+        .await?
         .into_iter()
         .map(|b| b.into())
         .collect::<Vec<ApiBoard>>();
@@ -105,10 +112,84 @@ async fn board<DB: db::Database>(
     extract::Path(board): extract::Path<String>,
 ) -> ApiResult<ApiBoard> {
     let board_id = actions.board_url_to_id(&board)?;
-    let board = db.get_board(board_id)?.into();
+    let board = util::blocking(move || db.get_board(board_id)).await?.into();
     api_ok(board)
 }
 
+// Lets clients lay out images before they load
+#[derive(Serialize)]
+struct ApiFileInfo {
+    width:        u32,
+    height:       u32,
+    size:         u64,
+    format:       String,
+    duration_ms:  Option<u64>,
+    thumb_width:  u32,
+    thumb_height: u32,
+}
+
+#[derive(Serialize)]
+struct ApiFile {
+    file_id:   String,
+    file_name: Option<String>,
+    // SHA-256 of the file's contents, absent for files stored before content addressing
+    file_hash: Option<String>,
+    file_info: Option<ApiFileInfo>,
+    spoiler:   bool,
+    alt_text:  Option<String>,
+}
+
+impl From<site::PostFile> for ApiFile {
+    fn from(file: site::PostFile) -> Self {
+        ApiFile {
+            file_hash: fr::content_hash(&file.file_id).map(String::from),
+            file_id:   file.file_id,
+            file_name: file.file_name,
+            file_info: file.file_info.map(ApiFileInfo::from),
+            spoiler:   file.spoiler,
+            alt_text:  file.alt_text,
+        }
+    }
+}
+
+// A post's first file, as the API gave it before posts could have several
+#[derive(Serialize)]
+struct ApiFirstFile {
+    file_id:   Option<String>,
+    // SHA-256 of the file's contents, absent for files stored before content addressing
+    file_hash: Option<String>,
+    file_info: Option<ApiFileInfo>,
+}
+
+impl From<&[site::PostFile]> for ApiFirstFile {
+    fn from(files: &[site::PostFile]) -> Self {
+        let first = files.first();
+        ApiFirstFile {
+            file_id:   first.map(|file| file.file_id.clone()),
+            file_hash: first
+                .and_then(|file| fr::content_hash(&file.file_id))
+                .map(String::from),
+            file_info: first
+                .and_then(|file| file.file_info.clone())
+                .map(ApiFileInfo::from),
+        }
+    }
+}
+
+impl From<site::FileInfo> for ApiFileInfo {
+    fn from(info: site::FileInfo) -> Self {
+        ApiFileInfo {
+            width:        info.width,
+            height:       info.height,
+            size:         info.size,
+            format:       info.format,
+            duration_ms:  info.duration_ms,
+            thumb_width:  info.thumb_width,
+            thumb_height: info.thumb_height,
+        }
+    }
+}
+
 #[derive(Serialize)]
 struct ApiOriginal {
     board_url:    String,
@@ -120,7 +201,9 @@ struct ApiOriginal {
     is_moderator: bool,
     is_admin:     bool,
     trip:         Option<String>,
-    file_id:      Option<String>,
+    #[serde(flatten)]
+    first_file:   ApiFirstFile,
+    files:        Vec<ApiFile>,
     is_approved:  bool,
     is_flagged:   bool,
     bump_time:    u64,
@@ -146,7 +229,8 @@ fn original_to_api(
             site::Feather::Trip(s) => Some(s),
             _ => None,
         },
-        file_id:      orig.file_id,
+        first_file:   ApiFirstFile::from(&orig.files[..]),
+        files:        orig.files.into_iter().map(ApiFile::from).collect(),
         is_approved:  matches!(orig.approval, site::Approval::Approved),
         is_flagged:   matches!(orig.approval, site::Approval::Flagged),
         bump_time:    orig.bump_time,
@@ -162,8 +246,8 @@ async fn threads<DB: db::Database>(
     extract::Path(board_url): extract::Path<String>,
 ) -> ApiResult<Vec<ApiOriginal>> {
     let board_id = actions.board_url_to_id(&board_url)?;
-    let threads = db
-        .get_catalog(board_id)?
+    let threads = util::blocking(move || db.get_catalog(board_id))
+        .await?
         .originals
         .into_iter()
         .map(|orig| original_to_api(&actions, orig))
@@ -182,7 +266,9 @@ struct ApiReply {
     is_moderator: bool,
     is_admin:     bool,
     trip:         Option<String>,
-    file_id:      Option<String>,
+    #[serde(flatten)]
+    first_file:   ApiFirstFile,
+    files:        Vec<ApiFile>,
     is_approved:  bool,
     is_flagged:   bool,
 }
@@ -201,7 +287,8 @@ fn reply_to_api(actions: &Arc<Actions>, reply: site::Reply) -> Result<ApiReply,
             site::Feather::Trip(s) => Some(s),
             _ => None,
         },
-        file_id:      reply.file_id,
+        first_file:   ApiFirstFile::from(&reply.files[..]),
+        files:        reply.files.into_iter().map(ApiFile::from).collect(),
         is_approved:  matches!(reply.approval, site::Approval::Approved),
         is_flagged:   matches!(reply.approval, site::Approval::Flagged),
     })
@@ -218,7 +305,8 @@ async fn thread<DB: db::Database>(
     State(DbState { db }): State<DbState<DB>>,
     extract::Path((board_url, post_num)): extract::Path<(String, u64)>,
 ) -> ApiResult<ApiThread> {
-    let thread = db.get_thread(actions.board_url_to_id(&board_url)?, post_num)?;
+    let board_id = actions.board_url_to_id(&board_url)?;
+    let thread = util::blocking(move || db.get_thread(board_id, post_num)).await?;
     api_ok(ApiThread {
         original: original_to_api(&actions, thread.original)?,
         replies:  thread
@@ -243,7 +331,8 @@ async fn post<DB: db::Database>(
     State(DbState { db }): State<DbState<DB>>,
     extract::Path((board_url, post_num)): extract::Path<(String, u64)>,
 ) -> ApiResult<ApiPost> {
-    let post = db.get_differentiated_post(actions.board_url_to_id(&board_url)?, post_num)?;
+    let board_id = actions.board_url_to_id(&board_url)?;
+    let post = util::blocking(move || db.get_differentiated_post(board_id, post_num)).await?;
 
     api_ok(match post {
         site::DifferentiatedPost::Original(orig) => {
@@ -265,4 +354,9 @@ where
         .route("/board/{board_url}/threads", routing::get(threads))
         .route("/board/{board_url}/thread/{post_num}", routing::get(thread))
         .route("/board/{board_url}/post/{post_num}", routing::get(post))
+        .layer(CompressionLayer::new())
+        .layer(SetResponseHeaderLayer::if_not_present(
+            header::CACHE_CONTROL,
+            HeaderValue::from_static(headers::CACHE_API),
+        ))
 }
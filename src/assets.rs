@@ -0,0 +1,100 @@
+use crate::util;
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+// Length of the content hash embedded in fingerprinted file names
+const FINGERPRINT_LEN: usize = 12;
+
+fn static_err(msg: &'static str) -> util::PlainchantErr {
+    util::PlainchantErr {
+        origin: util::ErrOrigin::Web,
+        code:   500,
+        msg:    String::from(msg),
+    }
+}
+
+// Manifest of static assets, hashed once at startup
+// Templates refer to assets by their logical path (e.g. thread.css) and are given
+// a fingerprinted path (e.g. thread.3fa2b1c9d0e4.css) which can be cached forever
+pub struct StaticAssets {
+    fingerprinted: HashMap<String, String>,
+    logical:       HashMap<String, String>,
+}
+
+// Insert the fingerprint before the final extension: dir/thread.css -> dir/thread.<fp>.css
+fn fingerprint_path(path: &str, fingerprint: &str) -> String {
+    let (dir, file) = match path.rsplit_once('/') {
+        Some((dir, file)) => (format!("{}/", dir), file),
+        None => (String::new(), path),
+    };
+
+    match file.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => format!("{}{}.{}.{}", dir, stem, fingerprint, ext),
+        _ => format!("{}{}.{}", dir, file, fingerprint),
+    }
+}
+
+fn walk_dir(root: &Path, dir: &Path, assets: &mut StaticAssets) -> Result<(), util::PlainchantErr> {
+    let entries = fs::read_dir(dir).map_err(|_| static_err("Could not read static directory"))?;
+
+    for entry in entries {
+        let entry = entry.map_err(|_| static_err("Could not read static directory entry"))?;
+        let path = entry.path();
+
+        if path.is_dir() {
+            walk_dir(root, &path, assets)?;
+            continue;
+        }
+
+        // Precompressed siblings are served in place of the file they belong to
+        if matches!(
+            path.extension().and_then(|ext| ext.to_str()),
+            Some("br" | "gz")
+        ) {
+            continue;
+        }
+
+        let logical = match path.strip_prefix(root).ok().and_then(|p| p.to_str()) {
+            Some(logical) => logical.replace('\\', "/"),
+            None => continue,
+        };
+
+        let contents = fs::read(&path).map_err(|_| static_err("Could not read static file"))?;
+        let digest = sha256::digest(&contents[..]);
+        let fingerprinted = fingerprint_path(&logical, &digest[..FINGERPRINT_LEN]);
+
+        assets
+            .logical
+            .insert(fingerprinted.clone(), logical.clone());
+        assets.fingerprinted.insert(logical, fingerprinted);
+    }
+
+    Ok(())
+}
+
+impl StaticAssets {
+    pub fn from_dir(dir: &Path) -> Result<StaticAssets, util::PlainchantErr> {
+        let mut assets = StaticAssets {
+            fingerprinted: HashMap::new(),
+            logical:       HashMap::new(),
+        };
+
+        walk_dir(dir, dir, &mut assets)?;
+        Ok(assets)
+    }
+
+    // URL for a static asset, falling back to the unhashed path for unknown files
+    pub fn url(&self, path: &str) -> String {
+        match self.fingerprinted.get(path) {
+            Some(fingerprinted) => format!("/static/{}", fingerprinted),
+            None => format!("/static/{}", path),
+        }
+    }
+
+    // Map a fingerprinted path back to the file it names
+    pub fn resolve(&self, fingerprinted: &str) -> Option<&str> {
+        self.logical.get(fingerprinted).map(|s| s.as_str())
+    }
+}
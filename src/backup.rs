@@ -0,0 +1,259 @@
+use crate::Config;
+use crate::actions::Actions;
+use crate::db;
+use crate::fr;
+use crate::fr::FileRack;
+use crate::fsfr;
+use crate::media;
+use crate::sqlite3db;
+use crate::util;
+use crate::util::PlainchantErr;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+// Each backup is a directory `backup-<timestamp>` holding `db.sqlite3` and an `fsfr`
+// directory, so that it can be served directly by pointing a config file at it
+
+const BACKUP_PREFIX: &str = "backup-";
+
+pub struct BackupConfig {
+    pub dir:      PathBuf,
+    pub keep:     usize,
+    pub interval: Option<Duration>,
+}
+
+pub struct Backup {
+    pub path:    PathBuf,
+    pub removed: Vec<PathBuf>,
+}
+
+fn backup_err(msg: String) -> PlainchantErr {
+    PlainchantErr {
+        origin: util::ErrOrigin::Backup,
+        code: 500,
+        msg,
+    }
+}
+
+// Completed backups in `dir`, newest first
+fn list_backups(dir: &Path) -> Result<Vec<(u64, PathBuf)>, PlainchantErr> {
+    let entries = fs::read_dir(dir)
+        .map_err(|err| backup_err(format!("Could not read backup directory: {}", err)))?;
+
+    let mut backups = vec![];
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        // Partial backups have a suffix, so their timestamps do not parse
+        let timestamp = name
+            .to_str()
+            .and_then(|name| name.strip_prefix(BACKUP_PREFIX))
+            .and_then(|ts| ts.parse::<u64>().ok());
+
+        if let Some(timestamp) = timestamp
+            && entry.path().is_dir()
+        {
+            backups.push((timestamp, entry.path()));
+        }
+    }
+
+    backups.sort_by_key(|(timestamp, _)| std::cmp::Reverse(*timestamp));
+    Ok(backups)
+}
+
+// Remove all but the newest `keep` backups
+fn rotate(config: &BackupConfig) -> Result<Vec<PathBuf>, PlainchantErr> {
+    let mut removed = vec![];
+    for (_, path) in list_backups(&config.dir)?.into_iter().skip(config.keep) {
+        fs::remove_dir_all(&path)
+            .map_err(|err| backup_err(format!("Could not remove old backup: {}", err)))?;
+        removed.push(path);
+    }
+    Ok(removed)
+}
+
+pub fn create<DB: db::Database, FR: fr::FileRack>(
+    actions: &Actions,
+    database: &DB,
+    file_rack: &FR,
+    config: &BackupConfig,
+) -> Result<Backup, PlainchantErr> {
+    let name = format!("{}{}", BACKUP_PREFIX, util::timestamp());
+    let path = config.dir.join(&name);
+
+    if path.exists() {
+        return Err(backup_err(format!("Backup {} already exists", name)));
+    }
+
+    // Work in a staging directory so that a failed backup is never mistaken for a good one
+    let staging = config.dir.join(format!("{}.partial", name));
+    fs::create_dir_all(&staging)
+        .map_err(|err| backup_err(format!("Could not create backup directory: {}", err)))?;
+
+    if let Err(err) = actions.backup(database, file_rack, &staging) {
+        let _ = fs::remove_dir_all(&staging);
+        return Err(err);
+    }
+
+    fs::rename(&staging, &path)
+        .map_err(|err| backup_err(format!("Could not finalise backup: {}", err)))?;
+
+    let removed = rotate(config)?;
+
+    Ok(Backup { path, removed })
+}
+
+// Take a backup every `interval`, for as long as the server runs
+pub async fn schedule<DB: db::Database, FR: fr::FileRack>(
+    config: Arc<Config>,
+    actions: Arc<Actions>,
+    database: Arc<DB>,
+    file_rack: Arc<FR>,
+) {
+    let interval = match config.backup.as_ref().and_then(|backup| backup.interval) {
+        Some(interval) => interval,
+        None => return,
+    };
+
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    // The first tick completes immediately - don't take a backup on every restart
+    ticker.tick().await;
+
+    loop {
+        ticker.tick().await;
+
+        let (config, actions, database, file_rack) = (
+            config.clone(),
+            actions.clone(),
+            database.clone(),
+            file_rack.clone(),
+        );
+
+        let result = util::blocking(move || match &config.backup {
+            Some(backup_config) => create(
+                actions.as_ref(),
+                database.as_ref(),
+                file_rack.as_ref(),
+                backup_config,
+            ),
+            None => Err(backup_err(String::from("Backups are not configured"))),
+        })
+        .await;
+
+        match result {
+            Ok(backup) => println!("Backup written to {}", backup.path.display()),
+            Err(err) => eprintln!("Backup Error - {:?} - {}", err.origin, err.msg),
+        }
+    }
+}
+
+// Check that every file referenced by the database is present in the rack
+fn check_files<DB: db::Database, FR: fr::FileRack>(
+    database: &DB,
+    file_rack: &FR,
+) -> Result<Vec<String>, PlainchantErr> {
+    let file_ids = database
+        .get_file_refs()?
+        .into_iter()
+        .map(|file_ref| file_ref.file_id)
+        .collect::<Vec<String>>();
+
+    let missing = file_ids
+        .iter()
+        .filter(|id| {
+            file_rack.stat_file(id).is_err()
+                || media::ThumbKind::ALL
+                    .iter()
+                    .any(|kind| file_rack.get_file_thumbnail(id, *kind).is_err())
+        })
+        .cloned()
+        .collect::<Vec<String>>();
+
+    if missing.is_empty() {
+        Ok(file_ids)
+    } else {
+        Err(backup_err(format!(
+            "{} referenced file(s) missing from rack, including {}",
+            missing.len(),
+            missing[..missing.len().min(5)].join(", ")
+        )))
+    }
+}
+
+// A sibling of `path` with a suffix appended to its file name
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+// Restore a backup over the instance's database and file rack
+// This must only be run while the server is stopped. The backup is fully verified before
+// anything is touched, and the existing database is kept alongside the restored one
+pub fn restore(
+    backup_dir: &Path,
+    db_path: &Path,
+    fsfr_dir: &Path,
+) -> Result<String, PlainchantErr> {
+    let backup_db = backup_dir.join("db.sqlite3");
+    let backup_rack = fsfr::FSFileRack::open_read_only(&backup_dir.join("fsfr"))?;
+
+    if !backup_db.is_file() {
+        return Err(backup_err(format!(
+            "No database in {}",
+            backup_dir.display()
+        )));
+    }
+
+    // Verify a copy of the backed up database, so that the backup itself is never modified
+    let staged_db = with_suffix(db_path, ".restoring");
+    fs::copy(&backup_db, &staged_db)
+        .map_err(|err| backup_err(format!("Could not copy backup database: {}", err)))?;
+
+    let verified = sqlite3db::Sqlite3Database::from_path(staged_db.clone()).and_then(|db| {
+        db.check_integrity()?;
+        check_files(&db, &backup_rack)
+    });
+
+    let file_ids = match verified {
+        Ok(file_ids) => file_ids,
+        Err(err) => {
+            let _ = fs::remove_file(&staged_db);
+            return Err(err);
+        },
+    };
+
+    // Rack files are immutable and uniquely named, so existing ones are left alone
+    backup_rack.snapshot_files(&file_ids, fsfr_dir)?;
+
+    let mut out = String::new();
+
+    if db_path.exists() {
+        let previous = with_suffix(db_path, &format!(".pre-restore-{}", util::timestamp()));
+        fs::rename(db_path, &previous)
+            .map_err(|err| backup_err(format!("Could not move existing database: {}", err)))?;
+        out.push_str(&format!(
+            "Existing database moved to {}\n",
+            previous.display()
+        ));
+    }
+
+    fs::rename(&staged_db, db_path)
+        .map_err(|err| backup_err(format!("Could not move restored database: {}", err)))?;
+
+    let database = sqlite3db::Sqlite3Database::from_path(db_path.to_path_buf())?;
+    let file_rack = fsfr::FSFileRack::from_dir(fsfr_dir)?;
+    let file_ids = check_files(&database, &file_rack)?;
+
+    out.push_str(&format!(
+        "Restored {} with {} file(s)\n",
+        backup_dir.display(),
+        file_ids.len()
+    ));
+
+    Ok(out)
+}
@@ -1,4 +1,6 @@
+use crate::Config;
 use crate::actions;
+use crate::backup;
 use crate::db;
 use crate::fr;
 use crate::site;
@@ -7,6 +9,7 @@ use crate::util;
 use std::sync::Arc;
 
 pub fn execute<DB, FR>(
+    config: Arc<Config>,
     actions: Arc<actions::Actions>,
     database: Arc<DB>,
     file_rack: Arc<FR>,
@@ -36,7 +39,7 @@ where
         "post" => {
             if parts.len() < 4 {
                 return String::from(
-                    "post (show|rm|approve|mod|admin|nocap) <board_id> <post_num>\n",
+                    "post (show|rm|approve|mod|admin|nocap|spoiler|unspoiler) <board_id> <post_num>\n",
                 );
             }
 
@@ -107,6 +110,20 @@ where
                         Err(err) => format!("Error: {:?}\n", err),
                     }
                 },
+                cmd @ ("spoiler" | "unspoiler") => match database.get_post(board_id, post_num) {
+                    Ok(mut post) => {
+                        let spoiler = cmd == "spoiler";
+                        for file in post.files_mut() {
+                            file.spoiler = spoiler;
+                        }
+
+                        match database.update_post(post) {
+                            Ok(_) => format!("{}ed post's files\n", cmd),
+                            Err(err) => format!("Error: {:?}\n", err),
+                        }
+                    },
+                    Err(err) => format!("Error: {:?}\n", err),
+                },
                 _ => String::from("?\n"),
             }
         },
@@ -133,6 +150,68 @@ where
             }
         },
 
+        "fileban" => match (parts.get(1).map(|cmd| cmd.trim()), parts.len()) {
+            (Some("list"), _) => {
+                let banned_files = match database.get_banned_files() {
+                    Ok(banned_files) => banned_files,
+                    Err(err) => return format!("Error: {:?}\n", err),
+                };
+
+                let mut str_out = String::new();
+                for banned in banned_files {
+                    str_out.push_str(&format!(
+                        "{} - {} - {}\n",
+                        banned.file_hash,
+                        banned
+                            .perceptual_hash
+                            .map(|hash| format!("{:016x}", hash))
+                            .unwrap_or(String::from("<not an image>")),
+                        banned.time_banned
+                    ));
+                }
+                str_out
+            },
+            (Some("add"), 4) => {
+                let board_id = match parts[2].parse::<u64>() {
+                    Ok(id) => id,
+                    Err(_) => {
+                        return String::from("Board ID did not parse");
+                    },
+                };
+
+                let post_num = match parts[3].trim().parse::<u64>() {
+                    Ok(id) => id,
+                    Err(_) => {
+                        return String::from("Post num did not parse");
+                    },
+                };
+
+                match actions.ban_file(
+                    database.as_ref(),
+                    file_rack.as_ref(),
+                    &config,
+                    board_id,
+                    post_num,
+                ) {
+                    Ok(banned) => banned
+                        .iter()
+                        .map(|banned| format!("Banned file: {}\n", banned.file_hash))
+                        .collect(),
+                    Err(err) => format!("Error: {:?}\n", err),
+                }
+            },
+            (Some("rm"), 3) => {
+                let file_hash = parts[2].trim();
+                match actions.unban_file(database.as_ref(), file_hash) {
+                    Ok(_) => format!("Un-banned file: {}\n", file_hash),
+                    Err(err) => format!("Error: {:?}\n", err),
+                }
+            },
+            _ => String::from(
+                "fileban list, fileban add <board_id> <post_num>, fileban rm <file_hash>\n",
+            ),
+        },
+
         "purge" => {
             if parts.len() < 4 {
                 return String::from("purge (dry|exec) <board_id> <post_num>\n");
@@ -214,6 +293,127 @@ where
             str_out
         },
 
+        "fsck" => {
+            let mode = match parts.get(1).map(|mode| mode.trim()) {
+                Some("dry") => actions::FsckMode::Dry,
+                Some("repair") => actions::FsckMode::Repair,
+                Some("delete") => actions::FsckMode::Delete,
+                _ => return String::from("fsck (dry|repair|delete)\n"),
+            };
+
+            let report = match actions.fsck(database.as_ref(), file_rack.as_ref(), mode) {
+                Ok(report) => report,
+                Err(err) => return format!("Error: {:?}\n", err),
+            };
+
+            let mut str_out = String::new();
+
+            for file_ref in &report.missing_files {
+                str_out.push_str(&format!(
+                    "Post {}/#{} is missing file {}\n",
+                    file_ref.board_id, file_ref.post_num, file_ref.file_id
+                ));
+            }
+
+            for counts in &report.miscounted {
+                str_out.push_str(&format!(
+                    "Thread {}/#{} has counts {}/{} but should have {}/{}\n",
+                    counts.board_id,
+                    counts.post_num,
+                    counts.replies,
+                    counts.img_replies,
+                    counts.actual_replies,
+                    counts.actual_img_replies
+                ));
+            }
+
+            for counts in &report.misreferenced {
+                str_out.push_str(&format!(
+                    "File {} has reference count {} but is used {} time(s)\n",
+                    counts.file_id, counts.ref_count, counts.actual_refs
+                ));
+            }
+
+            for file_id in &report.orphaned_files {
+                str_out.push_str(&format!("File {} is not referenced by any post\n", file_id));
+            }
+
+            let problems = report.missing_files.len()
+                + report.miscounted.len()
+                + report.misreferenced.len()
+                + report.orphaned_files.len();
+
+            str_out.push_str(&match mode {
+                actions::FsckMode::Dry => format!("{} problem(s) found\n", problems),
+                _ => format!("{} problem(s) fixed\n", problems),
+            });
+
+            str_out
+        },
+
+        "thumbnails" => match parts.get(1).map(|cmd| cmd.trim()) {
+            Some("regen") => {
+                let report =
+                    actions.regenerate_thumbnails(database.as_ref(), file_rack.as_ref(), &config);
+                match report {
+                    Ok(report) => {
+                        let mut str_out = String::new();
+                        for file_id in &report.failed {
+                            str_out.push_str(&format!("Could not regenerate {}\n", file_id));
+                        }
+                        str_out.push_str(&format!(
+                            "Regenerated thumbnails for {} file(s)\n",
+                            report.regenerated
+                        ));
+                        str_out
+                    },
+                    Err(err) => format!("Error: {:?}\n", err),
+                }
+            },
+            _ => String::from("thumbnails regen\n"),
+        },
+
+        "rack" => match parts.get(1).map(|cmd| cmd.trim()) {
+            Some("migrate") => match actions.migrate_rack_layout(file_rack.as_ref()) {
+                Ok(report) => {
+                    let mut str_out = String::new();
+                    for file_id in &report.failed {
+                        str_out.push_str(&format!("Could not move {}\n", file_id));
+                    }
+                    str_out.push_str(&format!(
+                        "Moved {} file(s) into the current layout\n",
+                        report.migrated
+                    ));
+                    str_out
+                },
+                Err(err) => format!("Error: {:?}\n", err),
+            },
+            _ => String::from("rack migrate\n"),
+        },
+
+        "backup" => {
+            let backup_config = match &config.backup {
+                Some(backup_config) => backup_config,
+                None => return String::from("No [backup] section in config\n"),
+            };
+
+            match backup::create(
+                actions.as_ref(),
+                database.as_ref(),
+                file_rack.as_ref(),
+                backup_config,
+            ) {
+                Ok(backup) => {
+                    let mut str_out = format!("Backup written to {}\n", backup.path.display());
+                    for path in backup.removed {
+                        str_out.push_str(&format!("Removed old backup {}\n", path.display()));
+                    }
+                    str_out
+                },
+                Err(err) => format!("Error: {:?}\n", err),
+            }
+        },
+
         "modq" => {
             if parts.len() < 2 {
                 return String::from("modq <board_id>\n");
@@ -240,11 +440,13 @@ where
                     orig.poster().unwrap_or("Anonymous"),
                     orig.title().unwrap_or("<untitled>")
                 ));
-                str_out.push_str(&format!(
-                    "\t{}/files/{}\n",
-                    site.url.as_deref().unwrap_or(""),
-                    orig.file_id().unwrap_or("")
-                ));
+                for file in orig.files() {
+                    str_out.push_str(&format!(
+                        "\t{}/files/{}\n",
+                        site.url.as_deref().unwrap_or(""),
+                        file.file_id
+                    ));
+                }
                 str_out.push_str(&format!("{}\n\n", orig.body()));
             };
 
@@ -254,11 +456,13 @@ where
                     reply.post_num(),
                     reply.poster().unwrap_or("Anonymous")
                 ));
-                str_out.push_str(&format!(
-                    "\t{}/files/{}\n",
-                    site.url.as_deref().unwrap_or(""),
-                    reply.file_id().unwrap_or("")
-                ));
+                for file in reply.files() {
+                    str_out.push_str(&format!(
+                        "\t{}/files/{}\n",
+                        site.url.as_deref().unwrap_or(""),
+                        file.file_id
+                    ));
+                }
                 str_out.push_str(&format!("{}\n\n", reply.body()));
             };
 
@@ -1,12 +1,93 @@
 use crate::site;
 use crate::util;
 
+use std::path::Path;
+
+// Catalog previews only ever show the start of each thread's body
+pub const PREVIEW_BODY_CHARS: usize = 100;
+
+// The orders in which a catalog can list its threads, each most recent or most first
+#[derive(Hash, PartialEq, Eq, Clone, Copy, Debug)]
+pub enum CatalogSort {
+    Bump,
+    Created,
+    Replies,
+    Images,
+    LastReply,
+}
+
+impl CatalogSort {
+    pub const ALL: [CatalogSort; 5] = [
+        CatalogSort::Bump,
+        CatalogSort::Created,
+        CatalogSort::Replies,
+        CatalogSort::Images,
+        CatalogSort::LastReply,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            CatalogSort::Bump => "bump",
+            CatalogSort::Created => "created",
+            CatalogSort::Replies => "replies",
+            CatalogSort::Images => "images",
+            CatalogSort::LastReply => "last_reply",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<CatalogSort> {
+        CatalogSort::ALL
+            .into_iter()
+            .find(|sort| sort.name() == name)
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            CatalogSort::Bump => "Bump order",
+            CatalogSort::Created => "Creation date",
+            CatalogSort::Replies => "Reply count",
+            CatalogSort::Images => "Image count",
+            CatalogSort::LastReply => "Last reply",
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Thread {
     pub original: site::Original,
     pub replies:  Vec<site::Reply>,
 }
 
+// A post which references a file in the rack
+#[derive(Debug)]
+pub struct FileRef {
+    pub board_id: u64,
+    pub post_num: u64,
+    pub file_id:  String,
+}
+
+// The reference count stored for a file, alongside the number of posts using it
+// Files with no stored count, or which no post uses, count as zero
+#[derive(Debug)]
+pub struct FileRefCount {
+    pub file_id:     String,
+    pub ref_count:   u64,
+    pub actual_refs: u64,
+}
+
+// The reply counts stored for a thread, alongside those derived from its replies
+#[derive(Debug)]
+pub struct ReplyCounts {
+    pub board_id:    u64,
+    pub post_num:    u64,
+    pub replies:     u16,
+    pub img_replies: u16,
+
+    // Counted from the replies themselves
+    pub actual_replies:     u16,
+    pub actual_img_replies: u16,
+}
+
 pub trait Database: Sync + Send + 'static {
     fn get_site(&self) -> Result<site::Site, util::PlainchantErr>;
     fn set_site(&self, site: site::Site) -> Result<(), util::PlainchantErr>;
@@ -18,6 +99,17 @@ pub trait Database: Sync + Send + 'static {
 
     fn get_catalog(&self, board_id: u64) -> Result<site::Catalog, util::PlainchantErr>;
 
+    // Only the live (or only the archived) threads of a board, with each body truncated
+    // to PREVIEW_BODY_CHARS characters - this is all a catalog page needs
+    // A filter keeps only threads whose title or full body contains it, ignoring ASCII case
+    fn get_catalog_preview(
+        &self,
+        board_id: u64,
+        archived: bool,
+        sort: CatalogSort,
+        filter: Option<&str>,
+    ) -> Result<site::Catalog, util::PlainchantErr>;
+
     fn get_original(
         &self,
         board_id: u64,
@@ -59,14 +151,45 @@ pub trait Database: Sync + Send + 'static {
 
     fn get_bans(&self) -> Result<Vec<site::Ban>, util::PlainchantErr>;
 
+    fn get_banned_files(&self) -> Result<Vec<site::BannedFile>, util::PlainchantErr>;
+
+    // Every post which references a file
+    fn get_file_refs(&self) -> Result<Vec<FileRef>, util::PlainchantErr>;
+
+    fn get_reply_counts(&self) -> Result<Vec<ReplyCounts>, util::PlainchantErr>;
+
+    fn get_file_ref_counts(&self) -> Result<Vec<FileRefCount>, util::PlainchantErr>;
+
+    // Overwrite a file's reference count, forgetting the file entirely at zero
+    fn set_file_ref_count(&self, file_id: &str, ref_count: u64) -> Result<(), util::PlainchantErr>;
+
     // These two methods are called with dummy post IDs, which are auto-filled and returned
     fn create_original(&self, orig: site::Original) -> Result<u64, util::PlainchantErr>;
     fn create_reply(&self, reply: site::Reply) -> Result<u64, util::PlainchantErr>;
 
-    fn delete_original(&self, board_id: u64, post_num: u64) -> Result<(), util::PlainchantErr>;
-    fn delete_reply(&self, board_id: u64, post_num: u64) -> Result<(), util::PlainchantErr>;
+    // These two methods return the files which are no longer referenced by any post
+    fn delete_original(
+        &self,
+        board_id: u64,
+        post_num: u64,
+    ) -> Result<Vec<String>, util::PlainchantErr>;
+    fn delete_reply(
+        &self,
+        board_id: u64,
+        post_num: u64,
+    ) -> Result<Vec<String>, util::PlainchantErr>;
 
     fn update_original(&self, orig: site::Original) -> Result<(), util::PlainchantErr>;
+
+    // Archive all but the `post_cap` most recently bumped threads, then keep at most
+    // `archive_cap` archived threads. Only threads whose state changes are written.
+    // Returns the threads beyond both caps, which the caller must delete.
+    fn update_archive(
+        &self,
+        board_id: u64,
+        post_cap: u16,
+        archive_cap: u16,
+    ) -> Result<Vec<u64>, util::PlainchantErr>;
     fn update_post(&self, post: Box<dyn site::Post>) -> Result<(), util::PlainchantErr>;
 
     fn create_board(&self, board: site::Board) -> Result<(), util::PlainchantErr>;
@@ -74,4 +197,10 @@ pub trait Database: Sync + Send + 'static {
 
     fn create_ban(&self, ban: site::Ban) -> Result<(), util::PlainchantErr>;
     fn delete_bans(&self, ip: &str) -> Result<(), util::PlainchantErr>;
+
+    fn create_banned_file(&self, banned: site::BannedFile) -> Result<(), util::PlainchantErr>;
+    fn delete_banned_file(&self, file_hash: &str) -> Result<(), util::PlainchantErr>;
+
+    // Write a consistent copy of the whole database to `dest` while it remains online
+    fn backup(&self, dest: &Path) -> Result<(), util::PlainchantErr>;
 }
@@ -0,0 +1,120 @@
+use axum::http::HeaderMap;
+use axum::http::header;
+
+use bytes::Bytes;
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use std::io::Write;
+
+// Brotli quality 11 is far too slow to run on every render
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW: u32 = 22;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Identity,
+    Gzip,
+    Brotli,
+}
+
+impl Encoding {
+    pub fn content_encoding(&self) -> Option<&'static str> {
+        match self {
+            Encoding::Identity => None,
+            Encoding::Gzip => Some("gzip"),
+            Encoding::Brotli => Some("br"),
+        }
+    }
+
+    // Extension of a precompressed sibling file (e.g. global.css.br)
+    pub fn file_extension(&self) -> Option<&'static str> {
+        match self {
+            Encoding::Identity => None,
+            Encoding::Gzip => Some("gz"),
+            Encoding::Brotli => Some("br"),
+        }
+    }
+
+    // Each encoding is a distinct representation, so it needs a distinct strong ETag
+    pub fn tag_etag(&self, etag: &str) -> String {
+        match self.content_encoding() {
+            Some(enc) => match etag.strip_suffix('"') {
+                Some(inner) => format!("{}-{}\"", inner, enc),
+                None => etag.to_string(),
+            },
+            None => etag.to_string(),
+        }
+    }
+}
+
+// Parse Accept-Encoding and return the acceptable encodings, most preferred first
+// Brotli beats gzip on a tie as it compresses text noticeably better
+pub fn negotiate(headers: &HeaderMap) -> Vec<Encoding> {
+    let accept = match headers
+        .get(header::ACCEPT_ENCODING)
+        .and_then(|val| val.to_str().ok())
+    {
+        Some(accept) => accept,
+        None => return vec![Encoding::Identity],
+    };
+
+    let mut prefs: Vec<(Encoding, u16)> = vec![];
+    let mut wildcard = None;
+
+    for item in accept.split(',') {
+        let mut params = item.split(';');
+        let coding = params.next().unwrap_or("").trim().to_lowercase();
+
+        // Quality values are fixed point with at most three decimal places
+        let mut qvalue = 1000;
+        for param in params {
+            if let Some(q) = param.trim().strip_prefix("q=") {
+                qvalue = q
+                    .trim()
+                    .parse::<f32>()
+                    .map(|q| (q.clamp(0.0, 1.0) * 1000.0) as u16)
+                    .unwrap_or(0);
+            }
+        }
+
+        match coding.as_str() {
+            "br" => prefs.push((Encoding::Brotli, qvalue)),
+            "gzip" | "x-gzip" => prefs.push((Encoding::Gzip, qvalue)),
+            "*" => wildcard = Some(qvalue),
+            _ => {},
+        }
+    }
+
+    if let Some(qvalue) = wildcard {
+        for enc in [Encoding::Brotli, Encoding::Gzip] {
+            if !prefs.iter().any(|(e, _)| *e == enc) {
+                prefs.push((enc, qvalue));
+            }
+        }
+    }
+
+    prefs.retain(|(_, q)| *q > 0);
+    // Stable sort keeps brotli ahead of gzip when the client is indifferent
+    prefs.sort_by_key(|(enc, q)| (u16::MAX - q, *enc != Encoding::Brotli));
+
+    let mut encodings = prefs.into_iter().map(|(enc, _)| enc).collect::<Vec<_>>();
+    encodings.push(Encoding::Identity);
+    encodings
+}
+
+pub fn gzip(data: &[u8]) -> Bytes {
+    let mut encoder = GzEncoder::new(Vec::with_capacity(data.len() / 4), Compression::default());
+    // Writing into a Vec cannot fail
+    let _ = encoder.write_all(data);
+    Bytes::from(encoder.finish().unwrap_or_default())
+}
+
+pub fn brotli(data: &[u8]) -> Bytes {
+    let mut out = Vec::with_capacity(data.len() / 4);
+    {
+        let mut writer =
+            brotli::CompressorWriter::new(&mut out, 4096, BROTLI_QUALITY, BROTLI_WINDOW);
+        let _ = writer.write_all(data);
+    }
+    Bytes::from(out)
+}
@@ -1,4 +1,5 @@
-use crate::site::Feather;
+use crate::media;
+use crate::site::{Feather, FileInfo, PostFile};
 use crate::util;
 use crate::util::URL;
 use chrono::{MappedLocalTime, TimeZone, Utc};
@@ -204,6 +205,141 @@ pub fn display_feather(feather: &Feather) -> String {
     }
 }
 
+pub fn humanise_size(size: u64) -> String {
+    if size >= 1024 * 1024 {
+        format!("{:.1} MB", size as f64 / (1024.0 * 1024.0))
+    } else if size >= 1024 {
+        format!("{} KB", size / 1024)
+    } else {
+        format!("{} B", size)
+    }
+}
+
+// For example "1:05" or "0:03.5" - short clips are often shorter than a second
+pub fn humanise_duration(duration_ms: u64) -> String {
+    let secs = duration_ms / 1000;
+    if secs < 10 && !duration_ms.is_multiple_of(1000) {
+        format!("0:{:02}.{}", secs, (duration_ms % 1000) / 100)
+    } else {
+        format!("{}:{:02}", secs / 60, secs % 60)
+    }
+}
+
+// For example "1920x1080, 342 KB, PNG" or "1280x720, 2.1 MB, WEBM, 0:12"
+pub fn display_file_info(info: Option<&FileInfo>) -> String {
+    match info {
+        Some(info) => {
+            let mut text = format!(
+                "{}x{}, {}, {}",
+                info.width,
+                info.height,
+                humanise_size(info.size),
+                info.format
+            );
+            if let Some(duration_ms) = info.duration_ms {
+                text.push_str(&format!(", {}", humanise_duration(duration_ms)));
+            }
+            text
+        },
+        None => String::from(""),
+    }
+}
+
+// Files stored before their format was recorded are all images
+pub fn is_video(info: Option<&FileInfo>) -> bool {
+    info.is_some_and(|info| media::is_video(&info.format))
+}
+
+// Lets the browser reserve space for a thumbnail before it has loaded
+// Sizes are worked out from the file, as each kind of thumbnail has its own size
+pub fn thumb_size_attrs(info: Option<&FileInfo>, max: u32) -> String {
+    match info {
+        Some(info) => {
+            let (width, height) = media::thumb_dimensions(info.width, info.height, max);
+            format!("width=\"{}\" height=\"{}\"", width, height)
+        },
+        None => String::from(""),
+    }
+}
+
+// The placeholder shown in place of a spoilered file's thumbnail is square
+const SPOILER_SIZE: u32 = 100;
+
+pub fn spoiler_size_attrs(max: u32) -> String {
+    let size = SPOILER_SIZE.min(max);
+    format!("width=\"{}\" height=\"{}\"", size, size)
+}
+
+// A post's files side by side, each thumbnail linking to the full file
+// Videos play in place instead, with their thumbnail as a poster
+// Spoilered files show a placeholder, which links to the file to reveal it
+pub fn display_files(
+    files: &[PostFile],
+    kind: media::ThumbKind,
+    max: u32,
+    spoiler_url: &str,
+) -> String {
+    files
+        .iter()
+        .map(|file| {
+            let file_url = format!("/files/{}", file.file_id);
+            let thumb_url = format!("/thumbnails/{}/{}", kind.name(), file.file_id);
+            let title = html_escape_attr(file.file_name.as_deref().unwrap_or(""));
+            let alt = alt_text(file);
+            let size = thumb_size_attrs(file.file_info.as_ref(), max);
+
+            let media = if file.spoiler {
+                format!(
+                    "<a href=\"{}\"><img class=\"post-image spoiler-image\" title=\"Spoiler\" alt=\"Spoiler\" src=\"{}\" {}></img></a>",
+                    file_url,
+                    spoiler_url,
+                    spoiler_size_attrs(max)
+                )
+            } else if is_video(file.file_info.as_ref()) {
+                format!(
+                    "<video class=\"post-image\" title=\"{}\" aria-label=\"{}\" src=\"{}\" poster=\"{}\" {} controls loop preload=\"none\"></video>",
+                    title, alt, file_url, thumb_url, size
+                )
+            } else {
+                format!(
+                    "<a href=\"{}\"><img class=\"post-image\" title=\"{}\" alt=\"{}\" src=\"{}\" {}></img></a>",
+                    file_url, title, alt, thumb_url, size
+                )
+            };
+
+            format!(
+                "<div class=\"post-image-frame\">{}<div class=\"file-info\">{}</div></div>",
+                media,
+                display_file_info(file.file_info.as_ref())
+            )
+        })
+        .collect()
+}
+
+// The poster's description of a file, or else its name, ready for an attribute
+pub fn alt_text(file: &PostFile) -> String {
+    html_escape_attr(
+        file.alt_text
+            .as_deref()
+            .or(file.file_name.as_deref())
+            .unwrap_or(""),
+    )
+}
+
+pub fn html_escape_attr(text: &str) -> String {
+    let mut buf = String::new();
+    for c in text.chars() {
+        match c {
+            '<' => buf.push_str("&lt;"),
+            '>' => buf.push_str("&gt;"),
+            '&' => buf.push_str("&amp;"),
+            '"' => buf.push_str("&quot;"),
+            c => buf.push(c),
+        }
+    }
+    buf
+}
+
 pub fn html_escape_and_trim(text: &str) -> String {
     let mut buf = String::new();
     for c in text.trim().chars() {
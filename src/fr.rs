@@ -1,6 +1,12 @@
+use crate::media;
+use crate::site;
 use crate::util;
 
 use bytes::Bytes;
+use dashmap::DashMap;
+use std::path::Path;
+use std::time::SystemTime;
+use tokio::io::AsyncRead;
 
 pub fn static_err(msg: &'static str) -> util::PlainchantErr {
     util::PlainchantErr {
@@ -11,9 +17,204 @@ pub fn static_err(msg: &'static str) -> util::PlainchantErr {
     }
 }
 
+// Files are addressed by the SHA-256 of their contents, so identical uploads share storage
+pub fn content_id(file: &Bytes) -> String {
+    sha256::digest(file.as_ref())
+}
+
+// Files uploaded before content addressing keep their random 12 character IDs,
+// so only longer IDs are known to be hashes
+pub fn content_hash(file_id: &str) -> Option<&str> {
+    if file_id.len() == 64 && file_id.bytes().all(|b| b.is_ascii_hexdigit()) {
+        Some(file_id)
+    } else {
+        None
+    }
+}
+
+// TODO: Implement memory cap (currently grows arbitrarily large)
+pub struct Cache {
+    inner: DashMap<String, Bytes>,
+}
+
+impl Cache {
+    pub fn new() -> Self {
+        Self {
+            inner: DashMap::new(),
+        }
+    }
+
+    #[allow(unused)]
+    pub fn contains(&self, key: &str) -> Result<bool, util::PlainchantErr> {
+        Ok(self.inner.contains_key(key))
+    }
+
+    pub fn retrieve(&self, key: &str) -> Result<Option<Bytes>, util::PlainchantErr> {
+        Ok(self.inner.get(key).map(|bytes| (*bytes).clone()))
+    }
+
+    pub fn store(&self, key: &str, buf: Bytes) -> Result<(), util::PlainchantErr> {
+        self.inner.insert(key.to_string(), buf);
+        Ok(())
+    }
+
+    pub fn delete(&self, key: &str) -> Result<(), util::PlainchantErr> {
+        self.inner.remove(key);
+        Ok(())
+    }
+}
+
+// Files are stored alongside a thumbnail of each kind
+pub fn thumb_id(file_id: &str, kind: media::ThumbKind) -> String {
+    format!("{}_thumb_{}", file_id, kind.name())
+}
+
+// Files stored before thumbnails were configurable have a single JPEG thumbnail
+pub fn legacy_thumb_id(file_id: &str) -> String {
+    format!("{}_thumb.jpeg", file_id)
+}
+
+// Every name a file's thumbnails might be stored under
+pub fn thumb_ids(file_id: &str) -> Vec<String> {
+    let mut ids = media::ThumbKind::ALL
+        .iter()
+        .map(|kind| thumb_id(file_id, *kind))
+        .collect::<Vec<_>>();
+    ids.push(legacy_thumb_id(file_id));
+    ids
+}
+
+// Open a file and make a thumbnail of each kind for it
+pub fn make_thumbnails(
+    file: &Bytes,
+    limits: image::Limits,
+    thumbs: &media::ThumbnailConfig,
+) -> Result<(site::FileInfo, Vec<(media::ThumbKind, Bytes)>), util::PlainchantErr> {
+    let media = media::Media::open(file, limits)?;
+
+    let mut made = vec![];
+    for kind in media::ThumbKind::ALL {
+        made.push((kind, media.thumbnail(thumbs, kind)?));
+    }
+
+    let (thumb_width, thumb_height) =
+        media::thumb_dimensions(media.width, media.height, thumbs.size(media::ThumbKind::Op));
+
+    let info = site::FileInfo {
+        width: media.width,
+        height: media.height,
+        size: file.len() as u64,
+        format: media.media_type.name(),
+        duration_ms: media.duration_ms,
+        thumb_width,
+        thumb_height,
+    };
+
+    Ok((info, made))
+}
+
+// A reader over some contiguous byte range of a stored file
+pub type FileReader = Box<dyn AsyncRead + Send + Unpin>;
+
+pub struct FileStat {
+    pub len:      u64,
+    pub modified: SystemTime,
+}
+
+// How a reverse proxy in front of the site is told to serve a file from disk itself
+#[derive(Clone)]
+pub enum Offload {
+    // nginx, which serves the file from an internal location under this URI prefix
+    XAccelRedirect(String),
+    // Apache and lighttpd, which are given the file's absolute path
+    XSendfile,
+}
+
+impl Offload {
+    pub fn header(&self) -> &'static str {
+        match self {
+            Offload::XAccelRedirect(_) => "X-Accel-Redirect",
+            Offload::XSendfile => "X-Sendfile",
+        }
+    }
+}
+
+// A file for the reverse proxy to serve, given in its header
+pub struct ProxyFile {
+    pub header:       &'static str,
+    pub location:     String,
+    pub content_type: &'static str,
+}
+
 pub trait FileRack: Sync + Send + 'static {
-    fn store_file(&self, file_id: &str, file: Bytes) -> Result<(), util::PlainchantErr>;
+    // Uploads are staged until their post is accepted, and then either committed to
+    // the rack under their file ID or discarded. Staged files cannot be retrieved.
+    // Committing a file which is already in the rack leaves the existing file in place.
+    fn stage_file(
+        &self,
+        stage_id: &str,
+        file: Bytes,
+    ) -> Result<site::FileInfo, util::PlainchantErr>;
+    fn commit_file(&self, stage_id: &str, file_id: &str) -> Result<(), util::PlainchantErr>;
+    fn discard_file(&self, stage_id: &str) -> Result<(), util::PlainchantErr>;
+
     fn get_file(&self, file_id: &str) -> Result<Bytes, util::PlainchantErr>;
-    fn get_file_thumbnail(&self, file_id: &str) -> Result<Bytes, util::PlainchantErr>;
+    fn get_file_thumbnail(
+        &self,
+        file_id: &str,
+        kind: media::ThumbKind,
+    ) -> Result<Bytes, util::PlainchantErr>;
+    // Replace a file's thumbnails with ones made using the current settings
+    fn regenerate_thumbnails(&self, file_id: &str) -> Result<site::FileInfo, util::PlainchantErr>;
     fn delete_file(&self, file_id: &str) -> Result<(), util::PlainchantErr>;
+
+    // The IDs of every file in the rack
+    fn list_files(&self) -> Result<Vec<String>, util::PlainchantErr>;
+
+    fn stat_file(&self, file_id: &str) -> Result<FileStat, util::PlainchantErr>;
+
+    // Open `len` bytes of a file starting from `start` without buffering them
+    fn read_file_range(
+        &self,
+        file_id: &str,
+        start: u64,
+        len: u64,
+    ) -> Result<FileReader, util::PlainchantErr>;
+
+    // Copy the given files and their thumbnails into `dest`, laid out as an FS file rack
+    fn snapshot_files(&self, file_ids: &[String], dest: &Path) -> Result<(), util::PlainchantErr>;
+
+    // Move a file stored under an older layout of the rack into the current one,
+    // returning whether it needed moving
+    fn migrate_layout(&self, _file_id: &str) -> Result<bool, util::PlainchantErr> {
+        Ok(false)
+    }
+
+    // Racks which a reverse proxy can read from directly may have it serve their files,
+    // so that only a file's existence and type are checked here. Ok(None) means the
+    // file should be served as usual.
+    fn offload_file(&self, _file_id: &str) -> Result<Option<ProxyFile>, util::PlainchantErr> {
+        Ok(None)
+    }
+    fn offload_thumbnail(
+        &self,
+        _file_id: &str,
+        _kind: media::ThumbKind,
+    ) -> Result<Option<ProxyFile>, util::PlainchantErr> {
+        Ok(None)
+    }
+
+    // Racks which can serve files to clients themselves give a URL to redirect them to,
+    // rather than having the files proxied through the site. As with offloading, the
+    // file's existence is checked, and Ok(None) means it should be served as usual.
+    fn file_url(&self, _file_id: &str) -> Result<Option<String>, util::PlainchantErr> {
+        Ok(None)
+    }
+    fn thumbnail_url(
+        &self,
+        _file_id: &str,
+        _kind: media::ThumbKind,
+    ) -> Result<Option<String>, util::PlainchantErr> {
+        Ok(None)
+    }
 }
@@ -1,47 +1,43 @@
 use crate::fr;
+use crate::media;
+use crate::site;
 use crate::util;
 use bytes::Bytes;
-use dashmap::DashMap;
 use std::fs;
 use std::fs::File;
-use std::io::{Cursor, Read, Write};
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
+use tokio::io::AsyncReadExt;
 
-// TODO: Implement memory cap (currently grows arbitrarily large)
-struct Cache {
-    inner: DashMap<String, Bytes>,
-}
-
-impl Cache {
-    fn new() -> Self {
-        Self {
-            inner: DashMap::new(),
-        }
-    }
-
-    #[allow(unused)]
-    fn contains(&self, key: &str) -> Result<bool, util::PlainchantErr> {
-        Ok(self.inner.contains_key(key))
-    }
-
-    fn retrieve(&self, key: &str) -> Result<Option<Bytes>, util::PlainchantErr> {
-        Ok(self.inner.get(key).map(|bytes| (*bytes).clone()))
-    }
-
-    fn store(&self, key: &str, buf: Bytes) -> Result<(), util::PlainchantErr> {
-        self.inner.insert(key.to_string(), buf);
-        Ok(())
+// Files are spread over two levels of subdirectories named after the start of their ID,
+// so that no one directory grows too large, and thumbnails are kept beside their file.
+// Racks written before sharding keep everything directly in the rack directory.
+pub fn sharded_path(rack_dir: &Path, file_id: &str, name: &str) -> PathBuf {
+    match file_id.get(..4) {
+        Some(prefix) if prefix.bytes().all(|b| b.is_ascii_alphanumeric()) => {
+            rack_dir.join(&prefix[..2]).join(&prefix[2..]).join(name)
+        },
+        _ => rack_dir.join(name),
     }
+}
 
-    fn delete(&self, key: &str) -> Result<(), util::PlainchantErr> {
-        self.inner.remove(key);
-        Ok(())
-    }
+// File IDs come from request paths, so anything which could name a file outside of the
+// rack is refused
+fn is_rack_name(name: &str) -> bool {
+    !name.starts_with('.')
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'.')
 }
 
 pub struct FSFileRack {
-    file_dir: PathBuf,
-    cache:    Cache,
+    file_dir:  PathBuf,
+    stage_dir: PathBuf,
+    cache:     fr::Cache,
+    limits:    image::Limits,
+    thumbs:    media::ThumbnailConfig,
+    offload:   Option<fr::Offload>,
 }
 
 impl FSFileRack {
@@ -56,94 +52,434 @@ impl FSFileRack {
             return Err(fr::static_err("Failed to create fsfr /rack directory"));
         }
 
-        Ok(FSFileRack {
-            file_dir: fr_path,
-            cache:    Cache::new(),
-        })
+        // Anything left in staging belongs to a submission interrupted by a restart
+        let stage_path = dir.join("staging").to_path_buf();
+
+        if stage_path.is_dir() && fs::remove_dir_all(&stage_path).is_err() {
+            return Err(fr::static_err("Failed to clear fsfr /staging directory"));
+        }
+
+        if fs::create_dir(&stage_path).is_err() {
+            return Err(fr::static_err("Failed to create fsfr /staging directory"));
+        }
+
+        Ok(FSFileRack::with_dirs(fr_path, stage_path))
     }
 
-    fn thumb_id(file_id: &str) -> String {
-        format!("{}_thumb.jpeg", file_id)
+    // Open an existing rack, such as a backup's, only to read from it
+    // Nothing under the directory is created or cleared, so staging files is unsupported
+    pub fn open_read_only(dir: &Path) -> Result<FSFileRack, util::PlainchantErr> {
+        let fr_path = dir.join("rack").to_path_buf();
+
+        if !fr_path.is_dir() {
+            return Err(fr::static_err("FS File Rack has no /rack directory"));
+        }
+
+        Ok(FSFileRack::with_dirs(
+            fr_path,
+            dir.join("staging").to_path_buf(),
+        ))
     }
 
-    fn retrieve_file(&self, file_id: &str) -> Result<Bytes, util::PlainchantErr> {
-        if let Some(buf) = self.cache.retrieve(file_id)? {
-            return Ok(buf);
+    fn with_dirs(file_dir: PathBuf, stage_dir: PathBuf) -> FSFileRack {
+        FSFileRack {
+            file_dir,
+            stage_dir,
+            cache: fr::Cache::new(),
+            limits: image::Limits::default(),
+            thumbs: media::ThumbnailConfig::default(),
+            offload: None,
         }
+    }
 
-        let f_res = File::open(self.file_dir.join(file_id));
-        match f_res {
-            Ok(f) => {
-                let bytes_res = f.bytes().collect::<Result<Vec<u8>, std::io::Error>>();
-                match bytes_res {
-                    Ok(bytes) => {
-                        let bytes = Bytes::from(bytes);
-                        self.cache.store(file_id, bytes.clone())?;
-                        Ok(bytes)
-                    },
-                    Err(_read_err) => {
-                        Err(fr::static_err("Could not read bytes from requested file"))
-                    },
-                }
+    // Limits on decoding uploaded images, which otherwise only have image's defaults
+    pub fn with_decode_limits(mut self, limits: image::Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn with_thumbnails(mut self, thumbs: media::ThumbnailConfig) -> Self {
+        self.thumbs = thumbs;
+        self
+    }
+
+    pub fn with_offload(mut self, offload: Option<fr::Offload>) -> Self {
+        self.offload = offload;
+        self
+    }
+
+    fn write_file(path: &Path, buf: &[u8]) -> Result<(), util::PlainchantErr> {
+        let mut fd = File::create(path)
+            .map_err(|_| fr::static_err("Could not open requested write file"))?;
+        fd.write_all(buf)
+            .map_err(|_| fr::static_err("Could not write to requested file"))
+    }
+
+    // Open a file and write a thumbnail of each kind for it into `dir`
+    fn write_thumbnails(
+        &self,
+        file: &Bytes,
+        dir: &Path,
+        name: &str,
+    ) -> Result<site::FileInfo, util::PlainchantErr> {
+        let (info, thumbs) = fr::make_thumbnails(file, self.limits.clone(), &self.thumbs)?;
+
+        for (kind, thumb) in thumbs {
+            FSFileRack::write_file(&dir.join(fr::thumb_id(name, kind)), &thumb)?;
+        }
+
+        Ok(info)
+    }
+
+    fn remove_if_present(path: &Path) -> Result<(), util::PlainchantErr> {
+        match fs::remove_file(path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                Err(fr::static_err("Could not delete file"))
             },
-            Err(_read_err) => Err(fr::static_err("Could not open requested file")),
+            _ => Ok(()),
         }
     }
-}
 
-impl fr::FileRack for FSFileRack {
-    fn store_file(&self, file_id: &str, file: Bytes) -> Result<(), util::PlainchantErr> {
-        let img = image::load_from_memory(file.as_ref())
-            .map_err(|_| fr::static_err("Could not handle file"))?;
+    // Where `name`, which is `file_id` or one of its thumbnails, belongs in the rack
+    fn rack_path(&self, file_id: &str, name: &str) -> PathBuf {
+        sharded_path(&self.file_dir, file_id, name)
+    }
 
-        let thumb = img.thumbnail(300, 300).to_rgb8();
+    fn flat_path(&self, name: &str) -> PathBuf {
+        self.file_dir.join(name)
+    }
 
-        let mut fd = File::create(self.file_dir.join(file_id))
-            .map_err(|_| fr::static_err("Could not open requested write file"))?;
+    // Files which have not been moved into the sharded layout are still in the flat one,
+    // and may be moved between the first two attempts
+    fn with_either<T>(
+        &self,
+        file_id: &str,
+        name: &str,
+        op: impl Fn(&Path) -> io::Result<T>,
+    ) -> io::Result<T> {
+        if !is_rack_name(name) {
+            return Err(io::ErrorKind::NotFound.into());
+        }
 
-        fd.write(&file)
-            .map_err(|_| fr::static_err("Could not write to requested file"))?;
+        let path = self.rack_path(file_id, name);
+        op(&path)
+            .or_else(|_| op(&self.flat_path(name)))
+            .or_else(|_| op(&path))
+    }
 
-        let thumb_id = FSFileRack::thumb_id(file_id);
-        let thumb_path = self.file_dir.join(&thumb_id);
+    fn exists(&self, file_id: &str, name: &str) -> bool {
+        self.rack_path(file_id, name).is_file() || self.flat_path(name).is_file()
+    }
 
-        let mut thumb_buf: Vec<u8> = vec![];
-        thumb
-            .write_to(&mut Cursor::new(&mut thumb_buf), image::ImageFormat::Jpeg)
-            .map_err(|_| fr::static_err("Could not write thumbnail buffer"))?;
-        let thumb_buf = Bytes::from(thumb_buf);
+    // Move a staged or regenerated file into its place in the rack
+    fn place(&self, from: &Path, file_id: &str, name: &str) -> io::Result<()> {
+        let path = self.rack_path(file_id, name);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(from, path)
+    }
 
-        let mut thumbfd = File::create(thumb_path)
-            .map_err(|_| fr::static_err("Could not open thumbnail file for writing"))?;
-        thumbfd
-            .write(&thumb_buf)
-            .map_err(|_| fr::static_err("Could not write to thumbnail file"))?;
+    // Find a file on disk in either layout, and tell the proxy where it is
+    fn proxy_file(
+        &self,
+        offload: &fr::Offload,
+        file_id: &str,
+        name: &str,
+    ) -> Result<fr::ProxyFile, util::PlainchantErr> {
+        let (path, head) = self
+            .with_either(file_id, name, |path| {
+                let mut head = vec![];
+                File::open(path)?
+                    .take(media::SNIFF_LENGTH)
+                    .read_to_end(&mut head)?;
+                Ok((path.to_path_buf(), head))
+            })
+            .map_err(|_| fr::static_err("Could not open requested file"))?;
 
-        self.cache.store(file_id, file)?;
-        self.cache.store(&thumb_id, thumb_buf)?;
+        let location = match offload {
+            fr::Offload::XAccelRedirect(prefix) => {
+                let rel_path = path
+                    .strip_prefix(&self.file_dir)
+                    .map_err(|_| fr::static_err("File is outside of the rack"))?
+                    .iter()
+                    .map(|part| part.to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/");
+                format!("{}/{}", prefix.trim_end_matches('/'), rel_path)
+            },
+            fr::Offload::XSendfile => path.to_string_lossy().into_owned(),
+        };
+
+        Ok(fr::ProxyFile {
+            header: offload.header(),
+            location,
+            content_type: media::mime_type(&head),
+        })
+    }
+
+    fn read_file(&self, file_id: &str, name: &str) -> Result<Bytes, util::PlainchantErr> {
+        self.with_either(file_id, name, |path| fs::read(path))
+            .map(Bytes::from)
+            .map_err(|_| fr::static_err("Could not read requested file"))
+    }
+
+    // Only thumbnails are cached, as full files can be far larger and are mostly
+    // served by range
+    fn retrieve_file(&self, file_id: &str, name: &str) -> Result<Bytes, util::PlainchantErr> {
+        if let Some(buf) = self.cache.retrieve(name)? {
+            return Ok(buf);
+        }
+
+        let bytes = self.read_file(file_id, name)?;
+        self.cache.store(name, bytes.clone())?;
+        Ok(bytes)
+    }
+}
+
+impl fr::FileRack for FSFileRack {
+    fn stage_file(
+        &self,
+        stage_id: &str,
+        file: Bytes,
+    ) -> Result<site::FileInfo, util::PlainchantErr> {
+        let info = self.write_thumbnails(&file, &self.stage_dir, stage_id)?;
+        FSFileRack::write_file(&self.stage_dir.join(stage_id), &file)?;
+        Ok(info)
+    }
+
+    // Staging is on the same filesystem as the rack, so committing is just a rename
+    // The thumbnails go first, so a file in the rack always has its thumbnails
+    fn commit_file(&self, stage_id: &str, file_id: &str) -> Result<(), util::PlainchantErr> {
+        if self.exists(file_id, file_id) {
+            return self.discard_file(stage_id);
+        }
+
+        for kind in media::ThumbKind::ALL {
+            self.place(
+                &self.stage_dir.join(fr::thumb_id(stage_id, kind)),
+                file_id,
+                &fr::thumb_id(file_id, kind),
+            )
+            .map_err(|_| fr::static_err("Could not commit thumbnail file"))?;
+        }
+
+        self.place(&self.stage_dir.join(stage_id), file_id, file_id)
+            .map_err(|_| fr::static_err("Could not commit file"))?;
 
         Ok(())
     }
 
+    fn discard_file(&self, stage_id: &str) -> Result<(), util::PlainchantErr> {
+        for name in fr::thumb_ids(stage_id) {
+            FSFileRack::remove_if_present(&self.stage_dir.join(name))?;
+        }
+
+        FSFileRack::remove_if_present(&self.stage_dir.join(stage_id))
+    }
+
     fn get_file(&self, file_id: &str) -> Result<Bytes, util::PlainchantErr> {
-        self.retrieve_file(file_id)
+        self.read_file(file_id, file_id)
+    }
+
+    fn stat_file(&self, file_id: &str) -> Result<fr::FileStat, util::PlainchantErr> {
+        let meta = self
+            .with_either(file_id, file_id, |path| fs::metadata(path))
+            .map_err(|_| fr::static_err("Could not stat requested file"))?;
+
+        Ok(fr::FileStat {
+            len:      meta.len(),
+            modified: meta
+                .modified()
+                .map_err(|_| fr::static_err("Could not get file modification time"))?,
+        })
+    }
+
+    fn read_file_range(
+        &self,
+        file_id: &str,
+        start: u64,
+        len: u64,
+    ) -> Result<fr::FileReader, util::PlainchantErr> {
+        let mut fd = self
+            .with_either(file_id, file_id, |path| File::open(path))
+            .map_err(|_| fr::static_err("Could not open requested file"))?;
+
+        fd.seek(SeekFrom::Start(start))
+            .map_err(|_| fr::static_err("Could not seek in requested file"))?;
+
+        Ok(Box::new(tokio::fs::File::from_std(fd).take(len)))
     }
 
-    fn get_file_thumbnail(&self, file_id: &str) -> Result<Bytes, util::PlainchantErr> {
-        self.retrieve_file(&FSFileRack::thumb_id(file_id))
+    fn get_file_thumbnail(
+        &self,
+        file_id: &str,
+        kind: media::ThumbKind,
+    ) -> Result<Bytes, util::PlainchantErr> {
+        self.retrieve_file(file_id, &fr::thumb_id(file_id, kind))
+            .or_else(|_| self.retrieve_file(file_id, &fr::legacy_thumb_id(file_id)))
+    }
+
+    // New thumbnails are written beside the old ones and renamed over them,
+    // so they are never seen half written
+    fn regenerate_thumbnails(&self, file_id: &str) -> Result<site::FileInfo, util::PlainchantErr> {
+        let file = self.read_file(file_id, file_id)?;
+        let tmp_name = format!("{}.regen", file_id);
+        let info = self.write_thumbnails(&file, &self.stage_dir, &tmp_name)?;
+
+        for kind in media::ThumbKind::ALL {
+            let thumb_id = fr::thumb_id(file_id, kind);
+            self.place(
+                &self.stage_dir.join(fr::thumb_id(&tmp_name, kind)),
+                file_id,
+                &thumb_id,
+            )
+            .map_err(|_| fr::static_err("Could not replace thumbnail file"))?;
+            // The new thumbnail takes precedence, but an unsharded old one is left behind
+            if self.rack_path(file_id, &thumb_id) != self.flat_path(&thumb_id) {
+                FSFileRack::remove_if_present(&self.flat_path(&thumb_id))?;
+            }
+            self.cache.delete(&thumb_id)?;
+        }
+
+        let legacy_id = fr::legacy_thumb_id(file_id);
+        FSFileRack::remove_if_present(&self.rack_path(file_id, &legacy_id))?;
+        FSFileRack::remove_if_present(&self.flat_path(&legacy_id))?;
+        self.cache.delete(&legacy_id)?;
+
+        Ok(info)
+    }
+
+    // Both layouts are listed, as the rack may be part way through being sharded
+    fn list_files(&self) -> Result<Vec<String>, util::PlainchantErr> {
+        let read_dir = |dir: &Path| {
+            fs::read_dir(dir)
+                .map(|entries| entries.flatten().collect::<Vec<_>>())
+                .map_err(|_| fr::static_err("Could not read fsfr /rack directory"))
+        };
+
+        let mut file_ids = vec![];
+        for entry in read_dir(&self.file_dir)? {
+            let path = entry.path();
+            if path.is_dir() {
+                // Stray files beside the shards are not in the rack
+                for shard in read_dir(&path)?
+                    .into_iter()
+                    .filter(|shard| shard.path().is_dir())
+                {
+                    file_ids.extend(
+                        read_dir(&shard.path())?
+                            .into_iter()
+                            .filter_map(|entry| entry.file_name().into_string().ok()),
+                    );
+                }
+            } else if let Ok(name) = entry.file_name().into_string() {
+                file_ids.push(name);
+            }
+        }
+
+        file_ids.retain(|name| !name.contains("_thumb"));
+        Ok(file_ids)
+    }
+
+    // Snapshots are always written in the sharded layout
+    fn snapshot_files(&self, file_ids: &[String], dest: &Path) -> Result<(), util::PlainchantErr> {
+        let dest_dir = dest.join("rack");
+        fs::create_dir_all(&dest_dir)
+            .map_err(|_| fr::static_err("Could not create snapshot rack directory"))?;
+
+        for file_id in file_ids {
+            let mut names = vec![file_id.to_string()];
+            names.extend(fr::thumb_ids(file_id));
+
+            for name in names {
+                let dest_path = sharded_path(&dest_dir, file_id, &name);
+
+                // Never copy over an existing file - it may be a hard link to the source
+                // A file only has some of the possible thumbnail names
+                if dest_path.exists() || (name != *file_id && !self.exists(file_id, &name)) {
+                    continue;
+                }
+
+                if let Some(parent) = dest_path.parent() {
+                    fs::create_dir_all(parent)
+                        .map_err(|_| fr::static_err("Could not create snapshot rack directory"))?;
+                }
+
+                // Rack files are immutable and regenerated thumbnails are renamed over
+                // the old ones rather than modified, so a hard link is as good as a copy
+                // It can only fail if the snapshot is on a different filesystem
+                self.with_either(file_id, &name, |src_path| {
+                    fs::hard_link(src_path, &dest_path)
+                        .or_else(|_| fs::copy(src_path, &dest_path).map(|_| ()))
+                })
+                .map_err(|_| fr::static_err("Could not copy file into snapshot"))?;
+            }
+        }
+
+        Ok(())
     }
 
     fn delete_file(&self, file_id: &str) -> Result<(), util::PlainchantErr> {
-        let thumb_id = FSFileRack::thumb_id(file_id);
         self.cache.delete(file_id)?;
-        self.cache.delete(&thumb_id)?;
 
-        let file_path = self.file_dir.join(file_id);
-        fs::remove_file(file_path).map_err(|_| fr::static_err("Could not delete file"))?;
-        let thumb_path = self.file_dir.join(&thumb_id);
-        fs::remove_file(thumb_path)
-            .map_err(|_| fr::static_err("Could not delete thumbnail file"))?;
+        let file_path = self.rack_path(file_id, file_id);
+        fs::remove_file(&file_path)
+            .or_else(|_| fs::remove_file(self.flat_path(file_id)))
+            .map_err(|_| fr::static_err("Could not delete file"))?;
+
+        // A file only has some of the possible thumbnails, and they may never have
+        // been written if storing the file was interrupted
+        for thumb_id in fr::thumb_ids(file_id) {
+            self.cache.delete(&thumb_id)?;
+            FSFileRack::remove_if_present(&self.rack_path(file_id, &thumb_id))?;
+            FSFileRack::remove_if_present(&self.flat_path(&thumb_id))?;
+        }
 
         Ok(())
     }
+
+    fn offload_file(&self, file_id: &str) -> Result<Option<fr::ProxyFile>, util::PlainchantErr> {
+        self.offload
+            .as_ref()
+            .map(|offload| self.proxy_file(offload, file_id, file_id))
+            .transpose()
+    }
+
+    fn offload_thumbnail(
+        &self,
+        file_id: &str,
+        kind: media::ThumbKind,
+    ) -> Result<Option<fr::ProxyFile>, util::PlainchantErr> {
+        self.offload
+            .as_ref()
+            .map(|offload| {
+                self.proxy_file(offload, file_id, &fr::thumb_id(file_id, kind))
+                    .or_else(|_| self.proxy_file(offload, file_id, &fr::legacy_thumb_id(file_id)))
+            })
+            .transpose()
+    }
+
+    // The thumbnails go first, so a file in the sharded layout always has its thumbnails
+    // there too. Each move is a rename, so the file can be read throughout.
+    fn migrate_layout(&self, file_id: &str) -> Result<bool, util::PlainchantErr> {
+        if self.rack_path(file_id, file_id) == self.flat_path(file_id)
+            || !self.flat_path(file_id).is_file()
+        {
+            return Ok(false);
+        }
+
+        let mut names = fr::thumb_ids(file_id);
+        names.push(file_id.to_string());
+
+        for name in names {
+            let flat_path = self.flat_path(&name);
+            if flat_path.is_file() {
+                self.place(&flat_path, file_id, &name)
+                    .map_err(|_| fr::static_err("Could not move file into sharded layout"))?;
+            }
+        }
+
+        Ok(true)
+    }
 }
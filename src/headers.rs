@@ -0,0 +1,346 @@
+use axum::http::HeaderMap;
+use axum::http::header;
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Cache-Control policies for each type of route
+
+// Rendered pages change at any moment, so they must always be revalidated
+pub const CACHE_PAGE: &str = "no-cache";
+// Unhashed static URLs may change whenever the site is redeployed
+pub const CACHE_STATIC: &str = "public, max-age=300";
+// Fingerprinted static URLs change name whenever their content changes
+pub const CACHE_STATIC_FINGERPRINTED: &str = "public, max-age=31536000, immutable";
+// Rack files are never modified once stored
+pub const CACHE_FILE: &str = "public, max-age=604800, immutable";
+// Thumbnails are remade when thumbnail settings change, so they may not be cached forever
+pub const CACHE_THUMBNAIL: &str = "public, max-age=86400";
+// API responses are small and always fresh
+pub const CACHE_API: &str = "no-cache";
+// Everything else (errors, redirects, forms) should not be cached at all
+pub const CACHE_NONE: &str = "no-store";
+
+// Outcome of evaluating a request's Range header against a resource
+#[derive(Debug, PartialEq, Eq)]
+pub enum ByteRange {
+    Full,
+    // Inclusive start and end offsets
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+impl ByteRange {
+    pub fn content_range(&self, len: u64) -> Option<String> {
+        match self {
+            ByteRange::Full => None,
+            ByteRange::Partial(start, end) => Some(format!("bytes {}-{}/{}", start, end, len)),
+            ByteRange::Unsatisfiable => Some(format!("bytes */{}", len)),
+        }
+    }
+}
+
+fn header_str(headers: &HeaderMap, name: header::HeaderName) -> Option<&str> {
+    headers.get(name).and_then(|val| val.to_str().ok())
+}
+
+// HTTP dates only have a resolution of one second
+fn truncate_to_secs(time: SystemTime) -> SystemTime {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::from_secs(0))
+        .as_secs();
+    UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+pub fn http_date(time: SystemTime) -> String {
+    httpdate::fmt_http_date(time)
+}
+
+pub fn timestamp_to_system_time(ts: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(ts)
+}
+
+// Weak comparison of entity tags, as required for If-None-Match
+fn etag_weak_eq(a: &str, b: &str) -> bool {
+    a.trim().trim_start_matches("W/") == b.trim().trim_start_matches("W/")
+}
+
+// Evaluate If-None-Match alone, for resources without a meaningful modification time
+pub fn etag_matches(headers: &HeaderMap, etag: &str) -> bool {
+    header_str(headers, header::IF_NONE_MATCH).is_some_and(|if_none_match| {
+        if_none_match
+            .split(',')
+            .any(|tag| tag.trim() == "*" || etag_weak_eq(tag, etag))
+    })
+}
+
+// Evaluate If-None-Match and If-Modified-Since for a GET request
+// If-None-Match takes precedence when both are present, per RFC 9110 §13.2.2
+pub fn is_not_modified(headers: &HeaderMap, etag: &str, modified: SystemTime) -> bool {
+    if header_str(headers, header::IF_NONE_MATCH).is_some() {
+        return etag_matches(headers, etag);
+    }
+
+    if let Some(if_modified_since) = header_str(headers, header::IF_MODIFIED_SINCE)
+        && let Ok(since) = httpdate::parse_http_date(if_modified_since)
+    {
+        return truncate_to_secs(modified) <= since;
+    }
+
+    false
+}
+
+// Parse a `Range: bytes=...` header for a resource of length `len`
+// Only single ranges are honoured - for anything fancier we serve the full resource,
+// which RFC 9110 explicitly permits
+fn parse_range(range: &str, len: u64) -> ByteRange {
+    let spec = match range.trim().strip_prefix("bytes=") {
+        Some(spec) => spec.trim(),
+        None => return ByteRange::Full,
+    };
+
+    if spec.contains(',') {
+        return ByteRange::Full;
+    }
+
+    let (first, last) = match spec.split_once('-') {
+        Some(parts) => parts,
+        None => return ByteRange::Full,
+    };
+
+    let (first, last) = (first.trim(), last.trim());
+
+    if first.is_empty() {
+        // Suffix range: the final `last` bytes
+        return match last.parse::<u64>() {
+            Ok(0) => ByteRange::Unsatisfiable,
+            Ok(_) if len == 0 => ByteRange::Unsatisfiable,
+            Ok(suffix) => ByteRange::Partial(len.saturating_sub(suffix), len - 1),
+            Err(_) => ByteRange::Full,
+        };
+    }
+
+    let start = match first.parse::<u64>() {
+        Ok(start) => start,
+        Err(_) => return ByteRange::Full,
+    };
+
+    let end = if last.is_empty() {
+        len.saturating_sub(1)
+    } else {
+        match last.parse::<u64>() {
+            Ok(end) if end >= start => end.min(len.saturating_sub(1)),
+            _ => return ByteRange::Full,
+        }
+    };
+
+    if start >= len {
+        ByteRange::Unsatisfiable
+    } else {
+        ByteRange::Partial(start, end)
+    }
+}
+
+// An If-Range precondition holds if it carries our strong ETag or exact modification date
+fn if_range_matches(if_range: &str, etag: &str, modified: SystemTime) -> bool {
+    let if_range = if_range.trim();
+    if if_range.starts_with('"') {
+        if_range == etag
+    } else {
+        match httpdate::parse_http_date(if_range) {
+            Ok(date) => date == truncate_to_secs(modified),
+            Err(_) => false,
+        }
+    }
+}
+
+// Determine which part of a resource to send, taking Range and If-Range into account
+pub fn requested_range(
+    headers: &HeaderMap,
+    len: u64,
+    etag: &str,
+    modified: SystemTime,
+) -> ByteRange {
+    let range = match header_str(headers, header::RANGE) {
+        Some(range) => range,
+        None => return ByteRange::Full,
+    };
+
+    if let Some(if_range) = header_str(headers, header::IF_RANGE)
+        && !if_range_matches(if_range, etag, modified)
+    {
+        return ByteRange::Full;
+    }
+
+    parse_range(range, len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    const ETAG: &str = "\"abc123\"";
+
+    fn headers(pairs: &[(header::HeaderName, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, val) in pairs {
+            headers.insert(name.clone(), HeaderValue::from_str(val).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn parses_ranges() {
+        assert_eq!(parse_range("bytes=0-9", 100), ByteRange::Partial(0, 9));
+        assert_eq!(parse_range("bytes=10-", 100), ByteRange::Partial(10, 99));
+        assert_eq!(parse_range("bytes=-10", 100), ByteRange::Partial(90, 99));
+        // Suffixes and ends past the end of the resource are clamped
+        assert_eq!(parse_range("bytes=-500", 100), ByteRange::Partial(0, 99));
+        assert_eq!(parse_range("bytes=50-500", 100), ByteRange::Partial(50, 99));
+    }
+
+    #[test]
+    fn rejects_unsatisfiable_ranges() {
+        assert_eq!(parse_range("bytes=100-", 100), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=200-300", 100), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-0", 100), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-", 0), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-5", 0), ByteRange::Unsatisfiable);
+    }
+
+    #[test]
+    fn ignores_unsupported_ranges() {
+        // Reversed, multiple, malformed and non-byte ranges all get the full resource
+        assert_eq!(parse_range("bytes=9-0", 100), ByteRange::Full);
+        assert_eq!(parse_range("bytes=0-9,20-29", 100), ByteRange::Full);
+        assert_eq!(parse_range("bytes=a-b", 100), ByteRange::Full);
+        assert_eq!(parse_range("bytes=5", 100), ByteRange::Full);
+        assert_eq!(parse_range("items=0-9", 100), ByteRange::Full);
+    }
+
+    #[test]
+    fn formats_content_ranges() {
+        assert_eq!(ByteRange::Full.content_range(100), None);
+        assert_eq!(
+            ByteRange::Partial(0, 9).content_range(100),
+            Some(String::from("bytes 0-9/100"))
+        );
+        assert_eq!(
+            ByteRange::Unsatisfiable.content_range(100),
+            Some(String::from("bytes */100"))
+        );
+    }
+
+    #[test]
+    fn matches_if_range() {
+        let modified = timestamp_to_system_time(1_700_000_000);
+        let date = http_date(modified);
+
+        assert!(if_range_matches(ETAG, ETAG, modified));
+        assert!(!if_range_matches("\"other\"", ETAG, modified));
+        // Weak tags never satisfy If-Range
+        assert!(!if_range_matches("W/\"abc123\"", ETAG, modified));
+        assert!(if_range_matches(
+            &date,
+            ETAG,
+            modified + Duration::from_millis(500)
+        ));
+        assert!(!if_range_matches(
+            &http_date(modified - Duration::from_secs(1)),
+            ETAG,
+            modified
+        ));
+        assert!(!if_range_matches("yesterday", ETAG, modified));
+    }
+
+    #[test]
+    fn applies_if_range_to_ranges() {
+        let modified = timestamp_to_system_time(1_700_000_000);
+
+        let hdrs = headers(&[(header::RANGE, "bytes=0-9")]);
+        assert_eq!(
+            requested_range(&hdrs, 100, ETAG, modified),
+            ByteRange::Partial(0, 9)
+        );
+
+        let hdrs = headers(&[(header::RANGE, "bytes=0-9"), (header::IF_RANGE, ETAG)]);
+        assert_eq!(
+            requested_range(&hdrs, 100, ETAG, modified),
+            ByteRange::Partial(0, 9)
+        );
+
+        let hdrs = headers(&[(header::RANGE, "bytes=0-9"), (header::IF_RANGE, "\"old\"")]);
+        assert_eq!(requested_range(&hdrs, 100, ETAG, modified), ByteRange::Full);
+
+        let stale = http_date(modified - Duration::from_secs(60));
+        let hdrs = headers(&[(header::RANGE, "bytes=0-9"), (header::IF_RANGE, &stale)]);
+        assert_eq!(requested_range(&hdrs, 100, ETAG, modified), ByteRange::Full);
+
+        assert_eq!(
+            requested_range(&HeaderMap::new(), 100, ETAG, modified),
+            ByteRange::Full
+        );
+    }
+
+    #[test]
+    fn checks_not_modified() {
+        let modified = timestamp_to_system_time(1_700_000_000);
+        let before = http_date(modified - Duration::from_secs(60));
+        let after = http_date(modified + Duration::from_secs(60));
+
+        assert!(!is_not_modified(&HeaderMap::new(), ETAG, modified));
+
+        assert!(is_not_modified(
+            &headers(&[(header::IF_NONE_MATCH, ETAG)]),
+            ETAG,
+            modified
+        ));
+        assert!(is_not_modified(
+            &headers(&[(header::IF_NONE_MATCH, "\"x\", W/\"abc123\"")]),
+            ETAG,
+            modified
+        ));
+        assert!(is_not_modified(
+            &headers(&[(header::IF_NONE_MATCH, "*")]),
+            ETAG,
+            modified
+        ));
+        assert!(!is_not_modified(
+            &headers(&[(header::IF_NONE_MATCH, "\"x\"")]),
+            ETAG,
+            modified
+        ));
+
+        assert!(is_not_modified(
+            &headers(&[(header::IF_MODIFIED_SINCE, &after)]),
+            ETAG,
+            modified
+        ));
+        assert!(is_not_modified(
+            &headers(&[(header::IF_MODIFIED_SINCE, &http_date(modified))]),
+            ETAG,
+            modified + Duration::from_millis(500)
+        ));
+        assert!(!is_not_modified(
+            &headers(&[(header::IF_MODIFIED_SINCE, &before)]),
+            ETAG,
+            modified
+        ));
+        assert!(!is_not_modified(
+            &headers(&[(header::IF_MODIFIED_SINCE, "garbage")]),
+            ETAG,
+            modified
+        ));
+
+        // If-None-Match takes precedence over If-Modified-Since
+        assert!(!is_not_modified(
+            &headers(&[
+                (header::IF_NONE_MATCH, "\"x\""),
+                (header::IF_MODIFIED_SINCE, &after)
+            ]),
+            ETAG,
+            modified
+        ));
+    }
+}
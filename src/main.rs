@@ -6,14 +6,23 @@ mod fr;
 
 mod actions;
 mod api;
+mod assets;
+mod backup;
 mod console;
+mod encoding;
 mod format;
 mod fsfr;
+mod headers;
+mod media;
 mod pages;
+mod s3fr;
 mod server;
 mod sqlite3db;
+mod sqlite3migrations;
 mod state;
 mod template;
+mod video;
+mod zip;
 
 use crate::db::Database;
 
@@ -23,6 +32,7 @@ use std::fs;
 use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
 use std::process::exit;
+use std::time::Duration;
 
 use toml::Value;
 
@@ -35,10 +45,14 @@ pub struct Config {
     addr: SocketAddr,
     templates_dir: PathBuf,
     static_dir: PathBuf,
+    assets: assets::StaticAssets,
     approve_threads_by_default: bool,
     approve_replies_by_default: bool,
     whitelist_domains: bool,
     access_key: Option<String>,
+    uploads: media::UploadConfig,
+    thumbnails: media::ThumbnailConfig,
+    backup: Option<backup::BackupConfig>,
 }
 
 fn val<'v_out, 'v_in: 'v_out>(v: &'v_in Value, k: &str) -> &'v_out Value {
@@ -47,10 +61,25 @@ fn val<'v_out, 'v_in: 'v_out>(v: &'v_in Value, k: &str) -> &'v_out Value {
 }
 
 fn main() {
-    let mut args = std::env::args();
-    let _bin = args.next();
+    let mut conf_arg = None;
+    let mut dry_run_migrations = false;
+    let mut restore_from = None;
 
-    let conf_path = fs::canonicalize(args.next().unwrap_or(String::from("./plainchant.toml")))
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--dry-run-migrations" => dry_run_migrations = true,
+            "--restore" => {
+                restore_from = Some(
+                    args.next()
+                        .unwrap_or_else(|| init_die("--restore requires a backup directory")),
+                )
+            },
+            _ => conf_arg = Some(arg),
+        }
+    }
+
+    let conf_path = fs::canonicalize(conf_arg.unwrap_or(String::from("./plainchant.toml")))
         .unwrap_or_else(|_| init_die("Config file does not exist."));
 
     let conf_string = fs::read_to_string(&conf_path)
@@ -97,6 +126,7 @@ fn main() {
         .unwrap_or_else(|_| init_die("Could not comprehend templates path"));
     let static_dir = fs::canonicalize(assets.join("static"))
         .unwrap_or_else(|_| init_die("Could not comprehend static path"));
+    let assets = assets::StaticAssets::from_dir(&static_dir).unwrap_or_else(|err| err.die());
 
     let approve_threads_by_default = val(&conf_data, "site")
         .get("approve_threads_by_default")
@@ -131,18 +161,176 @@ fn main() {
         })
         .map(String::from);
 
+    let uploads = conf_data.get("uploads");
+
+    let ban_distance = uploads
+        .and_then(|u| u.get("ban_distance"))
+        .map(|val| {
+            val.as_integer()
+                .and_then(|dist| u32::try_from(dist).ok())
+                .filter(|dist| *dist <= 64)
+                .unwrap_or_else(|| init_die("uploads.ban_distance is not between 0 and 64"))
+        })
+        .unwrap_or(6);
+
+    let auto_ban_ip = uploads
+        .and_then(|u| u.get("auto_ban_ip"))
+        .map(|val| {
+            val.as_bool()
+                .unwrap_or_else(|| init_die("uploads.auto_ban_ip is not a boolean"))
+        })
+        .unwrap_or(false);
+
+    let strip_metadata = uploads
+        .and_then(|u| u.get("strip_metadata"))
+        .map(|val| {
+            val.as_bool()
+                .unwrap_or_else(|| init_die("uploads.strip_metadata is not a boolean"))
+        })
+        .unwrap_or(true);
+
+    let reencode = uploads
+        .and_then(|u| u.get("reencode"))
+        .map(|val| {
+            val.as_bool()
+                .unwrap_or_else(|| init_die("uploads.reencode is not a boolean"))
+        })
+        .unwrap_or(false);
+
+    let check_type = uploads
+        .and_then(|u| u.get("check_type"))
+        .map(|val| {
+            val.as_bool()
+                .unwrap_or_else(|| init_die("uploads.check_type is not a boolean"))
+        })
+        .unwrap_or(true);
+
+    let upload_limit = |key: &str, default: u64| {
+        uploads
+            .and_then(|u| u.get(key))
+            .map(|val| {
+                val.as_integer()
+                    .and_then(|limit| u64::try_from(limit).ok())
+                    .filter(|limit| *limit > 0)
+                    .unwrap_or_else(|| {
+                        init_die(&format!("uploads.{} is not a positive integer", key))
+                    })
+            })
+            .unwrap_or(default)
+    };
+
+    let max_width = u32::try_from(upload_limit("max_width", 10_000))
+        .unwrap_or_else(|_| init_die("uploads.max_width is too large"));
+    let max_height = u32::try_from(upload_limit("max_height", 10_000))
+        .unwrap_or_else(|_| init_die("uploads.max_height is too large"));
+    let max_pixels = upload_limit("max_pixels", 50_000_000);
+    let max_alloc = upload_limit("max_decode_mb", 512) * 1024 * 1024;
+
+    let uploads = media::UploadConfig {
+        ban_distance,
+        auto_ban_ip,
+        strip_metadata,
+        reencode,
+        check_type,
+        max_width,
+        max_height,
+        max_pixels,
+        max_alloc,
+    };
+
+    let thumbnails = conf_data.get("thumbnails");
+    let thumb_defaults = media::ThumbnailConfig::default();
+
+    let thumb_format = thumbnails
+        .and_then(|t| t.get("format"))
+        .map(|val| match val.as_str() {
+            Some("jpeg") => media::ThumbFormat::Jpeg,
+            Some("webp") => media::ThumbFormat::WebP,
+            Some("png") => media::ThumbFormat::Png,
+            _ => init_die("thumbnails.format is not one of jpeg, webp or png"),
+        })
+        .unwrap_or(thumb_defaults.format);
+
+    let thumb_quality = thumbnails
+        .and_then(|t| t.get("quality"))
+        .map(|val| {
+            val.as_integer()
+                .and_then(|quality| u8::try_from(quality).ok())
+                .filter(|quality| (1..=100).contains(quality))
+                .unwrap_or_else(|| init_die("thumbnails.quality is not between 1 and 100"))
+        })
+        .unwrap_or(thumb_defaults.quality);
+
+    let thumb_size = |key: &str, default: u32| {
+        thumbnails
+            .and_then(|t| t.get(key))
+            .map(|val| {
+                val.as_integer()
+                    .and_then(|size| u32::try_from(size).ok())
+                    .filter(|size| (1..=2000).contains(size))
+                    .unwrap_or_else(|| {
+                        init_die(&format!("thumbnails.{} is not between 1 and 2000", key))
+                    })
+            })
+            .unwrap_or(default)
+    };
+
+    let thumbnails = media::ThumbnailConfig {
+        format:       thumb_format,
+        quality:      thumb_quality,
+        op_size:      thumb_size("op_size", thumb_defaults.op_size),
+        reply_size:   thumb_size("reply_size", thumb_defaults.reply_size),
+        catalog_size: thumb_size("catalog_size", thumb_defaults.catalog_size),
+    };
+
+    let backup = conf_data.get("backup").map(|backup| {
+        let dir = PathBuf::from(
+            val(backup, "path")
+                .as_str()
+                .unwrap_or_else(|| init_die("backup.path is not a string")),
+        );
+
+        let keep = backup
+            .get("keep")
+            .map(|val| {
+                val.as_integer()
+                    .and_then(|keep| usize::try_from(keep).ok())
+                    .filter(|keep| *keep > 0)
+                    .unwrap_or_else(|| init_die("backup.keep is not a positive integer"))
+            })
+            .unwrap_or(7);
+
+        let interval = backup.get("interval_hours").map(|val| {
+            val.as_integer()
+                .and_then(|hours| u64::try_from(hours).ok())
+                .filter(|hours| *hours > 0)
+                .map(|hours| Duration::from_secs(hours * 3600))
+                .unwrap_or_else(|| init_die("backup.interval_hours is not a positive integer"))
+        });
+
+        backup::BackupConfig {
+            dir,
+            keep,
+            interval,
+        }
+    });
+
     let config = Config {
         addr,
         templates_dir,
         static_dir,
+        assets,
         approve_threads_by_default,
         approve_replies_by_default,
         whitelist_domains,
         access_key,
+        uploads,
+        thumbnails,
+        backup,
     };
 
-    // Load database - this needs to be db::Database
-    let db = if let Some(path) = val(val(val(&conf_data, "db"), "sqlite"), "path").as_str() {
+    // Locate database - this needs to be db::Database
+    let db_path = if let Some(path) = val(val(val(&conf_data, "db"), "sqlite"), "path").as_str() {
         let path = PathBuf::from(path);
         let parent = path
             .parent()
@@ -152,46 +340,105 @@ fn main() {
             .unwrap_or_else(|| init_die("Database path has no file name"));
 
         match fs::canonicalize(parent) {
-            Ok(pp) => sqlite3db::Sqlite3Database::from_path(pp.join(file_name))
-                .unwrap_or_else(|err| err.die()),
+            Ok(pp) => pp.join(file_name),
             Err(_) => init_die("Could not comprehend sqlite3db path"),
         }
     } else {
         init_die("No database specified in config")
     };
 
-    // Load file rack - this needs to be fr::FileRack
-    let fr = if let Some(path) = val(val(val(&conf_data, "fr"), "fs"), "path").as_str() {
-        match fs::canonicalize(path) {
-            Ok(path) => fsfr::FSFileRack::from_dir(&path).unwrap_or_else(|err| err.die()),
-            Err(_) => init_die("Could not comprehend fsfr path"),
-        }
-    } else {
-        init_die("No file rack specified in config")
+    // Locate file rack - this needs to be fr::FileRack
+    let fr_conf = val(&conf_data, "fr");
+    let rack = fr_conf
+        .get("rack")
+        .map(|val| {
+            val.as_str()
+                .unwrap_or_else(|| init_die("fr.rack is not a string"))
+        })
+        .unwrap_or("fs");
+
+    let (fr_path, offload, s3_config) = match rack {
+        "fs" => {
+            let fs_conf = val(fr_conf, "fs");
+            let fr_path = if let Some(path) = val(fs_conf, "path").as_str() {
+                fs::canonicalize(path)
+                    .unwrap_or_else(|_| init_die("Could not comprehend fsfr path"))
+            } else {
+                init_die("No file rack specified in config")
+            };
+
+            // Have a reverse proxy in front of the site serve files straight from the rack
+            let offload = match fs_conf.get("offload").map(|val| val.as_str()) {
+                None | Some(Some("none")) => None,
+                Some(Some("x-accel-redirect")) => Some(fr::Offload::XAccelRedirect(
+                    fs_conf
+                        .get("offload_prefix")
+                        .and_then(|val| val.as_str())
+                        .map(String::from)
+                        .unwrap_or_else(|| {
+                            init_die("fr.fs.offload_prefix is required for x-accel-redirect")
+                        }),
+                )),
+                Some(Some("x-sendfile")) => Some(fr::Offload::XSendfile),
+                _ => init_die("fr.fs.offload is not one of none, x-accel-redirect or x-sendfile"),
+            };
+
+            (Some(fr_path), offload, None)
+        },
+        "s3" => (None, None, Some(s3_config(val(fr_conf, "s3")))),
+        _ => init_die("fr.rack is not one of fs or s3"),
     };
 
+    if dry_run_migrations {
+        let pending =
+            sqlite3db::Sqlite3Database::pending_migrations(db_path).unwrap_or_else(|err| err.die());
+        println!("{} pending migration(s)", pending.len());
+        for migration in pending {
+            println!("{:>4}: {}", migration.version, migration.description);
+        }
+        exit(0);
+    }
+
+    if let Some(backup_dir) = restore_from {
+        let backup_dir = fs::canonicalize(backup_dir)
+            .unwrap_or_else(|_| init_die("Backup directory does not exist"));
+        let fr_path = fr_path
+            .unwrap_or_else(|| init_die("--restore is only supported with the fs file rack"));
+        let report =
+            backup::restore(&backup_dir, &db_path, &fr_path).unwrap_or_else(|err| err.die());
+        print!("{}", report);
+        exit(0);
+    }
+
+    let db = sqlite3db::Sqlite3Database::from_path(db_path).unwrap_or_else(|err| err.die());
+
     // Load templates from template files
     let templates = pages::SiteTemplates {
         homepage_tmpl: template::Template::from_file(
             config.templates_dir.join("homepage.html.tmpl").as_path(),
         )
-        .unwrap_or_else(|err| err.die()),
+        .unwrap_or_else(|err| err.die())
+        .with_assets(&config.assets),
         catalog_tmpl:  template::Template::from_file(
             config.templates_dir.join("catalog.html.tmpl").as_path(),
         )
-        .unwrap_or_else(|err| err.die()),
+        .unwrap_or_else(|err| err.die())
+        .with_assets(&config.assets),
         archive_tmpl:  template::Template::from_file(
             config.templates_dir.join("archive.html.tmpl").as_path(),
         )
-        .unwrap_or_else(|err| err.die()),
+        .unwrap_or_else(|err| err.die())
+        .with_assets(&config.assets),
         thread_tmpl:   template::Template::from_file(
             config.templates_dir.join("thread.html.tmpl").as_path(),
         )
-        .unwrap_or_else(|err| err.die()),
+        .unwrap_or_else(|err| err.die())
+        .with_assets(&config.assets),
         create_tmpl:   template::Template::from_file(
             config.templates_dir.join("create.html.tmpl").as_path(),
         )
-        .unwrap_or_else(|err| err.die()),
+        .unwrap_or_else(|err| err.die())
+        .with_assets(&config.assets),
     };
 
     // Create structs for pages and actions
@@ -201,6 +448,82 @@ fn main() {
     let pages = pages::Pages::new(site, templates, 1).unwrap_or_else(|err| err.die());
     let actions = actions::Actions::new(&db).unwrap_or_else(|err| err.die());
 
-    // Serve the site using the pages, actions, and database
-    server::serve(config, pages, actions, db, fr);
+    // Serve the site using the pages, actions, database, and whichever file rack is configured
+    match (fr_path, s3_config) {
+        (_, Some(s3_config)) => {
+            let fr = s3fr::S3FileRack::from_config(s3_config)
+                .unwrap_or_else(|err| err.die())
+                .with_decode_limits(config.uploads.decode_limits())
+                .with_thumbnails(config.thumbnails.clone());
+            server::serve(config, pages, actions, db, fr);
+        },
+        (Some(fr_path), None) => {
+            let fr = fsfr::FSFileRack::from_dir(&fr_path)
+                .unwrap_or_else(|err| err.die())
+                .with_decode_limits(config.uploads.decode_limits())
+                .with_thumbnails(config.thumbnails.clone())
+                .with_offload(offload);
+            server::serve(config, pages, actions, db, fr);
+        },
+        (None, None) => init_die("No file rack specified in config"),
+    }
+}
+
+fn s3_config(s3: &Value) -> s3fr::S3Config {
+    let string = |key: &str| {
+        String::from(
+            val(s3, key)
+                .as_str()
+                .unwrap_or_else(|| init_die(&format!("fr.s3.{} is not a string", key))),
+        )
+    };
+
+    let path_style = s3
+        .get("path_style")
+        .map(|val| {
+            val.as_bool()
+                .unwrap_or_else(|| init_die("fr.s3.path_style is not a boolean"))
+        })
+        .unwrap_or(false);
+
+    // Presigned URLs can be valid for at most a week
+    let url_expiry = s3
+        .get("url_expiry")
+        .map(|val| {
+            val.as_integer()
+                .and_then(|secs| u64::try_from(secs).ok())
+                .filter(|secs| (60..=604_800).contains(secs))
+                .map(Duration::from_secs)
+                .unwrap_or_else(|| init_die("fr.s3.url_expiry is not between 60 and 604800"))
+        })
+        .unwrap_or(Duration::from_secs(3600));
+
+    let redirect = match s3.get("redirect").map(|val| val.as_str()) {
+        None | Some(Some("none")) => s3fr::S3Redirect::None,
+        Some(Some("signed")) => s3fr::S3Redirect::Signed(url_expiry),
+        Some(Some("public")) => s3fr::S3Redirect::Public(
+            s3.get("public_url")
+                .and_then(|val| val.as_str())
+                .map(String::from)
+                .unwrap_or_else(|| init_die("fr.s3.public_url is required for public redirects")),
+        ),
+        _ => init_die("fr.s3.redirect is not one of none, signed or public"),
+    };
+
+    s3fr::S3Config {
+        endpoint: string("endpoint"),
+        bucket: string("bucket"),
+        region: s3
+            .get("region")
+            .map(|val| {
+                val.as_str()
+                    .unwrap_or_else(|| init_die("fr.s3.region is not a string"))
+            })
+            .unwrap_or("us-east-1")
+            .to_string(),
+        access_key: string("access_key"),
+        secret_key: string("secret_key"),
+        path_style,
+        redirect,
+    }
 }
@@ -0,0 +1,1002 @@
+use crate::util::{ErrOrigin, PlainchantErr};
+use crate::video;
+
+use bytes::Bytes;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::imageops::FilterType;
+use image::metadata::Orientation;
+use image::{
+    DynamicImage, ImageDecoder, ImageError, ImageFormat, ImageReader, Limits, Rgb, RgbImage,
+};
+use std::io::Cursor;
+use std::path::Path;
+
+const JPEG_QUALITY: u8 = 90;
+
+// Chunks which carry metadata rather than image data. XMP is stored in an iTXt chunk.
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+const PNG_METADATA_CHUNKS: &[&[u8]] = &[b"eXIf", b"tEXt", b"zTXt", b"iTXt", b"iCCP", b"tIME"];
+
+// The VP8X header flags each optional chunk, so those removed must be unflagged as well
+const WEBP_METADATA_CHUNKS: &[(&[u8], u8)] = &[(b"ICCP", 0x20), (b"EXIF", 0x08), (b"XMP ", 0x04)];
+
+// Only the application extensions which control looping are kept. Others carry XMP,
+// ICC profiles, or anything else an editor wanted to add.
+const GIF_LOOP_EXTENSIONS: &[&[u8]] = &[b"NETSCAPE2.0", b"ANIMEXTS1.0"];
+
+const GIF_EXTENSION: u8 = 0x21;
+const GIF_IMAGE: u8 = 0x2C;
+const GIF_TRAILER: u8 = 0x3B;
+
+pub struct UploadConfig {
+    // Out of the 64 bits of an image's perceptual hash
+    pub ban_distance:   u32,
+    pub auto_ban_ip:    bool,
+    pub strip_metadata: bool,
+    pub reencode:       bool,
+    pub check_type:     bool,
+    pub max_width:      u32,
+    pub max_height:     u32,
+    pub max_pixels:     u64,
+    // Most memory any one decoder may allocate, in bytes
+    pub max_alloc:      u64,
+}
+
+impl UploadConfig {
+    pub fn decode_limits(&self) -> Limits {
+        let mut limits = Limits::default();
+        limits.max_image_width = Some(self.max_width);
+        limits.max_image_height = Some(self.max_height);
+        limits.max_alloc = Some(self.max_alloc);
+        limits
+    }
+}
+
+// Each file has a thumbnail of each kind, as they are shown at different sizes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ThumbKind {
+    Op,
+    Reply,
+    Catalog,
+}
+
+impl ThumbKind {
+    pub const ALL: [ThumbKind; 3] = [ThumbKind::Op, ThumbKind::Reply, ThumbKind::Catalog];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ThumbKind::Op => "op",
+            ThumbKind::Reply => "reply",
+            ThumbKind::Catalog => "catalog",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<ThumbKind> {
+        ThumbKind::ALL.into_iter().find(|kind| kind.name() == name)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum ThumbFormat {
+    Jpeg,
+    // image can only encode lossless WebP, so quality does not apply
+    WebP,
+    Png,
+}
+
+#[derive(Clone, Debug)]
+pub struct ThumbnailConfig {
+    pub format:       ThumbFormat,
+    pub quality:      u8,
+    // Thumbnails fit within a square with sides of this many pixels
+    pub op_size:      u32,
+    pub reply_size:   u32,
+    pub catalog_size: u32,
+}
+
+impl Default for ThumbnailConfig {
+    fn default() -> Self {
+        ThumbnailConfig {
+            format:       ThumbFormat::Jpeg,
+            quality:      75,
+            op_size:      300,
+            reply_size:   300,
+            catalog_size: 300,
+        }
+    }
+}
+
+impl ThumbnailConfig {
+    pub fn size(&self, kind: ThumbKind) -> u32 {
+        match kind {
+            ThumbKind::Op => self.op_size,
+            ThumbKind::Reply => self.reply_size,
+            ThumbKind::Catalog => self.catalog_size,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MediaType {
+    Image(ImageFormat),
+    Video(video::VideoFormat),
+}
+
+impl MediaType {
+    // Judged from the contents of a file, of which only the first few bytes are needed
+    pub fn detect(file: &[u8]) -> Option<MediaType> {
+        video::guess_format(file)
+            .map(MediaType::Video)
+            .or_else(|| image::guess_format(file).ok().map(MediaType::Image))
+    }
+
+    pub fn from_extension(ext: &str) -> Option<MediaType> {
+        video::VideoFormat::from_extension(ext)
+            .map(MediaType::Video)
+            .or_else(|| ImageFormat::from_extension(ext).map(MediaType::Image))
+    }
+
+    // Short upper case name for display, such as PNG or WEBM
+    pub fn name(&self) -> String {
+        match self {
+            MediaType::Image(format) => format_name(*format),
+            MediaType::Video(format) => format.name().to_string(),
+        }
+    }
+
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            MediaType::Image(format) => format.to_mime_type(),
+            MediaType::Video(format) => format.mime_type(),
+        }
+    }
+}
+
+// Whether a file recorded with this format name should be played rather than shown
+pub fn is_video(format_name: &str) -> bool {
+    video::VideoFormat::from_name(format_name).is_some()
+}
+
+// A stored file, opened to make its thumbnails and record its properties
+pub struct Media {
+    pub media_type:  MediaType,
+    pub width:       u32,
+    pub height:      u32,
+    // Only for videos and animated images
+    pub duration_ms: Option<u64>,
+    // Videos are not decoded, so they have no frame to make thumbnails from
+    still:           Option<DynamicImage>,
+}
+
+impl Media {
+    pub fn open(file: &[u8], limits: Limits) -> Result<Media, PlainchantErr> {
+        let media_type =
+            MediaType::detect(file).ok_or_else(|| media_err("File type not recognised"))?;
+
+        match media_type {
+            MediaType::Image(format) => {
+                let img = decode(file, limits)?;
+                Ok(Media {
+                    media_type,
+                    width: img.width(),
+                    height: img.height(),
+                    duration_ms: animation_duration(file, format),
+                    still: Some(img),
+                })
+            },
+            MediaType::Video(format) => {
+                let info = video::probe(file, format)?;
+                Ok(Media {
+                    media_type,
+                    width: info.width,
+                    height: info.height,
+                    duration_ms: Some(info.duration_ms),
+                    still: None,
+                })
+            },
+        }
+    }
+
+    pub fn thumbnail(
+        &self,
+        config: &ThumbnailConfig,
+        kind: ThumbKind,
+    ) -> Result<Bytes, PlainchantErr> {
+        match &self.still {
+            Some(img) => make_thumbnail(img, config, kind),
+            None => {
+                let (width, height) = thumb_dimensions(self.width, self.height, config.size(kind));
+                make_thumbnail(&video_placeholder(width, height), config, kind)
+            },
+        }
+    }
+}
+
+// Errors caused by the contents of an uploaded file
+fn media_err(msg: &'static str) -> PlainchantErr {
+    PlainchantErr {
+        origin: ErrOrigin::Media,
+        code:   415,
+        msg:    String::from(msg),
+    }
+}
+
+// Images which are within the configured limits but still cannot be decoded without
+// exceeding the decoder's memory limit are also treated as too large
+fn too_large_err() -> PlainchantErr {
+    PlainchantErr {
+        origin: ErrOrigin::Media,
+        code:   422,
+        msg:    String::from("Image is too large"),
+    }
+}
+
+fn decode_err(err: ImageError) -> PlainchantErr {
+    match err {
+        ImageError::Limits(_) => too_large_err(),
+        _ => media_err("Could not decode image"),
+    }
+}
+
+pub fn decode(file: &[u8], limits: Limits) -> Result<DynamicImage, PlainchantErr> {
+    let mut reader = ImageReader::new(Cursor::new(file))
+        .with_guessed_format()
+        .map_err(|_| media_err("Could not read image"))?;
+    reader.limits(limits);
+    reader.decode().map_err(decode_err)
+}
+
+// Short upper case name for display, such as PNG or JPEG
+pub fn format_name(format: ImageFormat) -> String {
+    format!("{:?}", format).to_uppercase()
+}
+
+// Scale an image to fit within a square with sides of `max` pixels, never enlarging it
+pub fn thumb_dimensions(width: u32, height: u32, max: u32) -> (u32, u32) {
+    if width <= max && height <= max {
+        return (width.max(1), height.max(1));
+    }
+
+    let ratio = f64::min(max as f64 / width as f64, max as f64 / height as f64);
+    (
+        ((width as f64 * ratio).round() as u32).max(1),
+        ((height as f64 * ratio).round() as u32).max(1),
+    )
+}
+
+// JPEG has no alpha channel, so transparent areas are drawn over white rather than black
+fn flatten(img: &DynamicImage) -> RgbImage {
+    if !img.color().has_alpha() {
+        return img.to_rgb8();
+    }
+
+    let rgba = img.to_rgba8();
+    RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let [r, g, b, a] = rgba.get_pixel(x, y).0;
+        let over_white = |c: u8| ((c as u32 * a as u32 + 255 * (255 - a as u32)) / 255) as u8;
+        Rgb([over_white(r), over_white(g), over_white(b)])
+    })
+}
+
+// A dark frame with a play symbol in the middle, standing in for a video's first frame
+fn video_placeholder(width: u32, height: u32) -> DynamicImage {
+    let (cx, cy) = (width as f64 / 2.0, height as f64 / 2.0);
+    let radius = f64::min(width as f64, height as f64) / 5.0;
+
+    DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+        let (dx, dy) = (x as f64 + 0.5 - cx, y as f64 + 0.5 - cy);
+        // A triangle pointing right, with its left edge upright
+        let in_triangle = dx >= -radius / 2.0 && dy.abs() <= (radius - dx) * 0.577;
+        if in_triangle {
+            Rgb([0xE0, 0xE0, 0xE0])
+        } else {
+            Rgb([0x30, 0x30, 0x30])
+        }
+    }))
+}
+
+fn make_thumbnail(
+    img: &DynamicImage,
+    config: &ThumbnailConfig,
+    kind: ThumbKind,
+) -> Result<Bytes, PlainchantErr> {
+    let (width, height) = thumb_dimensions(img.width(), img.height(), config.size(kind));
+    let thumb = img.thumbnail_exact(width, height);
+
+    let mut out = vec![];
+    let res = match config.format {
+        ThumbFormat::Jpeg => DynamicImage::ImageRgb8(flatten(&thumb))
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut out, config.quality)),
+        ThumbFormat::WebP => {
+            let thumb = if thumb.color().has_alpha() {
+                DynamicImage::ImageRgba8(thumb.to_rgba8())
+            } else {
+                DynamicImage::ImageRgb8(thumb.to_rgb8())
+            };
+            thumb.write_with_encoder(WebPEncoder::new_lossless(&mut out))
+        },
+        ThumbFormat::Png => thumb.write_to(&mut Cursor::new(&mut out), ImageFormat::Png),
+    };
+
+    res.map_err(|_| PlainchantErr {
+        origin: ErrOrigin::Media,
+        code:   500,
+        msg:    String::from("Could not encode thumbnail"),
+    })?;
+    Ok(Bytes::from(out))
+}
+
+// Enough of the start of a file to tell WebM from other Matroska files
+pub const SNIFF_LENGTH: u64 = 64;
+
+// MIME type of a stored file, judged from its first few bytes
+pub fn mime_type(file: &[u8]) -> &'static str {
+    MediaType::detect(file)
+        .map(|media_type| media_type.mime_type())
+        .unwrap_or("application/octet-stream")
+}
+
+// Total display time of an animated GIF or WebP, or None for a still image
+// Frame delays are read from the container, as decoding every frame could take a long time
+fn animation_duration(file: &[u8], format: ImageFormat) -> Option<u64> {
+    let (frames, duration_ms) = match format {
+        ImageFormat::Gif => gif_duration(file)?,
+        ImageFormat::WebP => webp_duration(file)?,
+        _ => return None,
+    };
+
+    if frames > 1 { Some(duration_ms) } else { None }
+}
+
+// dHash: shrink the image to 9x8 greyscale and record whether each pixel is brighter
+// than its right hand neighbour. Resizing, recompression and small edits leave most of
+// the 64 bits unchanged, so similar images have hashes a short Hamming distance apart.
+pub fn dhash(img: &DynamicImage) -> u64 {
+    let small = img.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            hash <<= 1;
+            if small.get_pixel(x, y).0[0] > small.get_pixel(x + 1, y).0[0] {
+                hash |= 1;
+            }
+        }
+    }
+    hash
+}
+
+// None if the file is not an image we can decode
+pub fn perceptual_hash(file: &Bytes, limits: Limits) -> Option<u64> {
+    decode(file, limits).ok().map(|img| dhash(&img))
+}
+
+pub fn hash_distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+// Prepare an upload for storage. Metadata such as EXIF GPS coordinates is removed, and
+// anything after the end of the image is dropped, so polyglot files lose their payload.
+pub fn sanitise(
+    config: &UploadConfig,
+    file: Bytes,
+    file_name: &str,
+) -> Result<Bytes, PlainchantErr> {
+    let media_type =
+        MediaType::detect(&file).ok_or_else(|| media_err("File type not recognised"))?;
+
+    match media_type {
+        MediaType::Image(format) => check_dimensions(config, &file, format)?,
+        MediaType::Video(format) => {
+            let info = video::probe(&file, format)?;
+            check_limits(config, info.width, info.height)?;
+        },
+    }
+
+    if config.check_type {
+        let ext_type = Path::new(file_name)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .and_then(MediaType::from_extension);
+
+        if ext_type != Some(media_type) {
+            return Err(media_err("File extension does not match its contents"));
+        }
+    }
+
+    let format = match media_type {
+        MediaType::Image(format) => format,
+        MediaType::Video(format) if config.strip_metadata => {
+            return video::strip_metadata(&file, format).map(Bytes::from);
+        },
+        MediaType::Video(_) => return Ok(file),
+    };
+
+    match format {
+        ImageFormat::Jpeg | ImageFormat::Png if config.reencode => {
+            reencode(&file, format, config.decode_limits())
+        },
+        // Stripping EXIF would lose the orientation, so rotated photos are re-encoded upright
+        ImageFormat::Jpeg if config.strip_metadata => {
+            if orientation(&file, format)? == Orientation::NoTransforms {
+                strip_jpeg(&file)
+            } else {
+                reencode(&file, format, config.decode_limits())
+            }
+        },
+        ImageFormat::Png if config.strip_metadata => strip_png(&file),
+        ImageFormat::WebP if config.strip_metadata => strip_webp(&file),
+        ImageFormat::Gif if config.strip_metadata => strip_gif(&file),
+        // Formats such as TIFF can carry EXIF too, but have no stripping of their own,
+        // so they are re-encoded, or refused if that is not possible
+        _ if config.strip_metadata => {
+            if !format.writing_enabled() {
+                return Err(media_err(
+                    "Images of this type cannot have their metadata removed",
+                ));
+            }
+            reencode(&file, format, config.decode_limits())
+        },
+        _ => Ok(file),
+    }
+}
+
+// Only the image header is read, so decompression bombs are caught before any
+// memory is allocated for their pixels
+fn check_dimensions(
+    config: &UploadConfig,
+    file: &[u8],
+    format: ImageFormat,
+) -> Result<(), PlainchantErr> {
+    let (width, height) = ImageReader::with_format(Cursor::new(file), format)
+        .into_dimensions()
+        .map_err(decode_err)?;
+
+    check_limits(config, width, height)
+}
+
+fn check_limits(config: &UploadConfig, width: u32, height: u32) -> Result<(), PlainchantErr> {
+    if width > config.max_width
+        || height > config.max_height
+        || width as u64 * height as u64 > config.max_pixels
+    {
+        return Err(too_large_err());
+    }
+
+    Ok(())
+}
+
+fn orientation(file: &[u8], format: ImageFormat) -> Result<Orientation, PlainchantErr> {
+    ImageReader::with_format(Cursor::new(file), format)
+        .into_decoder()
+        .and_then(|mut decoder| decoder.orientation())
+        .map_err(|_| media_err("Could not decode image"))
+}
+
+fn reencode(file: &[u8], format: ImageFormat, limits: Limits) -> Result<Bytes, PlainchantErr> {
+    let mut reader = ImageReader::with_format(Cursor::new(file), format);
+    reader.limits(limits);
+    let mut decoder = reader.into_decoder().map_err(decode_err)?;
+    let orientation = decoder.orientation().unwrap_or(Orientation::NoTransforms);
+    let mut img = DynamicImage::from_decoder(decoder).map_err(decode_err)?;
+    img.apply_orientation(orientation);
+
+    let mut out = vec![];
+    let res = match format {
+        ImageFormat::Jpeg => {
+            let encoder = JpegEncoder::new_with_quality(&mut out, JPEG_QUALITY);
+            match img {
+                DynamicImage::ImageLuma8(_) | DynamicImage::ImageRgb8(_) => {
+                    img.write_with_encoder(encoder)
+                },
+                _ => DynamicImage::ImageRgb8(img.to_rgb8()).write_with_encoder(encoder),
+            }
+        },
+        _ => img.write_to(&mut Cursor::new(&mut out), format),
+    };
+
+    res.map_err(|_| media_err("Could not re-encode image"))?;
+    Ok(Bytes::from(out))
+}
+
+// Application segments other than APP0 (JFIF) and APP14 (Adobe, which affects colour
+// decoding) hold metadata: APP1 holds EXIF and XMP, APP2 ICC profiles and APP13 IPTC
+fn is_jpeg_metadata(marker: u8) -> bool {
+    matches!(marker, 0xE1..=0xED | 0xEF | 0xFE)
+}
+
+fn ends_scan(byte: u8, next: u8) -> bool {
+    byte == 0xFF && !matches!(next, 0x00 | 0xD0..=0xD7 | 0xFF)
+}
+
+fn strip_jpeg(file: &[u8]) -> Result<Bytes, PlainchantErr> {
+    let malformed = || media_err("Malformed JPEG");
+
+    if !file.starts_with(&[0xFF, 0xD8]) {
+        return Err(malformed());
+    }
+
+    let mut out = Vec::with_capacity(file.len());
+    out.extend_from_slice(&file[..2]);
+    let mut pos = 2;
+
+    loop {
+        // Markers may be preceded by any number of fill bytes
+        while file.get(pos) == Some(&0xFF) && file.get(pos + 1) == Some(&0xFF) {
+            pos += 1;
+        }
+
+        if file.get(pos) != Some(&0xFF) {
+            return Err(malformed());
+        }
+        let marker = *file.get(pos + 1).ok_or_else(malformed)?;
+
+        match marker {
+            // End of image - anything after this is not part of the JPEG
+            0xD9 => {
+                out.extend_from_slice(&[0xFF, 0xD9]);
+                return Ok(Bytes::from(out));
+            },
+            // Markers without a length
+            0x01 | 0xD0..=0xD7 => {
+                out.extend_from_slice(&file[pos..pos + 2]);
+                pos += 2;
+                continue;
+            },
+            _ => (),
+        }
+
+        let len = file
+            .get(pos + 2..pos + 4)
+            .map(|len| u16::from_be_bytes([len[0], len[1]]) as usize)
+            .ok_or_else(malformed)?;
+        let end = pos + 2 + len;
+        if len < 2 || end > file.len() {
+            return Err(malformed());
+        }
+
+        if !is_jpeg_metadata(marker) {
+            out.extend_from_slice(&file[pos..end]);
+        }
+        pos = end;
+
+        // A scan header is followed by entropy coded data, which runs until the next
+        // marker other than a stuffed zero byte or a restart marker
+        if marker == 0xDA {
+            let start = pos;
+            while pos + 1 < file.len() && !ends_scan(file[pos], file[pos + 1]) {
+                pos += 1;
+            }
+
+            if pos + 1 >= file.len() {
+                return Err(malformed());
+            }
+            out.extend_from_slice(&file[start..pos]);
+        }
+    }
+}
+
+fn strip_png(file: &[u8]) -> Result<Bytes, PlainchantErr> {
+    let malformed = || media_err("Malformed PNG");
+
+    if !file.starts_with(PNG_SIGNATURE) {
+        return Err(malformed());
+    }
+
+    let mut out = Vec::with_capacity(file.len());
+    out.extend_from_slice(PNG_SIGNATURE);
+    let mut pos = PNG_SIGNATURE.len();
+
+    loop {
+        // Length, type, data, then CRC
+        let len = file
+            .get(pos..pos + 4)
+            .map(|len| u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize)
+            .ok_or_else(malformed)?;
+        let chunk_type = file.get(pos + 4..pos + 8).ok_or_else(malformed)?;
+        let end = pos
+            .checked_add(12)
+            .and_then(|end| end.checked_add(len))
+            .filter(|end| *end <= file.len())
+            .ok_or_else(malformed)?;
+
+        if !PNG_METADATA_CHUNKS.contains(&chunk_type) {
+            out.extend_from_slice(&file[pos..end]);
+        }
+        pos = end;
+
+        // Anything after the final chunk is not part of the PNG
+        if chunk_type == b"IEND" {
+            return Ok(Bytes::from(out));
+        }
+    }
+}
+
+// The end of a WebP's RIFF container, whose header gives its length
+// Anything after that is not part of the WebP
+fn riff_end(file: &[u8]) -> Option<usize> {
+    if file.get(..4) != Some(b"RIFF") || file.get(8..12) != Some(b"WEBP") {
+        return None;
+    }
+
+    let riff_len = u32::from_le_bytes(file.get(4..8)?.try_into().ok()?) as usize;
+    riff_len.checked_add(8).filter(|end| *end <= file.len())
+}
+
+// The RIFF chunk at `pos`: its type, where its data starts, and where it ends
+// Chunks have a type, a length, then data padded to an even length
+fn riff_chunk(file: &[u8], pos: usize, riff_end: usize) -> Option<(&[u8], usize, usize)> {
+    let chunk_type = file.get(pos..pos + 4)?;
+    let len = u32::from_le_bytes(file.get(pos + 4..pos + 8)?.try_into().ok()?) as usize;
+    let end = pos
+        .checked_add(8)
+        .and_then(|end| end.checked_add(len + (len & 1)))
+        .filter(|end| *end <= riff_end)?;
+    Some((chunk_type, pos + 8, end))
+}
+
+fn strip_webp(file: &[u8]) -> Result<Bytes, PlainchantErr> {
+    let malformed = || media_err("Malformed WebP");
+    let riff_end = riff_end(file).ok_or_else(malformed)?;
+
+    let mut out = Vec::with_capacity(riff_end);
+    out.extend_from_slice(&file[..12]);
+    let mut removed_flags = 0;
+    let mut pos = 12;
+
+    while pos < riff_end {
+        let (chunk_type, _, end) = riff_chunk(file, pos, riff_end).ok_or_else(malformed)?;
+
+        match WEBP_METADATA_CHUNKS
+            .iter()
+            .find(|(name, _)| *name == chunk_type)
+        {
+            Some((_, flag)) => removed_flags |= flag,
+            None => out.extend_from_slice(&file[pos..end]),
+        }
+        pos = end;
+    }
+
+    // The extended header is always the first chunk when present
+    if out.get(12..16) == Some(b"VP8X") && out.len() > 20 {
+        out[20] &= !removed_flags;
+    }
+
+    let out_len = (out.len() - 8) as u32;
+    out[4..8].copy_from_slice(&out_len.to_le_bytes());
+    Ok(Bytes::from(out))
+}
+
+// The total duration of an animated WebP's frames, each of which gives its own
+fn webp_duration(file: &[u8]) -> Option<(usize, u64)> {
+    let riff_end = riff_end(file)?;
+    let mut frames = 0;
+    let mut duration_ms = 0;
+    let mut pos = 12;
+
+    while pos < riff_end {
+        let (chunk_type, data, end) = riff_chunk(file, pos, riff_end)?;
+
+        // The frame's position and size, then its duration, each in three bytes
+        if chunk_type == b"ANMF" {
+            let duration = file.get(data + 12..data + 15)?;
+            duration_ms += u32::from_le_bytes([duration[0], duration[1], duration[2], 0]) as u64;
+            frames += 1;
+        }
+        pos = end;
+    }
+
+    Some((frames, duration_ms))
+}
+
+// The end of a run of GIF data sub-blocks, each a length byte followed by that many bytes,
+// which is ended by an empty block
+fn gif_sub_blocks_end(file: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *file.get(pos)? as usize;
+        pos += 1 + len;
+        if len == 0 {
+            return Some(pos).filter(|end| *end <= file.len());
+        }
+    }
+}
+
+// The header and logical screen descriptor, then any global colour table
+fn gif_header_len(file: &[u8]) -> Option<usize> {
+    let packed = *file.get(10)?;
+    let mut len = 13;
+    if packed & 0x80 != 0 {
+        len += 3 << ((packed & 0x07) + 1);
+    }
+    Some(len).filter(|len| *len <= file.len())
+}
+
+// The GIF block at `pos`: its introducer, its label if it is an extension, and its end
+fn gif_block(file: &[u8], pos: usize) -> Option<(u8, u8, usize)> {
+    match *file.get(pos)? {
+        // Trailer - anything after this is not part of the GIF
+        GIF_TRAILER => Some((GIF_TRAILER, 0, pos + 1)),
+        // Image descriptor, any local colour table, the LZW code size, then image data
+        GIF_IMAGE => {
+            let packed = *file.get(pos + 9)?;
+            let mut data = pos + 10;
+            if packed & 0x80 != 0 {
+                data += 3 << ((packed & 0x07) + 1);
+            }
+            Some((GIF_IMAGE, 0, gif_sub_blocks_end(file, data + 1)?))
+        },
+        GIF_EXTENSION => {
+            let label = *file.get(pos + 1)?;
+            Some((GIF_EXTENSION, label, gif_sub_blocks_end(file, pos + 2)?))
+        },
+        _ => None,
+    }
+}
+
+fn strip_gif(file: &[u8]) -> Result<Bytes, PlainchantErr> {
+    let malformed = || media_err("Malformed GIF");
+    let mut pos = gif_header_len(file).ok_or_else(malformed)?;
+
+    let mut out = Vec::with_capacity(file.len());
+    out.extend_from_slice(&file[..pos]);
+
+    loop {
+        let (introducer, label, end) = gif_block(file, pos).ok_or_else(malformed)?;
+
+        // Comments are dropped, along with any application extension which is not a
+        // loop count
+        let keep = match (introducer, label) {
+            (GIF_EXTENSION, 0xFE) => false,
+            (GIF_EXTENSION, 0xFF) => file
+                .get(pos + 2..pos + 14)
+                .is_some_and(|app| app[0] == 11 && GIF_LOOP_EXTENSIONS.contains(&&app[1..])),
+            _ => true,
+        };
+
+        if keep {
+            out.extend_from_slice(&file[pos..end]);
+        }
+        if introducer == GIF_TRAILER {
+            return Ok(Bytes::from(out));
+        }
+        pos = end;
+    }
+}
+
+// The total duration of a GIF's frames, each of which may have its delay set by a
+// graphic control extension before it
+fn gif_duration(file: &[u8]) -> Option<(usize, u64)> {
+    let mut pos = gif_header_len(file)?;
+    let mut frames = 0;
+    let mut duration_ms = 0;
+
+    loop {
+        let (introducer, label, end) = gif_block(file, pos)?;
+        match (introducer, label) {
+            (GIF_TRAILER, _) => return Some((frames, duration_ms)),
+            (GIF_IMAGE, _) => frames += 1,
+            // The delay is in hundredths of a second, after the block size and flags
+            (GIF_EXTENSION, 0xF9) => {
+                let delay = file.get(pos + 4..pos + 6)?;
+                duration_ms += u16::from_le_bytes([delay[0], delay[1]]) as u64 * 10;
+            },
+            _ => (),
+        }
+        pos = end;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::codecs::gif::GifEncoder;
+    use image::{Frame, Rgba, RgbaImage};
+
+    fn encode(format: ImageFormat) -> Vec<u8> {
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_pixel(4, 4, [200, 40, 90].into()));
+        let mut out = vec![];
+        match format {
+            ImageFormat::WebP => img
+                .write_with_encoder(WebPEncoder::new_lossless(&mut out))
+                .unwrap(),
+            ImageFormat::Gif => GifEncoder::new(&mut out)
+                .encode_frame(Frame::new(RgbaImage::from_pixel(
+                    4,
+                    4,
+                    Rgba([200, 40, 90, 255]),
+                )))
+                .unwrap(),
+            _ => img.write_to(&mut Cursor::new(&mut out), format).unwrap(),
+        }
+        out
+    }
+
+    fn decodes(file: &[u8], format: ImageFormat) -> bool {
+        image::load_from_memory_with_format(file, format).is_ok()
+    }
+
+    fn contains(file: &[u8], needle: &[u8]) -> bool {
+        file.windows(needle.len()).any(|window| window == needle)
+    }
+
+    fn splice(file: &[u8], pos: usize, insert: &[&[u8]]) -> Vec<u8> {
+        let mut out = file[..pos].to_vec();
+        for part in insert {
+            out.extend_from_slice(part);
+        }
+        out.extend_from_slice(&file[pos..]);
+        out
+    }
+
+    fn jpeg_segment(marker: u8, data: &[u8]) -> Vec<u8> {
+        let mut out = vec![0xFF, marker];
+        out.extend_from_slice(&(data.len() as u16 + 2).to_be_bytes());
+        out.extend_from_slice(data);
+        out
+    }
+
+    fn png_chunk(chunk_type: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut crc = flate2::Crc::new();
+        crc.update(chunk_type);
+        crc.update(data);
+
+        let mut out = (data.len() as u32).to_be_bytes().to_vec();
+        out.extend_from_slice(chunk_type);
+        out.extend_from_slice(data);
+        out.extend_from_slice(&crc.sum().to_be_bytes());
+        out
+    }
+
+    fn riff_chunk(chunk_type: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut out = chunk_type.to_vec();
+        out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        out.extend_from_slice(data);
+        if data.len() % 2 == 1 {
+            out.push(0);
+        }
+        out
+    }
+
+    #[test]
+    fn strips_jpeg_metadata() {
+        let jpeg = encode(ImageFormat::Jpeg);
+        let tagged = [
+            splice(
+                &jpeg,
+                2,
+                &[
+                    &jpeg_segment(0xE1, b"Exif\0\0GPS here"),
+                    &jpeg_segment(0xE1, b"http://ns.adobe.com/xap/1.0/\0<x:xmpmeta/>"),
+                    &jpeg_segment(0xFE, b"a comment"),
+                ],
+            ),
+            b"trailing payload".to_vec(),
+        ]
+        .concat();
+        assert!(decodes(&tagged, ImageFormat::Jpeg));
+
+        let stripped = strip_jpeg(&tagged).unwrap();
+        assert_eq!(stripped, jpeg);
+        assert!(decodes(&stripped, ImageFormat::Jpeg));
+    }
+
+    #[test]
+    fn strips_png_metadata() {
+        let png = encode(ImageFormat::Png);
+        // After the signature and the IHDR chunk
+        let tagged = [
+            splice(
+                &png,
+                33,
+                &[
+                    &png_chunk(b"tEXt", b"Comment\0a comment"),
+                    &png_chunk(b"eXIf", b"MM\0*GPS here"),
+                    &png_chunk(b"iTXt", b"XML:com.adobe.xmp\0\0\0\0\0<x:xmpmeta/>"),
+                ],
+            ),
+            b"trailing payload".to_vec(),
+        ]
+        .concat();
+        assert!(decodes(&tagged, ImageFormat::Png));
+
+        let stripped = strip_png(&tagged).unwrap();
+        assert_eq!(stripped, png);
+        assert!(decodes(&stripped, ImageFormat::Png));
+    }
+
+    #[test]
+    fn strips_webp_metadata() {
+        let webp = encode(ImageFormat::WebP);
+        let image_chunk = &webp[12..];
+
+        // An extended header flagging EXIF and XMP, on a 4x4 canvas
+        let mut vp8x = vec![0x08 | 0x04, 0, 0, 0];
+        vp8x.extend_from_slice(&[3, 0, 0, 3, 0, 0]);
+        let body = [
+            b"WEBP".to_vec(),
+            riff_chunk(b"VP8X", &vp8x),
+            image_chunk.to_vec(),
+            riff_chunk(b"EXIF", b"MM\0*GPS here"),
+            riff_chunk(b"XMP ", b"<x:xmpmeta/>"),
+        ]
+        .concat();
+        let tagged = [
+            b"RIFF".to_vec(),
+            (body.len() as u32).to_le_bytes().to_vec(),
+            body,
+            b"trailing payload".to_vec(),
+        ]
+        .concat();
+        assert!(decodes(&tagged, ImageFormat::WebP));
+
+        let stripped = strip_webp(&tagged).unwrap();
+        assert!(!contains(&stripped, b"GPS here"));
+        assert!(!contains(&stripped, b"xmpmeta"));
+        assert!(!contains(&stripped, b"trailing"));
+        assert_eq!(stripped[20], 0);
+        assert_eq!(riff_end(&stripped), Some(stripped.len()));
+        assert!(decodes(&stripped, ImageFormat::WebP));
+    }
+
+    #[test]
+    fn strips_gif_metadata() {
+        let gif = encode(ImageFormat::Gif);
+        let header_len = gif_header_len(&gif).unwrap();
+
+        let comment = [&[GIF_EXTENSION, 0xFE, 9][..], b"a comment", &[0]].concat();
+        let xmp = [
+            &[GIF_EXTENSION, 0xFF, 11][..],
+            b"XMP DataXMP",
+            &[12],
+            b"<x:xmpmeta/>",
+            &[0],
+        ]
+        .concat();
+        let tagged = [
+            splice(&gif, header_len, &[&comment, &xmp]),
+            b"trailing payload".to_vec(),
+        ]
+        .concat();
+        assert!(decodes(&tagged, ImageFormat::Gif));
+
+        let stripped = strip_gif(&tagged).unwrap();
+        assert_eq!(stripped, gif);
+        assert!(decodes(&stripped, ImageFormat::Gif));
+    }
+
+    #[test]
+    fn reencodes_other_formats() {
+        let config = UploadConfig {
+            ban_distance:   0,
+            auto_ban_ip:    false,
+            strip_metadata: true,
+            reencode:       false,
+            check_type:     true,
+            max_width:      64,
+            max_height:     64,
+            max_pixels:     4096,
+            max_alloc:      1 << 20,
+        };
+        let tiff = [encode(ImageFormat::Tiff), b"GPS here".to_vec()].concat();
+
+        let sanitised = sanitise(&config, Bytes::from(tiff), "photo.tiff").unwrap();
+        assert!(!contains(&sanitised, b"GPS here"));
+        assert!(decodes(&sanitised, ImageFormat::Tiff));
+    }
+
+    #[test]
+    fn keeps_gif_loop_counts() {
+        let gif = encode(ImageFormat::Gif);
+        let header_len = gif_header_len(&gif).unwrap();
+
+        let looping = [
+            &[GIF_EXTENSION, 0xFF, 11][..],
+            b"NETSCAPE2.0",
+            &[3, 1, 0, 0, 0],
+        ]
+        .concat();
+        let tagged = splice(&gif, header_len, &[&looping]);
+        assert_eq!(strip_gif(&tagged).unwrap(), tagged);
+    }
+}
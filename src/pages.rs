@@ -1,13 +1,17 @@
 use crate::Config;
 use crate::db;
+use crate::encoding;
 use crate::format;
+use crate::media;
 use crate::site;
 use crate::site::Post;
 use crate::template;
 use crate::util;
+use bytes::Bytes;
 use lazy_static::lazy_static;
 use regex::Regex;
 use std::collections::{HashMap, HashSet};
+use std::sync::OnceLock;
 
 // Maximum number of post replies to process in a given post
 // Prevents maliciously tagging everyone in a thread
@@ -21,7 +25,8 @@ pub struct StaticPages {
 #[derive(Hash, PartialEq, Eq, Clone, Copy)]
 pub enum PageRef {
     Homepage,
-    Catalog(u64),
+    // Each sort order is cached separately, while filtered catalogs are never cached
+    Catalog(u64, db::CatalogSort),
     Archive(u64),
     Thread(u64, u64),
     Create(u64),
@@ -30,7 +35,46 @@ pub enum PageRef {
 pub struct Page {
     pub page_ref:    PageRef,
     pub render_time: u64,
+    // Time at which the page content last changed, which may predate the latest render
+    pub modified:    u64,
+    pub etag:        String,
     pub page_text:   String,
+    // Each encoding is compressed on its first request, then reused until the next render
+    page_gzip:       OnceLock<Bytes>,
+    page_brotli:     OnceLock<Bytes>,
+}
+
+impl Page {
+    fn new(page_ref: PageRef, epoch: u64, render_data: &template::Data, page_text: String) -> Page {
+        let now = util::timestamp();
+
+        // The epoch is included so that template changes invalidate ETags after a restart
+        let etag = sha256::digest(format!("{}:{}", epoch, render_data.digest()));
+
+        Page {
+            page_ref,
+            render_time: now,
+            modified: now,
+            etag: format!("\"{}\"", &etag[..32]),
+            page_text,
+            page_gzip: OnceLock::new(),
+            page_brotli: OnceLock::new(),
+        }
+    }
+
+    pub fn encoded(&self, enc: encoding::Encoding) -> Bytes {
+        match enc {
+            encoding::Encoding::Identity => Bytes::from(self.page_text.clone()),
+            encoding::Encoding::Gzip => self
+                .page_gzip
+                .get_or_init(|| encoding::gzip(self.page_text.as_bytes()))
+                .clone(),
+            encoding::Encoding::Brotli => self
+                .page_brotli
+                .get_or_init(|| encoding::brotli(self.page_text.as_bytes()))
+                .clone(),
+        }
+    }
 }
 
 pub struct SiteTemplates {
@@ -43,6 +87,7 @@ pub struct SiteTemplates {
 
 pub struct Pages {
     site:        site::Site,
+    epoch:       u64,
     pages:       HashMap<PageRef, Page>,
     templates:   SiteTemplates,
     render_freq: u64,
@@ -52,6 +97,7 @@ fn clone_option_string_or_empty(o_str: &Option<String>) -> String {
     o_str.as_deref().map(|s| s.to_string()).unwrap_or_default()
 }
 
+#[allow(clippy::collapsible_if)]
 fn compute_fwd_links(thread: &db::Thread, posts: &HashSet<u64>) -> HashMap<u64, Vec<u64>> {
     lazy_static! {
         // Capture replies of form >>390290
@@ -105,18 +151,25 @@ fn populate_site_data(data: &mut template::Data, site: &site::Site) {
 fn populate_board_data(data: &mut template::Data, board: site::Board) {
     data.insert_value("board_url", board.url);
     data.insert_value("board_title", board.title);
+    data.insert_value("board_max_files", board.max_files.to_string());
+    data.set_flag("board_multiple_files", board.max_files > 1);
+
+    // Posting forms have a file input and an alt text input for each file a post may have
+    let mut slots = vec![];
+    for slot in 1..=board.max_files {
+        data.insert_collection_value("file_slot", slot, "num", slot.to_string());
+        slots.push(slot.to_string());
+    }
+    data.add_collection("file_slot", slots);
 }
 
-fn populate_preview<F>(data: &mut template::Data, originals: Vec<site::Original>, orig_filter: F)
-where
-    F: Fn(&site::Original) -> bool,
-{
+fn populate_preview(data: &mut template::Data, originals: Vec<site::Original>, config: &Config) {
+    let thumbs = &config.thumbnails;
+
     let mut orig_idents = vec![];
     let mut any_pending = false;
 
-    let filtered = originals.into_iter().filter(orig_filter);
-
-    for orig in filtered {
+    for orig in originals {
         match orig.approval {
             site::Approval::Approved => (),
             _ => {
@@ -125,11 +178,54 @@ where
             },
         }
 
+        let first_file = orig.files().first();
+        let file_id = first_file.map_or("", |file| file.file_id.as_str());
+        let spoiler = first_file.is_some_and(|file| file.spoiler);
+
+        // Spoilered threads can still be revealed from the catalog, without scripts
+        data.set_collection_flag("original", orig.post_num(), "spoiler", spoiler);
+
+        data.insert_collection_value(
+            "original",
+            orig.post_num(),
+            "file_alt",
+            match first_file {
+                Some(file) if !spoiler => format::alt_text(file),
+                Some(_) => String::from("Spoiler"),
+                None => String::from(""),
+            },
+        );
+
+        data.insert_collection_value(
+            "original",
+            orig.post_num(),
+            "full_file_url",
+            format!("/files/{}", file_id),
+        );
+
         data.insert_collection_value(
             "original",
             orig.post_num(),
             "file_url",
-            format!("/thumbnails/{}", orig.file_id().unwrap_or("")),
+            if spoiler {
+                config.assets.url("spoiler.svg")
+            } else {
+                format!("/thumbnails/catalog/{}", file_id)
+            },
+        );
+
+        data.insert_collection_value(
+            "original",
+            orig.post_num(),
+            "thumb_size",
+            if spoiler {
+                format::spoiler_size_attrs(thumbs.catalog_size)
+            } else {
+                format::thumb_size_attrs(
+                    first_file.and_then(|file| file.file_info.as_ref()),
+                    thumbs.catalog_size,
+                )
+            },
         );
 
         data.insert_collection_value(
@@ -166,7 +262,7 @@ where
         );
 
         let mut cat_desc = orig.body().to_string();
-        if let Some((i, _)) = cat_desc.char_indices().nth(100) {
+        if let Some((i, _)) = cat_desc.char_indices().nth(db::PREVIEW_BODY_CHARS) {
             cat_desc.truncate(i);
         }
 
@@ -187,7 +283,7 @@ where
 impl Pages {
     pub fn render<DB: db::Database>(
         &self,
-        _config: &Config,
+        config: &Config,
         database: &DB,
         pr: &PageRef,
     ) -> Result<Page, util::PlainchantErr> {
@@ -218,43 +314,23 @@ impl Pages {
                 render_data.add_collection("board", board_ids);
 
                 let page_text = self.templates.homepage_tmpl.render(&render_data);
-                Ok(Page {
-                    page_ref: *pr,
-                    render_time: util::timestamp(),
-                    page_text,
-                })
+                Ok(Page::new(*pr, self.epoch, &render_data, page_text))
             },
-            PageRef::Catalog(board_id) => {
-                let mut render_data = template::Data::full();
-                populate_site_data(&mut render_data, &self.site);
-                populate_board_data(&mut render_data, database.get_board(*board_id)?);
-
-                let cat_origs = database.get_catalog(*board_id)?.originals;
-                let select_catalog = |orig: &site::Original| !orig.archived;
-                populate_preview(&mut render_data, cat_origs, select_catalog);
-
-                let page_text = self.templates.catalog_tmpl.render(&render_data);
-                Ok(Page {
-                    page_ref: *pr,
-                    render_time: util::timestamp(),
-                    page_text,
-                })
+            PageRef::Catalog(board_id, sort) => {
+                self.render_catalog(config, database, *board_id, *sort, None)
             },
             PageRef::Archive(board_id) => {
                 let mut render_data = template::Data::full();
                 populate_site_data(&mut render_data, &self.site);
                 populate_board_data(&mut render_data, database.get_board(*board_id)?);
 
-                let cat_origs = database.get_catalog(*board_id)?.originals;
-                let select_archive = |orig: &site::Original| orig.archived;
-                populate_preview(&mut render_data, cat_origs, select_archive);
+                let cat_origs = database
+                    .get_catalog_preview(*board_id, true, db::CatalogSort::Bump, None)?
+                    .originals;
+                populate_preview(&mut render_data, cat_origs, config);
 
                 let page_text = self.templates.archive_tmpl.render(&render_data);
-                Ok(Page {
-                    page_ref: *pr,
-                    render_time: util::timestamp(),
-                    page_text,
-                })
+                Ok(Page::new(*pr, self.epoch, &render_data, page_text))
             },
             PageRef::Thread(board_id, orig_num) => {
                 let thread = database.get_thread(*board_id, *orig_num)?;
@@ -296,14 +372,27 @@ impl Pages {
 
                 render_data.insert_value("img_replies", thread.original.img_replies().to_string());
 
+                // Link previews show the thread's first file
                 render_data.insert_value(
                     "orig_file_url",
-                    format!("/files/{}", thread.original.file_id().unwrap_or("")),
+                    format!(
+                        "/files/{}",
+                        thread
+                            .original
+                            .files()
+                            .first()
+                            .map_or("", |file| file.file_id.as_str())
+                    ),
                 );
 
                 render_data.insert_value(
-                    "orig_thumbnail_url",
-                    format!("/thumbnails/{}", thread.original.file_id().unwrap_or("")),
+                    "orig_files",
+                    format::display_files(
+                        thread.original.files(),
+                        media::ThumbKind::Op,
+                        config.thumbnails.op_size,
+                        &config.assets.url("spoiler.svg"),
+                    ),
                 );
 
                 let title = thread.original.title().map(format::html_escape_and_trim);
@@ -366,22 +455,20 @@ impl Pages {
                     render_data.insert_collection_value(
                         "reply",
                         reply.post_num(),
-                        "file_url",
-                        format!("/files/{}", reply.file_id().unwrap_or("")),
-                    );
-
-                    render_data.insert_collection_value(
-                        "reply",
-                        reply.post_num(),
-                        "thumbnail_url",
-                        format!("/thumbnails/{}", reply.file_id().unwrap_or("")),
+                        "files",
+                        format::display_files(
+                            reply.files(),
+                            media::ThumbKind::Reply,
+                            config.thumbnails.reply_size,
+                            &config.assets.url("spoiler.svg"),
+                        ),
                     );
 
                     render_data.set_collection_flag(
                         "reply",
                         reply.post_num(),
                         "has_image",
-                        reply.file_id().is_some(),
+                        !reply.files().is_empty(),
                     );
 
                     render_data.insert_collection_value(
@@ -451,28 +538,99 @@ impl Pages {
                     .add_collection("reply", replies.iter().map(|r| r.to_string()).collect());
 
                 let page_text = self.templates.thread_tmpl.render(&render_data);
-                Ok(Page {
-                    page_ref: *pr,
-                    render_time: util::timestamp(),
-                    page_text,
-                })
+                Ok(Page::new(*pr, self.epoch, &render_data, page_text))
             },
             PageRef::Create(board_id) => {
-                let mut render_data = template::Data::simple();
+                let mut render_data = template::Data::full();
                 populate_site_data(&mut render_data, &self.site);
                 populate_board_data(&mut render_data, database.get_board(*board_id)?);
 
                 let page_text = self.templates.create_tmpl.render(&render_data);
-                Ok(Page {
-                    page_ref: *pr,
-                    render_time: util::timestamp(),
-                    page_text,
-                })
+                Ok(Page::new(*pr, self.epoch, &render_data, page_text))
             },
         }
     }
 
-    pub fn update(&mut self, pr: &PageRef, page: Page) -> &Page {
+    pub fn render_catalog<DB: db::Database>(
+        &self,
+        config: &Config,
+        database: &DB,
+        board_id: u64,
+        sort: db::CatalogSort,
+        filter: Option<&str>,
+    ) -> Result<Page, util::PlainchantErr> {
+        let mut render_data = template::Data::full();
+        populate_site_data(&mut render_data, &self.site);
+        let board = database.get_board(board_id)?;
+        let board_url = board.url.clone();
+        populate_board_data(&mut render_data, board);
+
+        // The filter is kept when switching sorts, and bump order needs no parameter
+        let filter_param = filter.map_or(String::new(), |filter| {
+            url::form_urlencoded::byte_serialize(filter.as_bytes()).collect::<String>()
+        });
+
+        let mut sort_names = vec![];
+        for option in db::CatalogSort::ALL {
+            let mut params = vec![];
+            if option != db::CatalogSort::Bump {
+                params.push(format!("sort={}", option.name()));
+            }
+            if filter.is_some() {
+                params.push(format!("q={}", filter_param));
+            }
+
+            let url = if params.is_empty() {
+                format!("/{}/catalog", board_url)
+            } else {
+                format!("/{}/catalog?{}", board_url, params.join("&amp;"))
+            };
+
+            render_data.insert_collection_value("sort", option.name(), "url", url);
+            render_data.insert_collection_value(
+                "sort",
+                option.name(),
+                "description",
+                option.description().to_string(),
+            );
+            render_data.set_collection_flag("sort", option.name(), "current", option == sort);
+            sort_names.push(option.name().to_string());
+        }
+        render_data.add_collection("sort", sort_names);
+
+        render_data.insert_value("catalog_sort", sort.name().to_string());
+        render_data.insert_value(
+            "catalog_filter",
+            format::html_escape_attr(filter.unwrap_or("")),
+        );
+        render_data.set_flag("catalog_filtered", filter.is_some());
+
+        let cat_origs = database
+            .get_catalog_preview(board_id, false, sort, filter)?
+            .originals;
+        render_data.set_flag(
+            "catalog_no_matches",
+            filter.is_some() && cat_origs.is_empty(),
+        );
+        populate_preview(&mut render_data, cat_origs, config);
+
+        let page_text = self.templates.catalog_tmpl.render(&render_data);
+        Ok(Page::new(
+            PageRef::Catalog(board_id, sort),
+            self.epoch,
+            &render_data,
+            page_text,
+        ))
+    }
+
+    pub fn update(&mut self, pr: &PageRef, mut page: Page) -> &Page {
+        // Re-rendering unchanged content should not make clients download it again
+        if let Some(old) = self.pages.get(pr)
+            && old.etag == page.etag
+        {
+            page.modified = old.modified;
+        }
+
         self.pages.insert(*pr, page);
         self.pages.get(pr).unwrap()
     }
@@ -480,7 +638,7 @@ impl Pages {
     pub fn page_exists<DB: db::Database>(&self, database: &DB, pr: &PageRef) -> bool {
         match pr {
             PageRef::Homepage => true,
-            PageRef::Catalog(board_id) => database.get_board(*board_id).is_ok(),
+            PageRef::Catalog(board_id, _) => database.get_board(*board_id).is_ok(),
             PageRef::Archive(board_id) => database.get_board(*board_id).is_ok(),
             PageRef::Thread(board_id, orig_num) => {
                 database.get_thread(*board_id, *orig_num).is_ok()
@@ -526,6 +684,7 @@ impl Pages {
 
         Ok(Pages {
             site,
+            epoch: util::timestamp(),
             pages,
             templates,
             render_freq,
@@ -1,25 +1,33 @@
 use crate::Config;
 use crate::actions;
 use crate::api;
+use crate::backup;
 use crate::console;
 use crate::db;
+use crate::encoding;
 use crate::fr;
+use crate::headers;
+use crate::media;
 use crate::pages;
 use crate::state::{DbState, FrState, PlainchantState};
 use crate::template::{Data, Template};
+use crate::util;
 use crate::util::{ErrOrigin, unwrap_or_return};
+use crate::zip;
 
 use axum::ServiceExt;
 use axum::extract::{Request, State};
 use axum::http;
 use axum::http::header::HeaderMap;
 use axum::http::{StatusCode, Uri};
-use axum::response::{ErrorResponse, Html, IntoResponse, IntoResponseParts};
+use axum::response::{ErrorResponse, Html, IntoResponse, IntoResponseParts, Response};
 use axum::{Router, body, extract, response, routing};
 
 use tower::Layer;
 use tower_http::normalize_path::NormalizePathLayer;
+use tower_http::set_header::SetResponseHeaderLayer;
 
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio_util::io::ReaderStream;
 
 use bytes::{BufMut, Bytes, BytesMut};
@@ -28,11 +36,16 @@ use std::net::{IpAddr, SocketAddr};
 use std::ops::DerefMut;
 use std::path;
 use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
 
 // This value is equivalent to 64 MiB in bytes;
 const FORM_MAX_LENGTH: usize = 67_108_864;
 // This values is equivalent to 4 MiB in bytes;
 const FILE_MAX_SIZE: usize = 4_194_304;
+// Thread zips are streamed through a pipe of this size, so only a little is held at once
+const ZIP_BUFFER: usize = 65_536;
+// Catalog filters are cut to this many characters, as each is matched against every thread
+const CATALOG_FILTER_CHARS: usize = 128;
 
 // Utility functions to generate static pages
 
@@ -71,12 +84,73 @@ fn not_found(sp: &pages::StaticPages, message: &str) -> (StatusCode, Html<String
     (StatusCode::NOT_FOUND, message_page(sp, message))
 }
 
+fn unprocessable(sp: &pages::StaticPages, message: &str) -> (StatusCode, Html<String>) {
+    (StatusCode::UNPROCESSABLE_ENTITY, message_page(sp, message))
+}
+
+fn image_too_large_message(config: &Config) -> String {
+    format!(
+        "Image is too large - images may be at most {}x{} pixels and {} megapixels",
+        config.uploads.max_width,
+        config.uploads.max_height,
+        config.uploads.max_pixels as f64 / 1_000_000.0
+    )
+}
+
 fn forbidden(sp: &pages::StaticPages, message: &str) -> (StatusCode, Html<String>) {
     (StatusCode::FORBIDDEN, message_page(sp, message))
 }
 
-fn ok_page(page: &pages::Page) -> (StatusCode, Html<String>) {
-    (StatusCode::OK, Html(page.page_text.to_string()))
+// Respond with 304 Not Modified, repeating the validators and caching policy
+fn not_modified(cache_control: &str, etag: &str, modified: SystemTime) -> Response {
+    (
+        StatusCode::NOT_MODIFIED,
+        [
+            ("Cache-Control", cache_control.to_string()),
+            ("ETag", etag.to_string()),
+            ("Last-Modified", headers::http_date(modified)),
+        ],
+    )
+        .into_response()
+}
+
+fn ok_page(page: &pages::Page, req_headers: &HeaderMap) -> Response {
+    let modified = headers::timestamp_to_system_time(page.modified);
+    let enc = encoding::negotiate(req_headers)[0];
+    let etag = enc.tag_etag(&page.etag);
+
+    let mut response = if headers::is_not_modified(req_headers, &etag, modified) {
+        not_modified(headers::CACHE_PAGE, &etag, modified)
+    } else {
+        (
+            StatusCode::OK,
+            [
+                ("Content-Type", "text/html; charset=utf-8".to_string()),
+                ("Cache-Control", headers::CACHE_PAGE.to_string()),
+                ("ETag", etag),
+                ("Last-Modified", headers::http_date(modified)),
+            ],
+            page.encoded(enc),
+        )
+            .into_response()
+    };
+
+    set_content_encoding(&mut response, enc);
+    response
+}
+
+fn set_content_encoding(response: &mut Response, enc: encoding::Encoding) {
+    let resp_headers = response.headers_mut();
+    resp_headers.insert(
+        http::header::VARY,
+        http::HeaderValue::from_static("Accept-Encoding"),
+    );
+    if let Some(content_encoding) = enc.content_encoding() {
+        resp_headers.insert(
+            http::header::CONTENT_ENCODING,
+            http::HeaderValue::from_static(content_encoding),
+        );
+    }
 }
 
 fn render_page<DB: db::Database>(
@@ -85,20 +159,21 @@ fn render_page<DB: db::Database>(
     pages: Arc<RwLock<pages::Pages>>,
     db: Arc<DB>,
     page_ref: &pages::PageRef,
-) -> (StatusCode, Html<String>) {
+    req_headers: &HeaderMap,
+) -> Response {
     let page = {
         let pg = unwrap_or_return!(pages.read(), {
-            internal_error(&sp, "Could not gain read access to Pages")
+            internal_error(&sp, "Could not gain read access to Pages").into_response()
         });
 
         match pg.render(config.as_ref(), db.as_ref(), page_ref) {
             Ok(page) => page,
             Err(err) => match err.origin {
                 ErrOrigin::Web => {
-                    return web_error(&sp, err.code, &err.msg);
+                    return web_error(&sp, err.code, &err.msg).into_response();
                 },
                 _ => {
-                    return internal_error(&sp, "Failed to render page");
+                    return internal_error(&sp, "Failed to render page").into_response();
                 },
             },
         }
@@ -107,45 +182,125 @@ fn render_page<DB: db::Database>(
     // Only grab the write-lock for inserting into the page map
 
     let mut pg = unwrap_or_return!(pages.write(), {
-        internal_error(&sp, "Could not gain write access to Pages")
+        internal_error(&sp, "Could not gain write access to Pages").into_response()
     });
 
     let pages = pg.deref_mut();
     let page = pages.update(page_ref, page);
-    ok_page(page)
+    ok_page(page, req_headers)
 }
 
 // static_dir: Handler to serve static resources
 
+// Open a precompressed sibling of a static file (e.g. thread.css.br) if one is acceptable
+async fn open_static_file(
+    full_path: &path::Path,
+    req_headers: &HeaderMap,
+) -> Option<(tokio::fs::File, encoding::Encoding)> {
+    for enc in encoding::negotiate(req_headers) {
+        let enc_path = match enc.file_extension() {
+            Some(ext) => {
+                let mut enc_path = full_path.as_os_str().to_owned();
+                enc_path.push(".");
+                enc_path.push(ext);
+                path::PathBuf::from(enc_path)
+            },
+            None => full_path.to_path_buf(),
+        };
+
+        if let Ok(file) = tokio::fs::File::open(&enc_path).await {
+            return Some((file, enc));
+        }
+    }
+    None
+}
+
 async fn static_dir(
     State(config): State<Arc<Config>>,
     extract::Path(path): extract::Path<path::PathBuf>,
+    req_headers: HeaderMap,
 ) -> impl IntoResponse {
+    let (path, cache_control) = match path.to_str().and_then(|p| config.assets.resolve(p)) {
+        Some(logical) => (
+            path::PathBuf::from(logical),
+            headers::CACHE_STATIC_FINGERPRINTED,
+        ),
+        None => (path, headers::CACHE_STATIC),
+    };
+
     let full_path = config.static_dir.join(&path);
 
-    match tokio::fs::File::open(&full_path).await {
-        Ok(file) => {
-            let mime = mime_guess::from_path(&full_path).first_or_octet_stream();
-            let headers = response::AppendHeaders([
-                ("Content-Type", mime.to_string()),
-                (
-                    "Cache-Control",
-                    "Cache-Control: public, max-age=604800".to_string(),
-                ),
-            ]);
-
-            let stream = ReaderStream::new(file);
-            let body = body::Body::from_stream(stream);
-
-            Ok((headers, body))
+    match open_static_file(&full_path, &req_headers).await {
+        Some((file, enc)) => {
+            let meta = file.metadata().await.ok();
+            let len = meta.as_ref().map(|m| m.len()).unwrap_or(0);
+            let modified = meta
+                .and_then(|m| m.modified().ok())
+                .unwrap_or(SystemTime::UNIX_EPOCH);
+
+            let etag = enc.tag_etag(&format!(
+                "W/\"{:x}-{:x}\"",
+                len,
+                modified
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or(0)
+            ));
+
+            let mut response = if headers::is_not_modified(&req_headers, &etag, modified) {
+                not_modified(cache_control, &etag, modified)
+            } else {
+                let mime = mime_guess::from_path(&full_path).first_or_octet_stream();
+                let headers = response::AppendHeaders([
+                    ("Content-Type", mime.to_string()),
+                    ("Content-Length", len.to_string()),
+                    ("Cache-Control", cache_control.to_string()),
+                    ("ETag", etag),
+                    ("Last-Modified", headers::http_date(modified)),
+                ]);
+
+                let stream = ReaderStream::new(file);
+                let body = body::Body::from_stream(stream);
+
+                (headers, body).into_response()
+            };
+
+            set_content_encoding(&mut response, enc);
+            Ok(response)
         },
-        Err(_err) => Err((
+        None => Err((
             StatusCode::NOT_FOUND,
             format!("Could not retrieve static file: {}", path.to_string_lossy()),
         )),
     }
 }
 
+// Serve a page from the cache, rendering it first if it is missing or stale
+// Checking and rendering pages queries the database, so this runs off the executor
+async fn serve_page<DB: db::Database>(
+    config: Arc<Config>,
+    sp: Arc<pages::StaticPages>,
+    pages: Arc<RwLock<pages::Pages>>,
+    db: Arc<DB>,
+    page_ref: pages::PageRef,
+    req_headers: HeaderMap,
+) -> Result<Response, util::PlainchantErr> {
+    util::blocking(move || {
+        {
+            let pg = unwrap_or_return!(pages.read(), {
+                Ok(internal_error(&sp, "Could not gain read access to Pages").into_response())
+            });
+
+            if let Some(page) = pg.get_page(db.as_ref(), &page_ref)? {
+                return Ok(ok_page(page, &req_headers));
+            }
+        }
+
+        Ok(render_page(config, sp, pages, db, &page_ref, &req_headers))
+    })
+    .await
+}
+
 // thread: Handler to serve thread pages
 
 async fn thread<DB: db::Database>(
@@ -154,38 +309,139 @@ async fn thread<DB: db::Database>(
     State(pages): State<Arc<RwLock<pages::Pages>>>,
     State(actions): State<Arc<actions::Actions>>,
     State(DbState { db }): State<DbState<DB>>,
+    req_headers: HeaderMap,
     extract::Path((board, post_num)): extract::Path<(String, u64)>,
-) -> Result<(StatusCode, Html<String>), ErrorResponse> {
-    let page_ref = {
-        let board_id = unwrap_or_return!(actions.board_url_to_id(&board), {
-            Ok(not_found(&sp, "No such board"))
-        });
-
-        let pg = unwrap_or_return!(pages.read(), {
-            Ok(internal_error(&sp, "Could not gain read access to Pages"))
-        });
+) -> Result<Response, ErrorResponse> {
+    let board_id = unwrap_or_return!(actions.board_url_to_id(&board), {
+        Ok(not_found(&sp, "No such board").into_response())
+    });
 
-        let page_ref = pages::PageRef::Thread(board_id, post_num);
+    let page_ref = pages::PageRef::Thread(board_id, post_num);
 
-        match pg.get_page(db.as_ref(), &page_ref) {
-            Ok(None) => page_ref,
-            Ok(Some(page)) => {
-                return Ok(ok_page(page));
-            },
-            Err(_) => {
-                // The board exists but the original post does not
-                // Let's try and fetch it as a reply
-                let reply = unwrap_or_return!(db.get_reply(board_id, post_num), {
+    match serve_page(config, sp.clone(), pages, db.clone(), page_ref, req_headers).await {
+        Ok(response) => Ok(response),
+        Err(err) if err.code == 404 => {
+            // The board exists but the original post does not
+            // Let's try and fetch it as a reply
+            let reply = unwrap_or_return!(
+                util::blocking(move || db.get_reply(board_id, post_num)).await,
+                {
                     // TODO this should check the error code now we have it
-                    Ok(not_found(&sp, "No such thread"))
-                });
-                let uri = format!("/{}/thread/{}#{}", &board, reply.orig_num, post_num);
-                return Err(response::Redirect::permanent(&uri).into());
-            },
-        }
+                    Ok(not_found(&sp, "No such thread").into_response())
+                }
+            );
+            let uri = format!("/{}/thread/{}#{}", &board, reply.orig_num, post_num);
+            Err(response::Redirect::permanent(&uri).into())
+        },
+        Err(err) => Ok(web_error(&sp, err.code, &err.msg).into_response()),
+    }
+}
+
+// thread_zip: Handler for downloading every file in a thread at once
+// The zip is streamed as each file is read from the rack, and files which cannot be
+// opened are left out. A file which fails part way through ends the download early.
+
+async fn thread_zip<DB: db::Database, FR: fr::FileRack>(
+    State(sp): State<Arc<pages::StaticPages>>,
+    State(actions): State<Arc<actions::Actions>>,
+    State(DbState { db }): State<DbState<DB>>,
+    State(FrState { fr }): State<FrState<FR>>,
+    extract::ConnectInfo(addr): extract::ConnectInfo<SocketAddr>,
+    req_headers: HeaderMap,
+    extract::Path((board, post_num)): extract::Path<(String, u64)>,
+) -> Result<Response, ErrorResponse> {
+    let board_id = unwrap_or_return!(actions.board_url_to_id(&board), {
+        Ok(not_found(&sp, "No such board").into_response())
+    });
+
+    let ip = determine_poster_ip(addr, &req_headers);
+
+    let result = {
+        let (actions, fr) = (actions.clone(), fr.clone());
+        util::blocking(move || actions.thread_zip(db.as_ref(), fr.as_ref(), ip, board_id, post_num))
+            .await
+    };
+
+    let files = match result {
+        Ok(actions::ThreadZipResult::Ready(files)) => files,
+        Ok(actions::ThreadZipResult::Cooldown) => {
+            return Ok(web_error(
+                &sp,
+                429,
+                "Please wait a minute before downloading another thread's files",
+            )
+            .into_response());
+        },
+        Ok(actions::ThreadZipResult::TooLarge) => {
+            return Ok(web_error(
+                &sp,
+                413,
+                "This thread's files are too large to download together",
+            )
+            .into_response());
+        },
+        Err(err) if err.code == 404 => {
+            return Ok(not_found(&sp, "No such thread").into_response());
+        },
+        Err(_) => return Ok(internal_error(&sp, "Could not gather thread files").into_response()),
     };
 
-    Ok(render_page(config, sp, pages, db, &page_ref))
+    let (mut writer, reader) = tokio::io::duplex(ZIP_BUFFER);
+
+    tokio::spawn(async move {
+        let mut zip = zip::ZipWriter::new();
+        let mut buf = vec![0; ZIP_BUFFER];
+        for file in files {
+            let fr = fr.clone();
+            let file_id = file.file_id.clone();
+            let opened = util::blocking(move || {
+                let stat = fr.stat_file(&file_id)?;
+                fr.read_file_range(&file_id, 0, stat.len)
+            })
+            .await;
+            let mut data = match opened {
+                Ok(data) => data,
+                Err(_) => continue,
+            };
+
+            // Stops if the client has gone away
+            if writer
+                .write_all(&zip.start_entry(&file.name, file.modified))
+                .await
+                .is_err()
+            {
+                return;
+            }
+            loop {
+                let read = match data.read(&mut buf).await {
+                    Ok(0) => break,
+                    Ok(read) => read,
+                    Err(_) => return,
+                };
+                zip.data(&buf[..read]);
+                if writer.write_all(&buf[..read]).await.is_err() {
+                    return;
+                }
+            }
+            if writer.write_all(&zip.end_entry()).await.is_err() {
+                return;
+            }
+        }
+        let _ = writer.write_all(&zip.finish()).await;
+    });
+
+    Ok((
+        StatusCode::OK,
+        [
+            ("Content-Type", "application/zip".to_string()),
+            (
+                "Content-Disposition",
+                format!("attachment; filename=\"{}-{}.zip\"", board, post_num),
+            ),
+        ],
+        body::Body::from_stream(ReaderStream::new(reader)),
+    )
+        .into_response())
 }
 
 // homepage: Handler to serve homepage
@@ -195,57 +451,135 @@ async fn homepage<DB: db::Database>(
     State(sp): State<Arc<pages::StaticPages>>,
     State(pages): State<Arc<RwLock<pages::Pages>>>,
     State(DbState { db }): State<DbState<DB>>,
-) -> (StatusCode, Html<String>) {
+    req_headers: HeaderMap,
+) -> Response {
     let page_ref = pages::PageRef::Homepage;
 
-    {
-        let pg = unwrap_or_return!(pages.read(), {
-            internal_error(&sp, "Could not gain read access to Pages")
-        });
-
-        if let Some(page) = pg
-            .get_page(db.as_ref(), &page_ref)
-            .expect("Could not access homepage")
-        {
-            return ok_page(page);
-        }
+    match serve_page(config, sp.clone(), pages, db, page_ref, req_headers).await {
+        Ok(response) => response,
+        Err(_) => internal_error(&sp, "Could not access homepage").into_response(),
     }
+}
+
+// Serve one of the pages belonging to a board (archive, create)
 
-    render_page(config, sp, pages, db, &page_ref)
+#[allow(clippy::too_many_arguments)]
+async fn board_page<DB: db::Database>(
+    config: Arc<Config>,
+    sp: Arc<pages::StaticPages>,
+    pages: Arc<RwLock<pages::Pages>>,
+    actions: Arc<actions::Actions>,
+    db: Arc<DB>,
+    req_headers: HeaderMap,
+    board: &str,
+    page_ref: fn(u64) -> pages::PageRef,
+) -> Response {
+    let board_id = unwrap_or_return!(actions.board_url_to_id(board), {
+        not_found(&sp, "No such board").into_response()
+    });
+
+    match serve_page(
+        config,
+        sp.clone(),
+        pages,
+        db,
+        page_ref(board_id),
+        req_headers,
+    )
+    .await
+    {
+        Ok(response) => response,
+        Err(err) => web_error(&sp, err.code, &err.msg).into_response(),
+    }
 }
 
 // catalog: Handler to serve catalog pages
+// Each sort is served from the page cache, but filtered catalogs are rendered per request
+
+#[derive(serde::Deserialize)]
+struct CatalogQuery {
+    sort: Option<String>,
+    q:    Option<String>,
+}
 
+#[allow(clippy::too_many_arguments)]
 async fn catalog<DB: db::Database>(
     State(config): State<Arc<Config>>,
     State(sp): State<Arc<pages::StaticPages>>,
     State(pages): State<Arc<RwLock<pages::Pages>>>,
     State(actions): State<Arc<actions::Actions>>,
     State(DbState { db }): State<DbState<DB>>,
+    extract::ConnectInfo(addr): extract::ConnectInfo<SocketAddr>,
+    req_headers: HeaderMap,
     extract::Path(board): extract::Path<String>,
-) -> (StatusCode, Html<String>) {
-    let page_ref = {
-        let board_id = unwrap_or_return!(actions.board_url_to_id(&board), {
-            not_found(&sp, "No such board")
-        });
+    extract::Query(query): extract::Query<CatalogQuery>,
+) -> Response {
+    let board_id = unwrap_or_return!(actions.board_url_to_id(&board), {
+        not_found(&sp, "No such board").into_response()
+    });
 
-        let pg = unwrap_or_return!(pages.read(), {
-            internal_error(&sp, "Could not gain read access to Pages")
-        });
+    // Unknown sorts fall back to bump order rather than failing
+    let sort = query
+        .sort
+        .as_deref()
+        .and_then(db::CatalogSort::from_name)
+        .unwrap_or(db::CatalogSort::Bump);
+
+    let filter = query
+        .q
+        .map(|q| {
+            q.trim()
+                .chars()
+                .take(CATALOG_FILTER_CHARS)
+                .collect::<String>()
+        })
+        .filter(|q| !q.is_empty());
+
+    let result = match filter {
+        None => {
+            let page_ref = pages::PageRef::Catalog(board_id, sort);
+            serve_page(config, sp.clone(), pages, db, page_ref, req_headers).await
+        },
+        Some(filter) => {
+            let ip = determine_poster_ip(addr, &req_headers);
+            match actions.start_catalog_filter(ip) {
+                Ok(true) => (),
+                Ok(false) => {
+                    return web_error(
+                        &sp,
+                        429,
+                        "Please wait a few seconds before searching the catalog again",
+                    )
+                    .into_response();
+                },
+                Err(_) => {
+                    return internal_error(&sp, "Could not search the catalog").into_response();
+                },
+            }
 
-        let page_ref = pages::PageRef::Catalog(board_id);
-        match pg
-            .get_page(db.as_ref(), &page_ref)
-            .expect("Could not access catalog for extant board")
-        {
-            Some(page) => {
-                return ok_page(page);
-            },
-            None => page_ref,
-        }
+            let sp = sp.clone();
+            util::blocking(move || {
+                let page = {
+                    let pg = unwrap_or_return!(pages.read(), {
+                        Ok(internal_error(&sp, "Could not gain read access to Pages")
+                            .into_response())
+                    });
+                    pg.render_catalog(config.as_ref(), db.as_ref(), board_id, sort, Some(&filter))?
+                };
+
+                // The page is dropped after this response, so only the negotiated encoding
+                // is ever compressed, and without holding up renders of cached pages
+                Ok(ok_page(&page, &req_headers))
+            })
+            .await
+        },
     };
 
-    render_page(config, sp, pages, db, &page_ref)
+    match result {
+        Ok(response) => response,
+        Err(err) if err.code == 404 => not_found(&sp, "No such board").into_response(),
+        Err(_) => internal_error(&sp, "Could not render the catalog").into_response(),
+    }
 }
 
 // archive: Handler to serve archive pages
@@ -256,30 +590,20 @@ async fn archive<DB: db::Database>(
     State(pages): State<Arc<RwLock<pages::Pages>>>,
     State(actions): State<Arc<actions::Actions>>,
     State(DbState { db }): State<DbState<DB>>,
+    req_headers: HeaderMap,
     extract::Path(board): extract::Path<String>,
-) -> (StatusCode, Html<String>) {
-    let page_ref = {
-        let board_id = unwrap_or_return!(actions.board_url_to_id(&board), {
-            not_found(&sp, "No such board")
-        });
-
-        let pg = unwrap_or_return!(pages.read(), {
-            internal_error(&sp, "Could not gain read access to Pages")
-        });
-
-        let page_ref = pages::PageRef::Archive(board_id);
-        match pg
-            .get_page(db.as_ref(), &page_ref)
-            .expect("Could not access archive for extant board")
-        {
-            Some(page) => {
-                return ok_page(page);
-            },
-            None => page_ref,
-        }
-    };
-
-    render_page(config, sp, pages, db, &page_ref)
+) -> Response {
+    board_page(
+        config,
+        sp,
+        pages,
+        actions,
+        db,
+        req_headers,
+        &board,
+        pages::PageRef::Archive,
+    )
+    .await
 }
 
 // create: Handler to serve original post creation page
@@ -290,30 +614,20 @@ async fn create<DB: db::Database>(
     State(pages): State<Arc<RwLock<pages::Pages>>>,
     State(actions): State<Arc<actions::Actions>>,
     State(DbState { db }): State<DbState<DB>>,
+    req_headers: HeaderMap,
     extract::Path(board): extract::Path<String>,
-) -> (StatusCode, Html<String>) {
-    let page_ref = {
-        let board_id = unwrap_or_return!(actions.board_url_to_id(&board), {
-            not_found(&sp, "No such board")
-        });
-
-        let pg = unwrap_or_return!(pages.read(), {
-            internal_error(&sp, "Could not gain read access to Pages")
-        });
-
-        let page_ref = pages::PageRef::Create(board_id);
-        match pg
-            .get_page(db.as_ref(), &page_ref)
-            .expect("Could not access thread creation page for extant board")
-        {
-            Some(page) => {
-                return ok_page(page);
-            },
-            None => page_ref,
-        }
-    };
-
-    render_page(config, sp, pages, db, &page_ref)
+) -> Response {
+    board_page(
+        config,
+        sp,
+        pages,
+        actions,
+        db,
+        req_headers,
+        &board,
+        pages::PageRef::Create,
+    )
+    .await
 }
 
 // Parse a multipart text field
@@ -371,6 +685,32 @@ async fn multipart_file_field<'f>(
     }
 }
 
+// Pair each file with the alt text field in the same position, then drop empty file
+// fields, which forms send for each file input left unused
+// The spoiler checkbox applies to every file in the form
+fn pair_uploads(
+    files: Vec<(Option<String>, Option<Bytes>)>,
+    alt_texts: Vec<Option<String>>,
+    spoiler: bool,
+) -> Vec<actions::Upload> {
+    let mut alt_texts = alt_texts.into_iter();
+    files
+        .into_iter()
+        .filter_map(|(file_name, file)| {
+            let alt_text = alt_texts
+                .next()
+                .flatten()
+                .filter(|alt| !alt.trim().is_empty());
+            Some(actions::Upload {
+                file: file?,
+                file_name,
+                spoiler,
+                alt_text,
+            })
+        })
+        .collect()
+}
+
 fn parse_raw_name(raw_name: Option<String>) -> (Option<String>, Option<String>) {
     match raw_name {
         Some(name_str) => {
@@ -391,6 +731,7 @@ fn parse_raw_name(raw_name: Option<String>) -> (Option<String>, Option<String>)
 // be the one we want to store as the poster IP.
 // However, if we are using a reverse proxy, it will be useless
 // (most likely localhost), so we have to use the Forwarded header instead.
+#[allow(clippy::collapsible_if)]
 fn determine_poster_ip(conn_addr: SocketAddr, headers: &HeaderMap) -> String {
     if let Some(hdr) = headers.get(http::header::FORWARDED) {
         if let Ok(hstr) = hdr.to_str() {
@@ -420,6 +761,7 @@ type Submission = extract::Multipart;
 
 // create_submit: Handler for original post creation forms
 
+#[allow(clippy::too_many_arguments, clippy::unnecessary_to_owned)]
 async fn create_submit<DB: db::Database, FR: fr::FileRack>(
     State(config): State<Arc<Config>>,
     State(sp): State<Arc<pages::StaticPages>>,
@@ -438,8 +780,9 @@ async fn create_submit<DB: db::Database, FR: fr::FileRack>(
     let mut raw_name = None;
     let mut title = None;
     let mut body = None;
-    let mut file_name = None;
-    let mut file = None;
+    let mut files = vec![];
+    let mut spoiler = false;
+    let mut alt_texts = vec![];
 
     while let Ok(Some(field)) = multipart.next_field().await {
         match field.name() {
@@ -452,45 +795,57 @@ async fn create_submit<DB: db::Database, FR: fr::FileRack>(
             Some("body") => {
                 body = multipart_text_field(&sp, field, 16_384).await?;
             },
+            // Posts may have several files, each in its own field
+            // Empty fields are kept for now, so that alt text fields line up with them
             Some("file") => {
-                (file_name, file) = multipart_file_field(&sp, field, FILE_MAX_SIZE).await?;
+                files.push(multipart_file_field(&sp, field, FILE_MAX_SIZE).await?);
+            },
+            Some("spoiler") => {
+                spoiler = multipart_text_field(&sp, field, 8).await?.is_some();
+            },
+            // Alt text fields describe the file fields in the same positions
+            Some("alt") => {
+                alt_texts.push(multipart_text_field(&sp, field, 1024).await?);
             },
             _ => {},
         }
     }
 
-    let file = match file {
-        Some(f) => f,
-        None => return Err(bad_request(&sp, "You must upload a file")),
-    };
-
-    let file_id = unwrap_or_return!(actions.upload_file(fr.as_ref(), file), {
-        Err(bad_request(
-            &sp,
-            "File upload failed - filetype may not be supported",
-        ))
-    });
+    let uploads = pair_uploads(files, alt_texts, spoiler);
+    if uploads.is_empty() {
+        return Err(bad_request(&sp, "You must upload a file"));
+    }
 
     let (name, trip) = parse_raw_name(raw_name);
 
     let poster_ip = determine_poster_ip(addr, &headers);
 
-    let submission_result = actions.submit_original(
-        db.as_ref(),
-        &config,
-        board_id,
-        poster_ip,
-        body.unwrap_or_else(|| String::from("")),
-        name,
-        trip,
-        file_id,
-        file_name.unwrap_or_else(|| String::from("")),
-        title,
-    );
+    let submission_result = {
+        let (config, actions, db, fr) = (config.clone(), actions.clone(), db.clone(), fr.clone());
+        util::blocking(move || {
+            actions.submit_original(
+                db.as_ref(),
+                fr.as_ref(),
+                &config,
+                board_id,
+                poster_ip,
+                body.unwrap_or_else(|| String::from("")),
+                name,
+                trip,
+                uploads,
+                title,
+            )
+        })
+        .await
+    };
 
     match submission_result {
         Ok(actions::SubmissionResult::Success(_)) => {
-            match actions.enforce_archive(db.as_ref(), fr.as_ref(), board_id) {
+            match util::blocking(move || {
+                actions.enforce_archive(db.as_ref(), fr.as_ref(), board_id)
+            })
+            .await
+            {
                 Ok(_) => Ok(response::Redirect::to(&format!("/{}/catalog", board))),
                 Err(err) => {
                     println!("{:?}", err);
@@ -509,12 +864,26 @@ async fn create_submit<DB: db::Database, FR: fr::FileRack>(
             Err(forbidden(&sp, "You must write something in your post"))
         },
         Ok(actions::SubmissionResult::BadContent) => Err(forbidden(&sp, "Post content disallowed")),
+        Ok(actions::SubmissionResult::BadFile) => Err(bad_request(
+            &sp,
+            "File upload failed - filetype may not be supported",
+        )),
+        Ok(actions::SubmissionResult::BannedFile) => {
+            Err(forbidden(&sp, "This file has been banned"))
+        },
+        Ok(actions::SubmissionResult::ImageTooLarge) => {
+            Err(unprocessable(&sp, &image_too_large_message(&config)))
+        },
+        Ok(actions::SubmissionResult::TooManyFiles) => {
+            Err(bad_request(&sp, "Too many files attached to this post"))
+        },
         _ => Err(internal_error(&sp, "Failed to submit post")),
     }
 }
 
 // create_reply: Handler for reply post creation forms
 
+#[allow(clippy::too_many_arguments)]
 async fn create_reply<DB: db::Database, FR: fr::FileRack>(
     State(config): State<Arc<Config>>,
     State(sp): State<Arc<pages::StaticPages>>,
@@ -532,8 +901,9 @@ async fn create_reply<DB: db::Database, FR: fr::FileRack>(
 
     let mut raw_name = None;
     let mut body = None;
-    let mut file_name = None;
-    let mut file = None;
+    let mut files = vec![];
+    let mut spoiler = false;
+    let mut alt_texts = vec![];
 
     while let Ok(Some(field)) = multipart.next_field().await {
         match field.name() {
@@ -543,40 +913,46 @@ async fn create_reply<DB: db::Database, FR: fr::FileRack>(
             Some("body") => {
                 body = multipart_text_field(&sp, field, 16_384).await?;
             },
+            // Posts may have several files, each in its own field
+            // Empty fields are kept for now, so that alt text fields line up with them
             Some("file") => {
-                (file_name, file) = multipart_file_field(&sp, field, FILE_MAX_SIZE).await?;
+                files.push(multipart_file_field(&sp, field, FILE_MAX_SIZE).await?);
+            },
+            Some("spoiler") => {
+                spoiler = multipart_text_field(&sp, field, 8).await?.is_some();
+            },
+            // Alt text fields describe the file fields in the same positions
+            Some("alt") => {
+                alt_texts.push(multipart_text_field(&sp, field, 1024).await?);
             },
             _ => {},
         }
     }
 
-    let mut file_id = None;
-
-    if let Some(file) = file {
-        file_id = Some(unwrap_or_return!(actions.upload_file(fr.as_ref(), file), {
-            Err(bad_request(
-                &sp,
-                "File upload failed - filetype may not be supported",
-            ))
-        }));
-    }
-
     let (name, trip) = parse_raw_name(raw_name);
 
+    let uploads = pair_uploads(files, alt_texts, spoiler);
+
     let poster_ip = determine_poster_ip(addr, &headers);
 
-    let submission_result = actions.submit_reply(
-        db.as_ref(),
-        &config,
-        board_id,
-        poster_ip,
-        body.unwrap_or_else(|| String::from("")),
-        name,
-        trip,
-        file_id,
-        file_name,
-        orig_num,
-    );
+    let submission_result = {
+        let config = config.clone();
+        util::blocking(move || {
+            actions.submit_reply(
+                db.as_ref(),
+                fr.as_ref(),
+                &config,
+                board_id,
+                poster_ip,
+                body.unwrap_or_else(|| String::from("")),
+                name,
+                trip,
+                uploads,
+                orig_num,
+            )
+        })
+        .await
+    };
 
     match submission_result {
         Ok(actions::SubmissionResult::Success(_)) => Ok(response::Redirect::to(&format!(
@@ -592,6 +968,19 @@ async fn create_reply<DB: db::Database, FR: fr::FileRack>(
             Err(forbidden(&sp, "You may not create empty posts"))
         },
         Ok(actions::SubmissionResult::BadContent) => Err(forbidden(&sp, "Post content disallowed")),
+        Ok(actions::SubmissionResult::BadFile) => Err(bad_request(
+            &sp,
+            "File upload failed - filetype may not be supported",
+        )),
+        Ok(actions::SubmissionResult::BannedFile) => {
+            Err(forbidden(&sp, "This file has been banned"))
+        },
+        Ok(actions::SubmissionResult::ImageTooLarge) => {
+            Err(unprocessable(&sp, &image_too_large_message(&config)))
+        },
+        Ok(actions::SubmissionResult::TooManyFiles) => {
+            Err(bad_request(&sp, "Too many files attached to this post"))
+        },
         Ok(actions::SubmissionResult::NotAcceptingReplies) => {
             Err(forbidden(&sp, "You cannot reply to this thread"))
         },
@@ -628,47 +1017,229 @@ async fn console<DB: db::Database, FR: fr::FileRack>(
         },
     }
 
-    (StatusCode::OK, console::execute(actions, db, fr, &body))
+    match util::blocking(move || Ok(console::execute(config, actions, db, fr, &body))).await {
+        Ok(output) => (StatusCode::OK, output),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.msg),
+    }
 }
 
 // Headers for filerack files (necessary to achieve display-in-browser)
 
-fn file_headers(file: &Bytes) -> impl IntoResponseParts + use<> {
+fn file_headers(
+    len: u64,
+    cache_control: &str,
+    content_type: &str,
+) -> impl IntoResponseParts + use<> {
     [
-        (
-            "Cache-Control",
-            "public, max-age=604800, immutable".to_string(),
-        ),
-        ("Content-Length", file.len().to_string()),
-        ("Content-Type", "image".to_string()),
+        ("Cache-Control", cache_control.to_string()),
+        ("Content-Length", len.to_string()),
+        ("Content-Type", content_type.to_string()),
         ("Content-Disposition", "inline".to_string()),
     ]
 }
 
+// A response with no body, which has the reverse proxy serve the file itself
+// The proxy handles ranges and validators for the files it serves
+fn proxy_response(proxy_file: fr::ProxyFile, cache_control: &str) -> Response {
+    (
+        StatusCode::OK,
+        [
+            (proxy_file.header, proxy_file.location),
+            ("Cache-Control", cache_control.to_string()),
+            ("Content-Type", proxy_file.content_type.to_string()),
+            ("Content-Disposition", "inline".to_string()),
+        ],
+    )
+        .into_response()
+}
+
 // files: Handler for full-size filerack files
+// Files are streamed from the rack, and single byte ranges are supported so that
+// large downloads can be resumed and media can be seeked. Racks which serve files
+// themselves have clients redirected to them instead, and racks on disk can have
+// the reverse proxy serve their files
 
 async fn files<FR: fr::FileRack>(
     State(sp): State<Arc<pages::StaticPages>>,
     State(FrState { fr }): State<FrState<FR>>,
     extract::Path(file_id): extract::Path<String>,
-) -> Result<(StatusCode, impl IntoResponseParts, Bytes), ErrorResponse> {
-    let file = fr
-        .get_file(&file_id)
+    req_headers: HeaderMap,
+) -> Result<Response, ErrorResponse> {
+    let url = {
+        let (fr, file_id) = (fr.clone(), file_id.clone());
+        util::blocking(move || fr.file_url(&file_id))
+            .await
+            .map_err(|_| -> ErrorResponse { not_found(&sp, "No such file").into() })?
+    };
+    if let Some(url) = url {
+        return Ok(response::Redirect::temporary(&url).into_response());
+    }
+
+    let proxy_file = {
+        let (fr, file_id) = (fr.clone(), file_id.clone());
+        util::blocking(move || fr.offload_file(&file_id))
+            .await
+            .map_err(|_| -> ErrorResponse { not_found(&sp, "No such file").into() })?
+    };
+    if let Some(proxy_file) = proxy_file {
+        return Ok(proxy_response(proxy_file, headers::CACHE_FILE));
+    }
+
+    let stat = {
+        let (fr, file_id) = (fr.clone(), file_id.clone());
+        util::blocking(move || fr.stat_file(&file_id))
+            .await
+            .map_err(|_| -> ErrorResponse { not_found(&sp, "No such file").into() })?
+    };
+
+    // File IDs are never reused, so the ID itself is a strong validator
+    let etag = format!("\"{}\"", file_id);
+
+    if headers::is_not_modified(&req_headers, &etag, stat.modified) {
+        return Ok(not_modified(headers::CACHE_FILE, &etag, stat.modified));
+    }
+
+    let range = headers::requested_range(&req_headers, stat.len, &etag, stat.modified);
+
+    let (status, start, len) = match range {
+        headers::ByteRange::Full => (StatusCode::OK, 0, stat.len),
+        headers::ByteRange::Partial(start, end) => {
+            (StatusCode::PARTIAL_CONTENT, start, end - start + 1)
+        },
+        headers::ByteRange::Unsatisfiable => {
+            return Ok((
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                [(
+                    "Content-Range",
+                    range.content_range(stat.len).unwrap_or_default(),
+                )],
+            )
+                .into_response());
+        },
+    };
+
+    // Opening and seeking the file is blocking I/O, while the streaming itself is async
+    // Files are sent with the type of their contents, which only needs their first few bytes
+    let (mut head, reader) = util::blocking(move || {
+        Ok((
+            fr.read_file_range(&file_id, 0, media::SNIFF_LENGTH)?,
+            fr.read_file_range(&file_id, start, len)?,
+        ))
+    })
+    .await
+    .map_err(|_| -> ErrorResponse { not_found(&sp, "No such file").into() })?;
+
+    let mut sniff = vec![];
+    head.read_to_end(&mut sniff)
+        .await
         .map_err(|_| -> ErrorResponse { not_found(&sp, "No such file").into() })?;
-    Ok((StatusCode::OK, file_headers(&file), file))
+
+    let mut response = (
+        status,
+        file_headers(len, headers::CACHE_FILE, media::mime_type(&sniff)),
+        [
+            ("Accept-Ranges", "bytes".to_string()),
+            ("ETag", etag),
+            ("Last-Modified", headers::http_date(stat.modified)),
+        ],
+        body::Body::from_stream(ReaderStream::new(reader)),
+    )
+        .into_response();
+
+    if let Some(content_range) = range.content_range(stat.len)
+        && let Ok(val) = http::HeaderValue::from_str(&content_range)
+    {
+        response
+            .headers_mut()
+            .insert(http::header::CONTENT_RANGE, val);
+    }
+
+    Ok(response)
 }
 
 // thumbnails: Handler for thumbnail filerack files
+// Each file has a thumbnail of each kind, and the legacy route without a kind serves the
+// OP thumbnail
 
 async fn thumbnails<FR: fr::FileRack>(
     State(sp): State<Arc<pages::StaticPages>>,
-    State(FrState { fr }): State<FrState<FR>>,
+    State(fr): State<FrState<FR>>,
     extract::Path(file_id): extract::Path<String>,
-) -> Result<(StatusCode, impl IntoResponseParts, Bytes), ErrorResponse> {
-    let file = fr
-        .get_file_thumbnail(&file_id)
+    req_headers: HeaderMap,
+) -> Result<Response, ErrorResponse> {
+    thumbnail_response(sp, fr, file_id, media::ThumbKind::Op, req_headers).await
+}
+
+async fn kind_thumbnails<FR: fr::FileRack>(
+    State(sp): State<Arc<pages::StaticPages>>,
+    State(fr): State<FrState<FR>>,
+    extract::Path((kind, file_id)): extract::Path<(String, String)>,
+    req_headers: HeaderMap,
+) -> Result<Response, ErrorResponse> {
+    let kind = match media::ThumbKind::from_name(&kind) {
+        Some(kind) => kind,
+        None => return Err(not_found(&sp, "No such thumbnail").into()),
+    };
+    thumbnail_response(sp, fr, file_id, kind, req_headers).await
+}
+
+async fn thumbnail_response<FR: fr::FileRack>(
+    sp: Arc<pages::StaticPages>,
+    FrState { fr }: FrState<FR>,
+    file_id: String,
+    kind: media::ThumbKind,
+    req_headers: HeaderMap,
+) -> Result<Response, ErrorResponse> {
+    let url = {
+        let (fr, file_id) = (fr.clone(), file_id.clone());
+        util::blocking(move || fr.thumbnail_url(&file_id, kind))
+            .await
+            .map_err(|_| -> ErrorResponse { not_found(&sp, "No such thumbnail").into() })?
+    };
+    if let Some(url) = url {
+        return Ok(response::Redirect::temporary(&url).into_response());
+    }
+
+    let proxy_file = {
+        let (fr, file_id) = (fr.clone(), file_id.clone());
+        util::blocking(move || fr.offload_thumbnail(&file_id, kind))
+            .await
+            .map_err(|_| -> ErrorResponse { not_found(&sp, "No such thumbnail").into() })?
+    };
+    if let Some(proxy_file) = proxy_file {
+        return Ok(proxy_response(proxy_file, headers::CACHE_THUMBNAIL));
+    }
+
+    let file = util::blocking(move || fr.get_file_thumbnail(&file_id, kind))
+        .await
         .map_err(|_| -> ErrorResponse { not_found(&sp, "No such thumbnail").into() })?;
-    Ok((StatusCode::OK, file_headers(&file), file))
+
+    // Thumbnails change when they are regenerated, so they are tagged by their contents
+    // The rack does not track when that happened, so there is no Last-Modified to
+    // revalidate against, and If-Modified-Since is ignored
+    let etag = format!("\"{}\"", &sha256::digest(file.as_ref())[..16]);
+    if headers::etag_matches(&req_headers, &etag) {
+        return Ok((
+            StatusCode::NOT_MODIFIED,
+            [
+                ("Cache-Control", headers::CACHE_THUMBNAIL.to_string()),
+                ("ETag", etag),
+            ],
+        )
+            .into_response());
+    }
+
+    Ok((
+        StatusCode::OK,
+        file_headers(
+            file.len() as u64,
+            headers::CACHE_THUMBNAIL,
+            media::mime_type(&file),
+        ),
+        [("ETag", etag)],
+        file,
+    )
+        .into_response())
 }
 
 // not_found: Handler for 404 fallback
@@ -698,13 +1269,22 @@ pub async fn serve<DB: db::Database, FR: fr::FileRack>(
 
     let sp = pages::StaticPages {
         error_tmpl:   Template::from_file(config.templates_dir.join("error.html.tmpl").as_path())
-            .unwrap_or_else(|err| err.die()),
+            .unwrap_or_else(|err| err.die())
+            .with_assets(&config.assets),
         message_tmpl: Template::from_file(config.templates_dir.join("message.html.tmpl").as_path())
-            .unwrap_or_else(|err| err.die()),
+            .unwrap_or_else(|err| err.die())
+            .with_assets(&config.assets),
     };
 
     let state = PlainchantState::new(config, sp, pages, actions, database, file_rack);
 
+    tokio::spawn(backup::schedule(
+        state.config.clone(),
+        state.actions.clone(),
+        state.db.clone(),
+        state.fr.clone(),
+    ));
+
     let router = Router::new()
         .route("/", routing::get(homepage))
         .route(
@@ -714,11 +1294,19 @@ pub async fn serve<DB: db::Database, FR: fr::FileRack>(
             }),
         )
         .route("/{board}/thread/{post_num}", routing::get(thread))
+        .route(
+            "/{board}/thread/{post_num}/files.zip",
+            routing::get(thread_zip),
+        )
         .route("/{board}/catalog", routing::get(catalog))
         .route("/{board}/archive", routing::get(archive))
         .route("/{board}/create", routing::get(create))
         .route("/files/{file_id}", routing::get(files))
         .route("/thumbnails/{file_id}", routing::get(thumbnails))
+        .route(
+            "/thumbnails/{kind}/{file_id}",
+            routing::get(kind_thumbnails),
+        )
         .route("/{board}/submit", routing::post(create_submit))
         .route("/{board}/reply/{orig_num}", routing::post(create_reply))
         .route("/static/{*path}", routing::get(static_dir))
@@ -726,6 +1314,10 @@ pub async fn serve<DB: db::Database, FR: fr::FileRack>(
         .nest("/api", api::get_api_router())
         .layer(extract::DefaultBodyLimit::max(FORM_MAX_LENGTH))
         .fallback(route_not_found)
+        .layer(SetResponseHeaderLayer::if_not_present(
+            http::header::CACHE_CONTROL,
+            http::HeaderValue::from_static(headers::CACHE_NONE),
+        ))
         .with_state(state);
 
     let app = NormalizePathLayer::trim_trailing_slash().layer(router);
@@ -23,12 +23,38 @@ pub trait Post {
     fn body(&self) -> &str;
     fn set_feather(&mut self, feather: Feather);
     fn feather(&self) -> &Feather;
-    fn file_id(&self) -> Option<&str>;
-    fn file_name(&self) -> Option<&str>;
+    fn files(&self) -> &[PostFile];
+    fn files_mut(&mut self) -> &mut Vec<PostFile>;
     fn set_approval(&mut self, approval: Approval);
     fn approval(&self) -> &Approval;
 }
 
+// Properties of an uploaded file, recorded when it is stored
+// Posts whose files were stored before these were recorded have none
+#[derive(Debug, Clone)]
+pub struct FileInfo {
+    pub width:        u32,
+    pub height:       u32,
+    pub size:         u64,
+    pub format:       String,
+    // Only for videos and animated images
+    pub duration_ms:  Option<u64>,
+    pub thumb_width:  u32,
+    pub thumb_height: u32,
+}
+
+// One of a post's files, which are kept in the order they were uploaded
+#[derive(Debug, Clone)]
+pub struct PostFile {
+    pub file_id:   String,
+    pub file_name: Option<String>,
+    pub file_info: Option<FileInfo>,
+    // Hidden behind a placeholder until the viewer chooses to see it
+    pub spoiler:   bool,
+    // Describes the file to those who cannot see it
+    pub alt_text:  Option<String>,
+}
+
 #[derive(Debug)]
 pub struct Original {
     pub board_id:    u64,
@@ -38,8 +64,7 @@ pub struct Original {
     pub poster:      Option<String>,
     pub body:        String,
     pub feather:     Feather,
-    pub file_id:     Option<String>,
-    pub file_name:   Option<String>,
+    pub files:       Vec<PostFile>,
     pub approval:    Approval,
     pub title:       Option<String>,
     pub bump_time:   u64,
@@ -51,17 +76,16 @@ pub struct Original {
 
 #[derive(Debug)]
 pub struct Reply {
-    pub board_id:  u64,
-    pub post_num:  u64,
-    pub time:      u64,
-    pub ip:        String,
-    pub poster:    Option<String>,
-    pub body:      String,
-    pub feather:   Feather,
-    pub file_id:   Option<String>,
-    pub file_name: Option<String>,
-    pub approval:  Approval,
-    pub orig_num:  u64,
+    pub board_id: u64,
+    pub post_num: u64,
+    pub time:     u64,
+    pub ip:       String,
+    pub poster:   Option<String>,
+    pub body:     String,
+    pub feather:  Feather,
+    pub files:    Vec<PostFile>,
+    pub approval: Approval,
+    pub orig_num: u64,
 }
 
 #[allow(unused)]
@@ -154,12 +178,12 @@ macro_rules! impl_post {
                 self.feather = feather
             }
 
-            fn file_id(&self) -> Option<&str> {
-                self.file_id.as_deref()
+            fn files(&self) -> &[PostFile] {
+                &self.files
             }
 
-            fn file_name(&self) -> Option<&str> {
-                self.file_name.as_deref()
+            fn files_mut(&mut self) -> &mut Vec<PostFile> {
+                &mut self.files
             }
 
             fn approval(&self) -> &Approval {
@@ -190,6 +214,7 @@ pub struct Board {
     pub archive_cap: u16,
     pub bump_limit: u16,
     pub next_post_num: u64,
+    pub max_files: u16,
 }
 
 #[derive(Debug)]
@@ -214,6 +239,15 @@ pub struct Ban {
     pub time_expires: u64,
 }
 
+// A file which may not be uploaded again, identified by the SHA-256 of its contents
+// and, for images, a perceptual hash which also catches resized or re-encoded copies
+#[derive(Debug, Clone)]
+pub struct BannedFile {
+    pub file_hash:       String,
+    pub perceptual_hash: Option<u64>,
+    pub time_banned:     u64,
+}
+
 #[derive(Debug, Clone)]
 pub struct Domain {
     pub id:     u64,
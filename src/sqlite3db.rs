@@ -1,13 +1,20 @@
 use crate::db;
 use crate::site;
+use crate::sqlite3migrations;
 use crate::util;
 use crate::util::PlainchantErr;
 
 use r2d2::Pool;
 use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::OptionalExtension;
 
 use core::ops::Deref;
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+// Posts whose files are loaded by a single query, each taking two parameters
+const FILES_BATCH: usize = 400;
 
 impl From<rusqlite::Error> for PlainchantErr {
     fn from(err: rusqlite::Error) -> Self {
@@ -84,109 +91,37 @@ impl Sqlite3Database {
         let manager = SqliteConnectionManager::file(&path);
         let pool = r2d2::Pool::new(manager)?;
 
-        let conn = pool.get()?;
-
-        conn.execute(
-            r#"
-            CREATE TABLE IF NOT EXISTS Site (
-                Identity    INTEGER  PRIMARY KEY,
-                Name        TEXT     NOT NULL,
-                Description TEXT     NOT NULL,
-                Contact     TEXT             ,
-                Url         TEXT
-            );
-        "#,
-            (),
-        )?;
+        let mut conn = pool.get()?;
+        sqlite3migrations::migrate(&mut conn)?;
 
-        conn.execute(
-            r#"
-            INSERT OR IGNORE INTO Site VALUES (
-                1,
-                'Plainchant',
-                'A lightweight and libre imageboard.',
-                NULL,
-                NULL
-            );
-        "#,
-            (),
-        )?;
-
-        conn.execute(
-            r#"
-            CREATE TABLE IF NOT EXISTS Bans (
-                BanId       INTEGER  PRIMARY KEY,
-                Ip          TEXT     NOT NULL,
-                TimeExpires INTEGER  NOT NULL
-            );
-        "#,
-            (),
-        )?;
-
-        conn.execute(
-            r#"
-            CREATE TABLE IF NOT EXISTS DomainWhitelist (
-                DomainId       INTEGER  PRIMARY KEY,
-                Domain         TEXT     NOT NULL
-            );
-        "#,
-            (),
-        )?;
+        Ok(Sqlite3Database { path, pool })
+    }
 
-        conn.execute(
-            r#"
-            CREATE TABLE IF NOT EXISTS Boards (
-                BoardId     INTEGER  PRIMARY KEY,
-                Url         TEXT     NOT NULL,
-                Title       TEXT     NOT NULL,
-                PostCap     INTEGER  NOT NULL,
-                BumpLimit   INTEGER  NOT NULL,
-                NextPostNum INTEGER  NOT NULL,
-                ArchiveCap  INTEGER  NOT NULL
-            );
-        "#,
-            (),
-        )?;
+    pub fn check_integrity(&self) -> Result<(), PlainchantErr> {
+        let conn = self.pool.get()?;
+        let result: String = conn.pragma_query_value(None, "integrity_check", |row| row.get(0))?;
 
-        conn.execute(
-            r#"
-            CREATE TABLE IF NOT EXISTS Posts (
-                BoardId     INTEGER  NUT NULL,
-                PostNum     INTEGER  NOT NULL,
-                Time        INTEGER  NOT NULL,
-                Ip          TEXT     NOT NULL,
-                Poster      TEXT             ,
-                Body        TEXT     NOT NULL,
-                FeatherType INTEGER          ,
-                FeatherText TEXT             ,
-                FileId      TEXT             ,
-                FileName    TEXT             ,
-                OrigNum     INTEGER          ,
-                Approval    INTEGER  NOT NULL,
-                PRIMARY KEY(BoardId, PostNum)
-            );
-        "#,
-            (),
-        )?;
+        if result == "ok" {
+            Ok(())
+        } else {
+            Err(PlainchantErr {
+                origin: util::ErrOrigin::Database,
+                code:   500,
+                msg:    format!("Integrity check failed: {}", result),
+            })
+        }
+    }
 
-        conn.execute(
-            r#"
-            CREATE TABLE IF NOT EXISTS Originals (
-                BoardId     INTEGER  NUT NULL,
-                PostNum     INTEGER  NOT NULL,
-                Title       TEXT             ,
-                BumpTime    INTEGER  NOT NULL,
-                Replies     INTEGER  NOT NULL,
-                ImgReplies  INTEGER  NOT NULL,
-                Pinned      INTEGER  NOT NULL,
-                Archived    INTEGER  NOT NULL,
-                PRIMARY KEY(BoardId, PostNum)
-            );
-        "#,
-            (),
+    // Report the migrations that from_path would apply, without applying them
+    // The database is opened read-only, so a wrong path is not created as an empty database
+    pub fn pending_migrations(
+        path: PathBuf,
+    ) -> Result<Vec<&'static sqlite3migrations::Migration>, PlainchantErr> {
+        let conn = rusqlite::Connection::open_with_flags(
+            path,
+            rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY,
         )?;
-
-        Ok(Sqlite3Database { path, pool })
+        sqlite3migrations::pending(&conn)
     }
 }
 
@@ -198,6 +133,15 @@ fn row_to_ban<'stmt>(row: &rusqlite::Row<'stmt>) -> rusqlite::Result<site::Ban>
     })
 }
 
+// SQLite integers are signed, so perceptual hashes are stored reinterpreted as i64
+fn row_to_banned_file<'stmt>(row: &rusqlite::Row<'stmt>) -> rusqlite::Result<site::BannedFile> {
+    Ok(site::BannedFile {
+        file_hash:       row.get(0)?,
+        perceptual_hash: row.get::<_, Option<i64>>(1)?.map(|hash| hash as u64),
+        time_banned:     row.get(2)?,
+    })
+}
+
 fn row_to_board<'stmt>(row: &rusqlite::Row<'stmt>) -> rusqlite::Result<site::Board> {
     Ok(site::Board {
         id: row.get(0)?,
@@ -207,6 +151,7 @@ fn row_to_board<'stmt>(row: &rusqlite::Row<'stmt>) -> rusqlite::Result<site::Boa
         bump_limit: row.get(4)?,
         next_post_num: row.get(5)?,
         archive_cap: row.get(6)?,
+        max_files: row.get(7)?,
     })
 }
 
@@ -217,9 +162,35 @@ fn row_to_domain<'stmt>(row: &rusqlite::Row<'stmt>) -> rusqlite::Result<site::Do
     })
 }
 
+fn row_to_file_info<'stmt>(row: &rusqlite::Row<'stmt>) -> rusqlite::Result<Option<site::FileInfo>> {
+    let width: Option<u32> = row.get("FileWidth")?;
+    match width {
+        Some(width) => Ok(Some(site::FileInfo {
+            width,
+            height: row.get("FileHeight")?,
+            size: row.get("FileSize")?,
+            format: row.get("FileFormat")?,
+            duration_ms: row.get("FileDuration")?,
+            thumb_width: row.get("ThumbWidth")?,
+            thumb_height: row.get("ThumbHeight")?,
+        })),
+        None => Ok(None),
+    }
+}
+
+fn row_to_post_file<'stmt>(row: &rusqlite::Row<'stmt>) -> rusqlite::Result<site::PostFile> {
+    Ok(site::PostFile {
+        file_id:   row.get("FileId")?,
+        file_name: row.get("FileName")?,
+        file_info: row_to_file_info(row)?,
+        spoiler:   row.get("Spoiler")?,
+        alt_text:  row.get("AltText")?,
+    })
+}
+
 fn row_to_reply<'stmt>(row: &rusqlite::Row<'stmt>) -> rusqlite::Result<site::Reply> {
     let feather = decode_feather(row.get::<usize, Option<u16>>(6)?, row.get(7)?);
-    let approval = decode_approval(row.get::<usize, Option<u16>>(10)?);
+    let approval = decode_approval(row.get::<usize, Option<u16>>(8)?);
 
     Ok(site::Reply {
         board_id: row.get(0)?,
@@ -229,16 +200,15 @@ fn row_to_reply<'stmt>(row: &rusqlite::Row<'stmt>) -> rusqlite::Result<site::Rep
         poster: row.get(4)?,
         body: row.get(5)?,
         feather,
-        file_id: row.get(8)?,
-        file_name: row.get(9)?,
+        files: vec![],
         approval,
-        orig_num: row.get::<usize, Option<u64>>(11)?.unwrap_or(0),
+        orig_num: row.get::<usize, Option<u64>>(9)?.unwrap_or(0),
     })
 }
 
 fn row_to_original<'stmt>(row: &rusqlite::Row<'stmt>) -> rusqlite::Result<site::Original> {
     let feather = decode_feather(row.get::<usize, Option<u16>>(6)?, row.get(7)?);
-    let approval = decode_approval(row.get::<usize, Option<u16>>(10)?);
+    let approval = decode_approval(row.get::<usize, Option<u16>>(8)?);
 
     Ok(site::Original {
         board_id: row.get(0)?,
@@ -248,22 +218,22 @@ fn row_to_original<'stmt>(row: &rusqlite::Row<'stmt>) -> rusqlite::Result<site::
         poster: row.get(4)?,
         body: row.get(5)?,
         feather,
-        file_id: row.get(8)?,
-        file_name: row.get(9)?,
+        files: vec![],
         approval,
-        title: row.get(12)?,
-        bump_time: row.get(13)?,
-        replies: row.get(14)?,
-        img_replies: row.get(15)?,
-        pinned: row.get(16)?,
-        archived: row.get(17)?,
+        title: row.get(10)?,
+        bump_time: row.get(11)?,
+        replies: row.get(12)?,
+        img_replies: row.get(13)?,
+        pinned: row.get(14)?,
+        archived: row.get(15)?,
     })
 }
 
+#[allow(clippy::redundant_closure)]
 fn row_to_differentiated_post<'stmt>(
     row: &rusqlite::Row<'stmt>,
 ) -> rusqlite::Result<site::DifferentiatedPost> {
-    let orig_board_id: Option<usize> = row.get(18)?;
+    let orig_board_id: Option<usize> = row.get(16)?;
     match orig_board_id {
         Some(_) => row_to_original(row).map(|orig| site::DifferentiatedPost::Original(orig)),
         None => row_to_reply(row).map(|reply| site::DifferentiatedPost::Reply(reply)),
@@ -276,7 +246,8 @@ fn query_board<T: Deref<Target = rusqlite::Connection>>(
 ) -> Result<site::Board, PlainchantErr> {
     let mut query = conn.prepare(
         r#"
-            SELECT BoardId, Url, Title, PostCap, BumpLimit, NextPostNum, ArchiveCap FROM Boards
+            SELECT BoardId, Url, Title, PostCap, BumpLimit, NextPostNum, ArchiveCap, MaxFiles
+            FROM Boards
                 WHERE BoardId=?1;
         "#,
     )?;
@@ -294,7 +265,7 @@ fn query_original<T: Deref<Target = rusqlite::Connection>>(
     let mut query = conn.prepare(
         r#"
         SELECT p.BoardId, p.PostNum, p.Time, p.Ip, p.Poster, p.Body,
-               p.FeatherType, p.FeatherText, p.FileId, p.FileName, p.Approval, p.OrigNum,
+               p.FeatherType, p.FeatherText, p.Approval, p.OrigNum,
                o.Title, o.BumpTime, o.Replies, o.ImgReplies,
                o.Pinned, o.Archived
 
@@ -305,9 +276,8 @@ fn query_original<T: Deref<Target = rusqlite::Connection>>(
     "#,
     )?;
 
-    query
-        .query_row((board_id, post_num), row_to_original)
-        .map_err(|e| e.into())
+    let orig = query.query_row((board_id, post_num), row_to_original)?;
+    with_files(conn, orig)
 }
 
 fn query_reply<T: Deref<Target = rusqlite::Connection>>(
@@ -318,7 +288,7 @@ fn query_reply<T: Deref<Target = rusqlite::Connection>>(
     let mut query = conn.prepare(
         r#"
         SELECT BoardId, PostNum, Time, Ip, Poster, Body,
-               FeatherType, FeatherText, FileId, FileName, Approval, OrigNum FROM Posts
+               FeatherType, FeatherText, Approval, OrigNum FROM Posts
             WHERE (BoardId, PostNum) = (?1, ?2);
     "#,
     )?;
@@ -332,7 +302,7 @@ fn query_reply<T: Deref<Target = rusqlite::Connection>>(
             msg:    format!("Post ({}, {}) is an Original", board_id, post_num),
         })
     } else {
-        Ok(post)
+        with_files(conn, post)
     }
 }
 
@@ -344,7 +314,7 @@ fn query_differentiated_post<T: Deref<Target = rusqlite::Connection>>(
     let mut query = conn.prepare(
         r#"
         SELECT p.BoardId, p.PostNum, p.Time, p.Ip, p.Poster, p.Body,
-               p.FeatherType, p.FeatherText, p.FileId, p.FileName, p.Approval, p.OrigNum,
+               p.FeatherType, p.FeatherText, p.Approval, p.OrigNum,
                o.Title, o.BumpTime, o.Replies, o.ImgReplies,
                o.Pinned, o.Archived,
 
@@ -357,9 +327,14 @@ fn query_differentiated_post<T: Deref<Target = rusqlite::Connection>>(
     "#,
     )?;
 
-    query
-        .query_row((board_id, post_num), row_to_differentiated_post)
-        .map_err(|e| e.into())
+    match query.query_row((board_id, post_num), row_to_differentiated_post)? {
+        site::DifferentiatedPost::Original(orig) => {
+            with_files(conn, orig).map(site::DifferentiatedPost::Original)
+        },
+        site::DifferentiatedPost::Reply(reply) => {
+            with_files(conn, reply).map(site::DifferentiatedPost::Reply)
+        },
+    }
 }
 
 fn increment_next_post_num<T: Deref<Target = rusqlite::Connection>>(
@@ -378,6 +353,186 @@ fn increment_next_post_num<T: Deref<Target = rusqlite::Connection>>(
     Ok(())
 }
 
+// Files are shared between posts with identical uploads, so they are reference counted
+
+fn acquire_file<T: Deref<Target = rusqlite::Connection>>(
+    conn: &T,
+    file_id: &str,
+) -> Result<(), PlainchantErr> {
+    conn.execute(
+        r#"
+        INSERT INTO Files VALUES (?1, 1)
+            ON CONFLICT(FileId) DO UPDATE SET RefCount = RefCount + 1;
+        "#,
+        (file_id,),
+    )?;
+
+    Ok(())
+}
+
+// Drop one reference to each file, returning those which are no longer referenced at all
+fn release_files<T: Deref<Target = rusqlite::Connection>>(
+    conn: &T,
+    file_ids: Vec<String>,
+) -> Result<Vec<String>, PlainchantErr> {
+    let mut released = vec![];
+
+    for file_id in file_ids {
+        conn.execute(
+            r#"
+            UPDATE Files SET RefCount = RefCount - 1 WHERE FileId = ?1;
+            "#,
+            (&file_id,),
+        )?;
+
+        let ref_count: Option<i64> = conn
+            .query_row(
+                r#"
+                SELECT RefCount FROM Files WHERE FileId = ?1;
+                "#,
+                (&file_id,),
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        if ref_count.unwrap_or(0) <= 0 {
+            conn.execute(
+                r#"
+                DELETE FROM Files WHERE FileId = ?1;
+                "#,
+                (&file_id,),
+            )?;
+            released.push(file_id);
+        }
+    }
+
+    Ok(released)
+}
+
+// A post's files live in their own table, in the order they were uploaded
+fn query_files<T: Deref<Target = rusqlite::Connection>>(
+    conn: &T,
+    board_id: u64,
+    post_num: u64,
+) -> Result<Vec<site::PostFile>, PlainchantErr> {
+    let mut query = conn.prepare_cached(
+        r#"
+        SELECT FileId, FileName, FileWidth, FileHeight, FileSize, FileFormat,
+               ThumbWidth, ThumbHeight, FileDuration, Spoiler, AltText FROM PostFiles
+            WHERE (BoardId, PostNum) = (?1, ?2)
+            ORDER BY Position;
+    "#,
+    )?;
+
+    let files = query
+        .query_map((board_id, post_num), row_to_post_file)?
+        .collect::<Result<Vec<site::PostFile>, _>>()?;
+
+    Ok(files)
+}
+
+fn with_files<T: Deref<Target = rusqlite::Connection>, P: site::Post>(
+    conn: &T,
+    mut post: P,
+) -> Result<P, PlainchantErr> {
+    *post.files_mut() = query_files(conn, post.board_id(), post.post_num())?;
+    Ok(post)
+}
+
+// Fill in the files of many posts at once, rather than querying for each post
+// Posts are looked up in batches, to stay within SQLite's limit on parameters
+fn with_all_files<T: Deref<Target = rusqlite::Connection>, P: site::Post>(
+    conn: &T,
+    mut posts: Vec<P>,
+) -> Result<Vec<P>, PlainchantErr> {
+    let mut files: HashMap<(u64, u64), Vec<site::PostFile>> = HashMap::new();
+
+    for batch in posts.chunks(FILES_BATCH) {
+        let mut query = conn.prepare(&format!(
+            r#"
+            SELECT BoardId, PostNum, FileId, FileName, FileWidth, FileHeight, FileSize,
+                   FileFormat, ThumbWidth, ThumbHeight, FileDuration, Spoiler, AltText
+                FROM PostFiles
+                WHERE (BoardId, PostNum) IN (VALUES {})
+                ORDER BY Position;
+        "#,
+            vec!["(?, ?)"; batch.len()].join(", ")
+        ))?;
+
+        let keys = batch
+            .iter()
+            .flat_map(|post| [post.board_id(), post.post_num()]);
+        let rows = query.query_map(rusqlite::params_from_iter(keys), |row| {
+            Ok(((row.get(0)?, row.get(1)?), row_to_post_file(row)?))
+        })?;
+
+        for row in rows {
+            let (key, file) = row?;
+            files.entry(key).or_default().push(file);
+        }
+    }
+
+    for post in &mut posts {
+        if let Some(post_files) = files.remove(&(post.board_id(), post.post_num())) {
+            *post.files_mut() = post_files;
+        }
+    }
+
+    Ok(posts)
+}
+
+// Replace all of a post's files, returning those which are no longer referenced at all
+// New references are taken before old ones are dropped, so files kept by the post survive
+fn write_files<T: Deref<Target = rusqlite::Connection>>(
+    conn: &T,
+    board_id: u64,
+    post_num: u64,
+    files: &[site::PostFile],
+) -> Result<Vec<String>, PlainchantErr> {
+    let old_file_ids = query_files(conn, board_id, post_num)?
+        .into_iter()
+        .map(|file| file.file_id)
+        .collect();
+
+    conn.execute(
+        r#"
+        DELETE FROM PostFiles WHERE (BoardId, PostNum) = (?1, ?2);
+        "#,
+        (board_id, post_num),
+    )?;
+
+    for (position, file) in files.iter().enumerate() {
+        let info = file.file_info.as_ref();
+        conn.execute(
+            r#"
+            INSERT INTO PostFiles
+            (BoardId, PostNum, Position, FileId, FileName, FileWidth, FileHeight, FileSize,
+             FileFormat, ThumbWidth, ThumbHeight, FileDuration, Spoiler, AltText)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14);
+            "#,
+            (
+                board_id,
+                post_num,
+                position,
+                &file.file_id,
+                &file.file_name,
+                info.map(|info| info.width),
+                info.map(|info| info.height),
+                info.map(|info| info.size),
+                info.map(|info| &info.format),
+                info.map(|info| info.thumb_width),
+                info.map(|info| info.thumb_height),
+                info.and_then(|info| info.duration_ms),
+                file.spoiler,
+                &file.alt_text,
+            ),
+        )?;
+        acquire_file(conn, &file.file_id)?;
+    }
+
+    release_files(conn, old_file_ids)
+}
+
 impl db::Database for Sqlite3Database {
     fn get_site(&self) -> Result<site::Site, PlainchantErr> {
         let conn = self.pool.get()?;
@@ -422,7 +577,8 @@ impl db::Database for Sqlite3Database {
         let conn = self.pool.get()?;
         let mut query = conn.prepare(
             r#"
-            SELECT BoardId, Url, Title, PostCap, BumpLimit, NextPostNum, ArchiveCap FROM Boards;
+            SELECT BoardId, Url, Title, PostCap, BumpLimit, NextPostNum, ArchiveCap, MaxFiles
+            FROM Boards;
         "#,
         )?;
 
@@ -464,7 +620,7 @@ impl db::Database for Sqlite3Database {
         let mut query = conn.prepare(
             r#"
             SELECT p.BoardId, p.PostNum, p.Time, p.Ip, p.Poster, p.Body,
-                   p.FeatherType, p.FeatherText, p.FileId, p.FileName, p.Approval, p.OrigNum,
+                   p.FeatherType, p.FeatherText, p.Approval, p.OrigNum,
                    o.Title, o.BumpTime, o.Replies, o.ImgReplies,
                    o.Pinned, o.Archived
 
@@ -472,18 +628,78 @@ impl db::Database for Sqlite3Database {
                         ON (p.BoardId, p.PostNum) = (o.BoardId, o.PostNum)
 
             WHERE p.BoardId = ?1
-            ORDER BY o.BumpTime DESC;
+            ORDER BY o.BumpTime DESC, o.PostNum DESC;
         "#,
         )?;
 
         let orig_iter = query.query_map((board_id,), row_to_original)?;
 
-        let mut originals = vec![];
+        let originals = orig_iter.collect::<Result<Vec<site::Original>, _>>()?;
+        let originals = with_all_files(&conn, originals)?;
+
+        Ok(site::Catalog {
+            board_id,
+            time: util::timestamp(),
+            originals,
+        })
+    }
+
+    fn get_catalog_preview(
+        &self,
+        board_id: u64,
+        archived: bool,
+        sort: db::CatalogSort,
+        filter: Option<&str>,
+    ) -> Result<site::Catalog, PlainchantErr> {
+        let conn = self.pool.get()?;
+
+        // Ties fall back to bump order, so that every sort is stable between renders
+        let order = match sort {
+            db::CatalogSort::Bump => "o.BumpTime DESC, o.PostNum DESC",
+            db::CatalogSort::Created => "o.PostNum DESC",
+            db::CatalogSort::Replies => "o.Replies DESC, o.BumpTime DESC, o.PostNum DESC",
+            db::CatalogSort::Images => "o.ImgReplies DESC, o.BumpTime DESC, o.PostNum DESC",
+            // Unlike the bump time, this counts sage replies, and threads without any
+            // replies count from their own post
+            db::CatalogSort::LastReply => {
+                r#"COALESCE((SELECT MAX(r.Time) FROM Posts r
+                                WHERE (r.BoardId, r.OrigNum, r.Approval) = (o.BoardId, o.PostNum, ?5)),
+                            p.Time) DESC, o.PostNum DESC"#
+            },
+        };
+
+        let mut query = conn.prepare(&format!(
+            r#"
+            SELECT p.BoardId, p.PostNum, p.Time, p.Ip, p.Poster, substr(p.Body, 1, ?3),
+                   p.FeatherType, p.FeatherText, p.Approval, p.OrigNum,
+                   o.Title, o.BumpTime, o.Replies, o.ImgReplies,
+                   o.Pinned, o.Archived
+
+            FROM   Originals o INNER JOIN Posts p
+                        ON (p.BoardId, p.PostNum) = (o.BoardId, o.PostNum)
 
-        for o in orig_iter {
-            originals.push(o?);
+            WHERE (o.BoardId, o.Archived) = (?1, ?2)
+              AND (?4 IS NULL
+                   OR instr(lower(COALESCE(o.Title, '')), lower(?4)) > 0
+                   OR instr(lower(p.Body), lower(?4)) > 0)
+            ORDER BY {};
+        "#,
+            order
+        ))?;
+
+        // Only the last reply sort compares against an approval
+        let approved = encode_approval(site::Approval::Approved);
+        let mut params: Vec<&dyn rusqlite::ToSql> =
+            vec![&board_id, &archived, &db::PREVIEW_BODY_CHARS, &filter];
+        if matches!(sort, db::CatalogSort::LastReply) {
+            params.push(&approved);
         }
 
+        let orig_iter = query.query_map(params.as_slice(), row_to_original)?;
+
+        let originals = orig_iter.collect::<Result<Vec<site::Original>, _>>()?;
+        let originals = with_all_files(&conn, originals)?;
+
         Ok(site::Catalog {
             board_id,
             time: util::timestamp(),
@@ -503,16 +719,14 @@ impl db::Database for Sqlite3Database {
         let mut replies_query = conn.prepare(
             r#"
             SELECT BoardId, PostNum, Time, Ip, Poster, Body,
-                   FeatherType, FeatherText, FileId, FileName, Approval, OrigNum FROM Posts
+                   FeatherType, FeatherText, Approval, OrigNum FROM Posts
                 WHERE (BoardId, OrigNum) = (?1, ?2);
         "#,
         )?;
 
         let replies_iter = replies_query.query_map((board_id, post_num), row_to_reply)?;
-        let mut replies = vec![];
-        for r in replies_iter {
-            replies.push(r?);
-        }
+        let replies = replies_iter.collect::<Result<Vec<site::Reply>, _>>()?;
+        let replies = with_all_files(&conn, replies)?;
 
         Ok(db::Thread { original, replies })
     }
@@ -527,7 +741,7 @@ impl db::Database for Sqlite3Database {
         let mut query = conn.prepare(
             r#"
                 SELECT p.BoardId, p.PostNum, p.Time, p.Ip, p.Poster, p.Body,
-                       p.FeatherType, p.FeatherText, p.FileId, p.FileName, p.Approval, p.OrigNum,
+                       p.FeatherType, p.FeatherText, p.Approval, p.OrigNum,
                        o.Title, o.BumpTime, o.Replies, o.ImgReplies,
                        o.Pinned, o.Archived
 
@@ -539,11 +753,8 @@ impl db::Database for Sqlite3Database {
         )?;
 
         let orig_iter = query.query_map((board_id, encode_approval(approval)), row_to_original)?;
-        let mut originals = vec![];
-
-        for o in orig_iter {
-            originals.push(o?);
-        }
+        let originals = orig_iter.collect::<Result<Vec<site::Original>, _>>()?;
+        let originals = with_all_files(&conn, originals)?;
 
         Ok(originals)
     }
@@ -558,17 +769,15 @@ impl db::Database for Sqlite3Database {
         let mut replies_query = conn.prepare(
             r#"
             SELECT BoardId, PostNum, Time, Ip, Poster, Body,
-                   FeatherType, FeatherText, FileId, FileName, Approval, OrigNum FROM Posts
-                WHERE (BoardId, Approval) = (?1, ?2) AND (OrigNum) != (NULL);
+                   FeatherType, FeatherText, Approval, OrigNum FROM Posts
+                WHERE (BoardId, Approval) = (?1, ?2) AND OrigNum IS NOT NULL;
         "#,
         )?;
 
         let replies_iter =
             replies_query.query_map((board_id, encode_approval(approval)), row_to_reply)?;
-        let mut replies = vec![];
-        for r in replies_iter {
-            replies.push(r?);
-        }
+        let replies = replies_iter.collect::<Result<Vec<site::Reply>, _>>()?;
+        let replies = with_all_files(&conn, replies)?;
 
         Ok(replies)
     }
@@ -582,23 +791,19 @@ impl db::Database for Sqlite3Database {
         let mut query = conn.prepare(
             r#"
             SELECT BoardId, PostNum, Time, Ip, Poster, Body,
-                   FeatherType, FeatherText, FileId, FileName, Approval, OrigNum FROM Posts
+                   FeatherType, FeatherText, Approval, OrigNum FROM Posts
                 WHERE (Ip)=(?1);
         "#,
         )?;
 
         // we use a Reply structure to fetch all posts, it won't matter when we cast to Post
         let posts_iter = query.query_map((ip,), row_to_reply)?;
-        let mut posts: Vec<Box<dyn site::Post>> = vec![];
-
-        for post in posts_iter {
-            match post {
-                Ok(p) => posts.push(Box::new(p)),
-                Err(err) => return Err(util::PlainchantErr::from(err)),
-            }
-        }
+        let posts = posts_iter.collect::<Result<Vec<site::Reply>, _>>()?;
 
-        Ok(posts)
+        Ok(with_all_files(&conn, posts)?
+            .into_iter()
+            .map(|post| Box::new(post) as Box<dyn site::Post>)
+            .collect())
     }
 
     fn get_reply(&self, board_id: u64, post_num: u64) -> Result<site::Reply, PlainchantErr> {
@@ -620,7 +825,7 @@ impl db::Database for Sqlite3Database {
         let mut query = conn.prepare(
             r#"
             SELECT BoardId, PostNum, Time, Ip, Poster, Body,
-                   FeatherType, FeatherText, FileId, FileName, Approval, OrigNum FROM Posts
+                   FeatherType, FeatherText, Approval, OrigNum FROM Posts
                 WHERE (BoardId, PostNum)=(?1, ?2);
         "#,
         )?;
@@ -628,18 +833,19 @@ impl db::Database for Sqlite3Database {
         // we use a Reply structure to fetch all posts, it won't matter when we cast to Post
         let post = query.query_row((board_id, post_num), row_to_reply)?;
 
-        Ok(Box::new(post) as Box<dyn site::Post>)
+        Ok(Box::new(with_files(&conn, post)?) as Box<dyn site::Post>)
     }
 
     fn update_post(&self, post: Box<dyn site::Post>) -> Result<(), PlainchantErr> {
-        let conn = self.pool.get()?;
+        let mut conn = self.pool.get()?;
+        let tx = conn.transaction()?;
 
         let (feather_type, feather_text) = encode_feather(post.feather());
         let approval = encode_approval(*post.approval());
 
         // Forbid updating of board_id, post_num, orig_num
 
-        conn.execute(
+        tx.execute(
             r#"
             UPDATE Posts
             SET
@@ -649,9 +855,7 @@ impl db::Database for Sqlite3Database {
                 Body = ?6,
                 FeatherType = ?7,
                 FeatherText = ?8,
-                FileId = ?9,
-                FileName = ?10,
-                Approval = ?11
+                Approval = ?9
             WHERE (BoardId, PostNum) = (?1, ?2) ;
             "#,
             (
@@ -663,12 +867,15 @@ impl db::Database for Sqlite3Database {
                 post.body(),
                 feather_type,
                 feather_text,
-                post.file_id(),
-                post.file_name(),
                 approval,
             ),
         )?;
 
+        // Any file released here is left in the rack for fsck to clear up
+        write_files(&tx, post.board_id(), post.post_num(), post.files())?;
+
+        tx.commit()?;
+
         Ok(())
     }
 
@@ -689,6 +896,23 @@ impl db::Database for Sqlite3Database {
         Ok(bans)
     }
 
+    fn get_banned_files(&self) -> Result<Vec<site::BannedFile>, PlainchantErr> {
+        let conn = self.pool.get()?;
+        let mut query = conn.prepare(
+            r#"
+            SELECT FileHash, PerceptualHash, TimeBanned FROM BannedFiles
+        "#,
+        )?;
+
+        let banned_iter = query.query_map((), row_to_banned_file)?;
+
+        let mut banned = vec![];
+        for b in banned_iter {
+            banned.push(b?);
+        }
+        Ok(banned)
+    }
+
     fn create_original(&self, mut orig: site::Original) -> Result<u64, PlainchantErr> {
         let mut conn = self.pool.get()?;
 
@@ -705,7 +929,9 @@ impl db::Database for Sqlite3Database {
         tx.execute(
             r#"
             INSERT INTO Posts
-            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, NULL, ?11);
+            (BoardId, PostNum, Time, Ip, Poster, Body, FeatherType, FeatherText,
+             OrigNum, Approval)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, NULL, ?9);
             "#,
             (
                 orig.board_id,
@@ -716,12 +942,12 @@ impl db::Database for Sqlite3Database {
                 &orig.body,
                 feather_type,
                 feather_text,
-                &orig.file_id,
-                &orig.file_name,
                 approval,
             ),
         )?;
 
+        write_files(&tx, orig.board_id, orig.post_num, &orig.files)?;
+
         tx.execute(
             r#"
             INSERT INTO Originals
@@ -753,7 +979,7 @@ impl db::Database for Sqlite3Database {
         let orig = query_original(&conn, reply.board_id, reply.orig_num)?;
 
         let new_reply_count = orig.replies + 1;
-        let new_img_reply_count = if reply.file_id.is_some() {
+        let new_img_reply_count = if !reply.files.is_empty() {
             orig.img_replies + 1
         } else {
             orig.img_replies
@@ -774,7 +1000,9 @@ impl db::Database for Sqlite3Database {
         tx.execute(
             r#"
             INSERT INTO Posts
-            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12);
+            (BoardId, PostNum, Time, Ip, Poster, Body, FeatherType, FeatherText,
+             OrigNum, Approval)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10);
             "#,
             (
                 reply.board_id,
@@ -785,13 +1013,13 @@ impl db::Database for Sqlite3Database {
                 &reply.body,
                 feather_type,
                 feather_text,
-                &reply.file_id,
-                &reply.file_name,
                 orig.post_num,
                 approval,
             ),
         )?;
 
+        write_files(&tx, reply.board_id, reply.post_num, &reply.files)?;
+
         tx.execute(
             r#"
             UPDATE Originals
@@ -811,10 +1039,34 @@ impl db::Database for Sqlite3Database {
         Ok(reply.post_num)
     }
 
-    fn delete_original(&self, board_id: u64, post_num: u64) -> Result<(), PlainchantErr> {
+    fn delete_original(&self, board_id: u64, post_num: u64) -> Result<Vec<String>, PlainchantErr> {
         let mut conn = self.pool.get()?;
         let tx = conn.transaction()?;
 
+        let file_ids = {
+            let mut query = tx.prepare(
+                r#"
+                SELECT f.FileId
+                FROM   PostFiles f INNER JOIN Posts p
+                            ON (f.BoardId, f.PostNum) = (p.BoardId, p.PostNum)
+                WHERE p.BoardId = ?1 AND (p.PostNum = ?2 OR p.OrigNum = ?2);
+                "#,
+            )?;
+
+            query
+                .query_map((board_id, post_num), |row| row.get(0))?
+                .collect::<Result<Vec<String>, _>>()?
+        };
+
+        tx.execute(
+            r#"
+            DELETE FROM PostFiles WHERE BoardId = ?1 AND PostNum IN (
+                SELECT PostNum FROM Posts WHERE BoardId = ?1 AND (PostNum = ?2 OR OrigNum = ?2)
+            );
+            "#,
+            (board_id, post_num),
+        )?;
+
         tx.execute(
             r#"
             DELETE FROM Posts WHERE (BoardId, PostNum)=(?1, ?2);
@@ -836,19 +1088,21 @@ impl db::Database for Sqlite3Database {
             (board_id, post_num),
         )?;
 
+        let released = release_files(&tx, file_ids)?;
+
         tx.commit()?;
 
-        Ok(())
+        Ok(released)
     }
 
-    fn delete_reply(&self, board_id: u64, post_num: u64) -> Result<(), PlainchantErr> {
+    fn delete_reply(&self, board_id: u64, post_num: u64) -> Result<Vec<String>, PlainchantErr> {
         let mut conn = self.pool.get()?;
 
         let reply = query_reply(&conn, board_id, post_num)?;
         let orig = query_original(&conn, board_id, reply.orig_num)?;
 
         let new_reply_count = orig.replies - 1;
-        let new_img_reply_count = if reply.file_id.is_some() {
+        let new_img_reply_count = if !reply.files.is_empty() {
             orig.img_replies - 1
         } else {
             orig.img_replies
@@ -863,6 +1117,13 @@ impl db::Database for Sqlite3Database {
             (board_id, post_num),
         )?;
 
+        tx.execute(
+            r#"
+            DELETE FROM PostFiles WHERE (BoardId, PostNum)=(?1, ?2);
+            "#,
+            (board_id, post_num),
+        )?;
+
         let new_bump_time: u64 = match tx.query_one(
             r#"
             SELECT MAX(Time) FROM Posts WHERE (BoardId, OrigNum)=(?1, ?2);
@@ -889,11 +1150,189 @@ impl db::Database for Sqlite3Database {
             ),
         )?;
 
+        let file_ids = reply.files.into_iter().map(|file| file.file_id).collect();
+        let released = release_files(&tx, file_ids)?;
+
         tx.commit()?;
 
+        Ok(released)
+    }
+
+    fn get_file_refs(&self) -> Result<Vec<db::FileRef>, PlainchantErr> {
+        let conn = self.pool.get()?;
+        let mut query = conn.prepare(
+            r#"
+            SELECT BoardId, PostNum, FileId FROM PostFiles;
+        "#,
+        )?;
+
+        let file_refs = query
+            .query_map((), |row| {
+                Ok(db::FileRef {
+                    board_id: row.get(0)?,
+                    post_num: row.get(1)?,
+                    file_id:  row.get(2)?,
+                })
+            })?
+            .collect::<Result<Vec<db::FileRef>, _>>()?;
+
+        Ok(file_refs)
+    }
+
+    fn get_reply_counts(&self) -> Result<Vec<db::ReplyCounts>, PlainchantErr> {
+        let conn = self.pool.get()?;
+        let mut query = conn.prepare(
+            r#"
+            SELECT o.BoardId, o.PostNum, o.Replies, o.ImgReplies,
+                   COUNT(p.PostNum), COUNT(f.PostNum)
+
+            FROM   Originals o LEFT JOIN Posts p
+                        ON (p.BoardId, p.OrigNum) = (o.BoardId, o.PostNum)
+                   -- Only a post's first file, so that each post is counted once
+                   LEFT JOIN PostFiles f
+                        ON (f.BoardId, f.PostNum, f.Position) = (p.BoardId, p.PostNum, 0)
+
+            GROUP BY o.BoardId, o.PostNum;
+        "#,
+        )?;
+
+        let counts = query
+            .query_map((), |row| {
+                Ok(db::ReplyCounts {
+                    board_id:    row.get(0)?,
+                    post_num:    row.get(1)?,
+                    replies:     row.get(2)?,
+                    img_replies: row.get(3)?,
+
+                    actual_replies:     row.get(4)?,
+                    actual_img_replies: row.get(5)?,
+                })
+            })?
+            .collect::<Result<Vec<db::ReplyCounts>, _>>()?;
+
+        Ok(counts)
+    }
+
+    fn get_file_ref_counts(&self) -> Result<Vec<db::FileRefCount>, PlainchantErr> {
+        let conn = self.pool.get()?;
+        let mut query = conn.prepare(
+            r#"
+            SELECT FileId, MAX(RefCount), SUM(Refs)
+
+            FROM   (SELECT FileId, RefCount, 0 AS Refs FROM Files
+                    UNION ALL
+                    SELECT FileId, 0, COUNT(*) FROM PostFiles GROUP BY FileId)
+
+            GROUP BY FileId;
+        "#,
+        )?;
+
+        let counts = query
+            .query_map((), |row| {
+                Ok(db::FileRefCount {
+                    file_id:     row.get(0)?,
+                    ref_count:   row.get(1)?,
+                    actual_refs: row.get(2)?,
+                })
+            })?
+            .collect::<Result<Vec<db::FileRefCount>, _>>()?;
+
+        Ok(counts)
+    }
+
+    fn set_file_ref_count(&self, file_id: &str, ref_count: u64) -> Result<(), PlainchantErr> {
+        let conn = self.pool.get()?;
+
+        if ref_count == 0 {
+            conn.execute(
+                r#"
+                DELETE FROM Files WHERE FileId = ?1;
+                "#,
+                (file_id,),
+            )?;
+        } else {
+            conn.execute(
+                r#"
+                INSERT INTO Files VALUES (?1, ?2)
+                    ON CONFLICT(FileId) DO UPDATE SET RefCount = ?2;
+                "#,
+                (file_id, ref_count),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn backup(&self, dest: &Path) -> Result<(), PlainchantErr> {
+        let conn = self.pool.get()?;
+        let mut dest_conn = rusqlite::Connection::open(dest)?;
+
+        // Copying every page in a single step means that concurrent writes
+        // cannot force the backup to restart, so it always completes
+        let backup = rusqlite::backup::Backup::new(&conn, &mut dest_conn)?;
+        backup.run_to_completion(i32::MAX, Duration::from_millis(100), None)?;
+
         Ok(())
     }
 
+    fn update_archive(
+        &self,
+        board_id: u64,
+        post_cap: u16,
+        archive_cap: u16,
+    ) -> Result<Vec<u64>, PlainchantErr> {
+        let mut conn = self.pool.get()?;
+        let tx = conn.transaction()?;
+
+        // Each statement walks the (BoardId, BumpTime) index and only
+        // writes to threads which need to change state
+
+        tx.execute(
+            r#"
+            UPDATE Originals SET Archived = 0
+            WHERE BoardId = ?1 AND Archived != 0 AND PostNum IN (
+                SELECT PostNum FROM Originals WHERE BoardId = ?1
+                ORDER BY BumpTime DESC, PostNum DESC
+                LIMIT ?2
+            );
+            "#,
+            (board_id, post_cap),
+        )?;
+
+        tx.execute(
+            r#"
+            UPDATE Originals SET Archived = 1
+            WHERE BoardId = ?1 AND Archived = 0 AND PostNum IN (
+                SELECT PostNum FROM Originals WHERE BoardId = ?1
+                ORDER BY BumpTime DESC, PostNum DESC
+                LIMIT ?3 OFFSET ?2
+            );
+            "#,
+            (board_id, post_cap, archive_cap),
+        )?;
+
+        let expired = {
+            let mut query = tx.prepare(
+                r#"
+                SELECT PostNum FROM Originals WHERE BoardId = ?1
+                ORDER BY BumpTime DESC, PostNum DESC
+                LIMIT -1 OFFSET ?2;
+                "#,
+            )?;
+
+            query
+                .query_map(
+                    (board_id, u32::from(post_cap) + u32::from(archive_cap)),
+                    |row| row.get(0),
+                )?
+                .collect::<Result<Vec<u64>, _>>()?
+        };
+
+        tx.commit()?;
+
+        Ok(expired)
+    }
+
     fn update_original(&self, orig: site::Original) -> Result<(), PlainchantErr> {
         let mut conn = self.pool.get()?;
         let tx = conn.transaction()?;
@@ -911,9 +1350,7 @@ impl db::Database for Sqlite3Database {
                 Body = ?6,
                 FeatherType = ?7,
                 FeatherText = ?8,
-                FileId = ?9,
-                FileName = ?10,
-                Approval = ?11
+                Approval = ?9
             WHERE (BoardId, PostNum) = (?1, ?2);
             "#,
             (
@@ -925,12 +1362,12 @@ impl db::Database for Sqlite3Database {
                 orig.body,
                 feather_type,
                 feather_text,
-                orig.file_id,
-                orig.file_name,
                 approval,
             ),
         )?;
 
+        write_files(&tx, orig.board_id, orig.post_num, &orig.files)?;
+
         tx.execute(
             r#"
             UPDATE Originals
@@ -966,7 +1403,7 @@ impl db::Database for Sqlite3Database {
         conn.execute(
             r#"
             INSERT INTO Boards
-            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7);
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8);
             "#,
             (
                 board.id,
@@ -976,6 +1413,7 @@ impl db::Database for Sqlite3Database {
                 board.bump_limit,
                 board.next_post_num,
                 board.archive_cap,
+                board.max_files,
             ),
         )?;
 
@@ -988,6 +1426,7 @@ impl db::Database for Sqlite3Database {
         let tx = conn.transaction()?;
 
         tx.execute("DELETE FROM Posts WHERE BoardId = ?1;", (board_id,))?;
+        tx.execute("DELETE FROM PostFiles WHERE BoardId = ?1;", (board_id,))?;
         tx.execute("DELETE FROM Originals WHERE BoardId = ?1;", (board_id,))?;
         tx.execute("DELETE FROM Boards WHERE BoardId = ?1;", (board_id,))?;
 
@@ -1016,4 +1455,29 @@ impl db::Database for Sqlite3Database {
         conn.execute("DELETE FROM Bans WHERE Ip = ?1;", (ip,))?;
         Ok(())
     }
+
+    fn create_banned_file(&self, banned: site::BannedFile) -> Result<(), PlainchantErr> {
+        let conn = self.pool.get()?;
+
+        conn.execute(
+            r#"
+            INSERT OR REPLACE INTO BannedFiles
+            (FileHash, PerceptualHash, TimeBanned)
+            VALUES (?1, ?2, ?3);
+            "#,
+            (
+                banned.file_hash,
+                banned.perceptual_hash.map(|hash| hash as i64),
+                banned.time_banned,
+            ),
+        )?;
+
+        Ok(())
+    }
+
+    fn delete_banned_file(&self, file_hash: &str) -> Result<(), PlainchantErr> {
+        let conn = self.pool.get()?;
+        conn.execute("DELETE FROM BannedFiles WHERE FileHash = ?1;", (file_hash,))?;
+        Ok(())
+    }
 }
@@ -0,0 +1,428 @@
+use crate::util;
+use crate::util::PlainchantErr;
+
+use rusqlite::Connection;
+
+// A single step in the evolution of the database schema
+// Migrations are applied in order, each in its own transaction, and the version of the
+// last one applied is recorded in the database's `user_version` pragma
+pub struct Migration {
+    pub version:     u32,
+    pub description: &'static str,
+    pub sql:         &'static str,
+}
+
+// Never edit a migration once it has been released - add a new one instead
+pub const MIGRATIONS: &[Migration] = &[
+    // Databases created before migrations existed are at version 0 but already have
+    // this schema, so every statement here must be idempotent
+    Migration {
+        version:     1,
+        description: "Create initial schema",
+        sql:         r#"
+            CREATE TABLE IF NOT EXISTS Site (
+                Identity    INTEGER  PRIMARY KEY,
+                Name        TEXT     NOT NULL,
+                Description TEXT     NOT NULL,
+                Contact     TEXT             ,
+                Url         TEXT
+            );
+            INSERT OR IGNORE INTO Site VALUES (
+                1,
+                'Plainchant',
+                'A lightweight and libre imageboard.',
+                NULL,
+                NULL
+            );
+            CREATE TABLE IF NOT EXISTS Bans (
+                BanId       INTEGER  PRIMARY KEY,
+                Ip          TEXT     NOT NULL,
+                TimeExpires INTEGER  NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS DomainWhitelist (
+                DomainId       INTEGER  PRIMARY KEY,
+                Domain         TEXT     NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS Boards (
+                BoardId     INTEGER  PRIMARY KEY,
+                Url         TEXT     NOT NULL,
+                Title       TEXT     NOT NULL,
+                PostCap     INTEGER  NOT NULL,
+                BumpLimit   INTEGER  NOT NULL,
+                NextPostNum INTEGER  NOT NULL,
+                ArchiveCap  INTEGER  NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS Posts (
+                BoardId     INTEGER  NUT NULL,
+                PostNum     INTEGER  NOT NULL,
+                Time        INTEGER  NOT NULL,
+                Ip          TEXT     NOT NULL,
+                Poster      TEXT             ,
+                Body        TEXT     NOT NULL,
+                FeatherType INTEGER          ,
+                FeatherText TEXT             ,
+                FileId      TEXT             ,
+                FileName    TEXT             ,
+                OrigNum     INTEGER          ,
+                Approval    INTEGER  NOT NULL,
+                PRIMARY KEY(BoardId, PostNum)
+            );
+            CREATE TABLE IF NOT EXISTS Originals (
+                BoardId     INTEGER  NUT NULL,
+                PostNum     INTEGER  NOT NULL,
+                Title       TEXT             ,
+                BumpTime    INTEGER  NOT NULL,
+                Replies     INTEGER  NOT NULL,
+                ImgReplies  INTEGER  NOT NULL,
+                Pinned      INTEGER  NOT NULL,
+                Archived    INTEGER  NOT NULL,
+                PRIMARY KEY(BoardId, PostNum)
+            );
+        "#,
+    },
+    // SQLite cannot alter column constraints, so the tables have to be rebuilt
+    Migration {
+        version:     2,
+        description: "Make BoardId NOT NULL on Posts and Originals",
+        sql:         r#"
+            CREATE TABLE Posts_New (
+                BoardId     INTEGER  NOT NULL,
+                PostNum     INTEGER  NOT NULL,
+                Time        INTEGER  NOT NULL,
+                Ip          TEXT     NOT NULL,
+                Poster      TEXT             ,
+                Body        TEXT     NOT NULL,
+                FeatherType INTEGER          ,
+                FeatherText TEXT             ,
+                FileId      TEXT             ,
+                FileName    TEXT             ,
+                OrigNum     INTEGER          ,
+                Approval    INTEGER  NOT NULL,
+                PRIMARY KEY(BoardId, PostNum)
+            );
+            INSERT INTO Posts_New SELECT * FROM Posts;
+            DROP TABLE Posts;
+            ALTER TABLE Posts_New RENAME TO Posts;
+
+            CREATE TABLE Originals_New (
+                BoardId     INTEGER  NOT NULL,
+                PostNum     INTEGER  NOT NULL,
+                Title       TEXT             ,
+                BumpTime    INTEGER  NOT NULL,
+                Replies     INTEGER  NOT NULL,
+                ImgReplies  INTEGER  NOT NULL,
+                Pinned      INTEGER  NOT NULL,
+                Archived    INTEGER  NOT NULL,
+                PRIMARY KEY(BoardId, PostNum)
+            );
+            INSERT INTO Originals_New SELECT * FROM Originals;
+            DROP TABLE Originals;
+            ALTER TABLE Originals_New RENAME TO Originals;
+        "#,
+    },
+    Migration {
+        version:     3,
+        description: "Index posts by thread, IP and approval, and threads by bump time",
+        sql:         r#"
+            CREATE INDEX IF NOT EXISTS PostsByThread   ON Posts (BoardId, OrigNum);
+            CREATE INDEX IF NOT EXISTS PostsByIp       ON Posts (Ip);
+            CREATE INDEX IF NOT EXISTS PostsByApproval ON Posts (BoardId, Approval);
+            CREATE INDEX IF NOT EXISTS OriginalsByBump ON Originals (BoardId, BumpTime);
+        "#,
+    },
+    Migration {
+        version:     4,
+        description: "Count references to each stored file",
+        sql:         r#"
+            CREATE TABLE Files (
+                FileId      TEXT     PRIMARY KEY,
+                RefCount    INTEGER  NOT NULL
+            );
+            INSERT INTO Files
+                SELECT FileId, COUNT(*) FROM Posts WHERE FileId IS NOT NULL GROUP BY FileId;
+        "#,
+    },
+    Migration {
+        version:     5,
+        description: "Record banned files",
+        sql:         r#"
+            CREATE TABLE BannedFiles (
+                FileHash        TEXT     PRIMARY KEY,
+                PerceptualHash  INTEGER          ,
+                TimeBanned      INTEGER  NOT NULL
+            );
+        "#,
+    },
+    Migration {
+        version:     6,
+        description: "Record the dimensions, size and format of each post's file",
+        sql:         r#"
+            ALTER TABLE Posts ADD COLUMN FileWidth   INTEGER;
+            ALTER TABLE Posts ADD COLUMN FileHeight  INTEGER;
+            ALTER TABLE Posts ADD COLUMN FileSize    INTEGER;
+            ALTER TABLE Posts ADD COLUMN FileFormat  TEXT;
+            ALTER TABLE Posts ADD COLUMN ThumbWidth  INTEGER;
+            ALTER TABLE Posts ADD COLUMN ThumbHeight INTEGER;
+        "#,
+    },
+    Migration {
+        version:     7,
+        description: "Record the duration of videos and animated images",
+        sql:         r#"
+            ALTER TABLE Posts ADD COLUMN FileDuration INTEGER;
+        "#,
+    },
+    Migration {
+        version:     8,
+        description: "Move files into their own table so posts can have several",
+        sql:         r#"
+            CREATE TABLE PostFiles (
+                BoardId      INTEGER  NOT NULL,
+                PostNum      INTEGER  NOT NULL,
+                Position     INTEGER  NOT NULL,
+                FileId       TEXT     NOT NULL,
+                FileName     TEXT             ,
+                FileWidth    INTEGER          ,
+                FileHeight   INTEGER          ,
+                FileSize     INTEGER          ,
+                FileFormat   TEXT             ,
+                ThumbWidth   INTEGER          ,
+                ThumbHeight  INTEGER          ,
+                FileDuration INTEGER          ,
+                PRIMARY KEY(BoardId, PostNum, Position)
+            );
+            INSERT INTO PostFiles
+                SELECT BoardId, PostNum, 0, FileId, FileName, FileWidth, FileHeight, FileSize,
+                       FileFormat, ThumbWidth, ThumbHeight, FileDuration
+                FROM Posts WHERE FileId IS NOT NULL;
+
+            CREATE TABLE Posts_New (
+                BoardId     INTEGER  NOT NULL,
+                PostNum     INTEGER  NOT NULL,
+                Time        INTEGER  NOT NULL,
+                Ip          TEXT     NOT NULL,
+                Poster      TEXT             ,
+                Body        TEXT     NOT NULL,
+                FeatherType INTEGER          ,
+                FeatherText TEXT             ,
+                OrigNum     INTEGER          ,
+                Approval    INTEGER  NOT NULL,
+                PRIMARY KEY(BoardId, PostNum)
+            );
+            INSERT INTO Posts_New
+                SELECT BoardId, PostNum, Time, Ip, Poster, Body, FeatherType, FeatherText,
+                       OrigNum, Approval
+                FROM Posts;
+            DROP TABLE Posts;
+            ALTER TABLE Posts_New RENAME TO Posts;
+
+            CREATE INDEX PostsByThread   ON Posts (BoardId, OrigNum);
+            CREATE INDEX PostsByIp       ON Posts (Ip);
+            CREATE INDEX PostsByApproval ON Posts (BoardId, Approval);
+
+            ALTER TABLE Boards ADD COLUMN MaxFiles INTEGER NOT NULL DEFAULT 1;
+        "#,
+    },
+    Migration {
+        version:     9,
+        description: "Allow files to be marked as spoilers",
+        sql:         r#"
+            ALTER TABLE PostFiles ADD COLUMN Spoiler INTEGER NOT NULL DEFAULT 0;
+        "#,
+    },
+    Migration {
+        version:     10,
+        description: "Record alt text for files",
+        sql:         r#"
+            ALTER TABLE PostFiles ADD COLUMN AltText TEXT;
+        "#,
+    },
+];
+
+pub fn latest_version() -> u32 {
+    MIGRATIONS.last().map(|m| m.version).unwrap_or(0)
+}
+
+pub fn schema_version(conn: &Connection) -> Result<u32, PlainchantErr> {
+    Ok(conn.pragma_query_value(None, "user_version", |row| row.get(0))?)
+}
+
+// Migrations not yet applied to this database, in the order they must be applied
+// A database from a newer build of Plainchant is refused rather than risk corrupting it
+pub fn pending(conn: &Connection) -> Result<Vec<&'static Migration>, PlainchantErr> {
+    let version = schema_version(conn)?;
+
+    if version > latest_version() {
+        return Err(PlainchantErr {
+            origin: util::ErrOrigin::Database,
+            code:   500,
+            msg:    format!(
+                "Database schema version {} is newer than the latest supported version {}",
+                version,
+                latest_version()
+            ),
+        });
+    }
+
+    Ok(MIGRATIONS.iter().filter(|m| m.version > version).collect())
+}
+
+pub fn migrate(conn: &mut Connection) -> Result<(), PlainchantErr> {
+    for migration in pending(conn)? {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration.sql)
+            .map_err(|err| PlainchantErr {
+                origin: util::ErrOrigin::Database,
+                code:   500,
+                msg:    format!(
+                    "Migration {} ({}) failed: {}",
+                    migration.version, migration.description, err
+                ),
+            })?;
+        tx.pragma_update(None, "user_version", migration.version)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn columns(conn: &Connection, table: &str) -> Vec<(String, bool)> {
+        let mut query = conn
+            .prepare(&format!(
+                "SELECT name, \"notnull\" FROM pragma_table_info('{}')",
+                table
+            ))
+            .unwrap();
+        query
+            .query_map((), |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    // A database from before migrations existed, which has the initial schema at version 0
+    fn baseline() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(MIGRATIONS[0].sql).unwrap();
+        conn.execute_batch(
+            r#"
+            INSERT INTO Boards VALUES (1, 'b', 'Random', 20, 100, 4, 10);
+            INSERT INTO Bans VALUES (1, '10.0.0.1', 2000000000);
+            INSERT INTO Posts VALUES
+                (1, 1, 1000, '10.0.0.2', 'anon', 'first', NULL, NULL, 'abc', 'a.png', NULL, 2),
+                (1, 2, 1010, '10.0.0.3', NULL, 'with a file', NULL, NULL, 'abc', 'b.png', 1, 2),
+                (1, 3, 1020, '10.0.0.4', NULL, 'without', NULL, NULL, NULL, NULL, 1, 1);
+            INSERT INTO Originals VALUES (1, 1, 'subject', 1020, 2, 1, 0, 0);
+        "#,
+        )
+        .unwrap();
+        conn
+    }
+
+    #[test]
+    fn migrates_baseline_databases() {
+        let mut conn = baseline();
+        assert_eq!(schema_version(&conn).unwrap(), 0);
+        assert_eq!(pending(&conn).unwrap().len(), MIGRATIONS.len());
+
+        migrate(&mut conn).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), latest_version());
+        assert!(pending(&conn).unwrap().is_empty());
+
+        // Posts and Originals were rebuilt with BoardId NOT NULL, and files moved out
+        for table in ["Posts", "Originals"] {
+            assert!(columns(&conn, table).contains(&(String::from("BoardId"), true)));
+        }
+        assert!(
+            !columns(&conn, "Posts")
+                .iter()
+                .any(|(name, _)| name == "FileId")
+        );
+
+        let posts: Vec<(u64, String, Option<u64>, u8)> = conn
+            .prepare("SELECT PostNum, Body, OrigNum, Approval FROM Posts ORDER BY PostNum")
+            .unwrap()
+            .query_map((), |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            posts,
+            vec![
+                (1, String::from("first"), None, 2),
+                (2, String::from("with a file"), Some(1), 2),
+                (3, String::from("without"), Some(1), 1),
+            ]
+        );
+
+        let files: Vec<(u64, u64, String, String, bool, Option<String>)> = conn
+            .prepare(
+                "SELECT PostNum, Position, FileId, FileName, Spoiler, AltText FROM PostFiles
+                    ORDER BY PostNum",
+            )
+            .unwrap()
+            .query_map((), |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                    row.get(5)?,
+                ))
+            })
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            files,
+            vec![
+                (
+                    1,
+                    0,
+                    String::from("abc"),
+                    String::from("a.png"),
+                    false,
+                    None
+                ),
+                (
+                    2,
+                    0,
+                    String::from("abc"),
+                    String::from("b.png"),
+                    false,
+                    None
+                ),
+            ]
+        );
+
+        let ref_count: u64 = conn
+            .query_row(
+                "SELECT RefCount FROM Files WHERE FileId = 'abc'",
+                (),
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(ref_count, 2);
+
+        let (title, replies, max_files): (String, u64, u64) = conn
+            .query_row(
+                "SELECT o.Title, o.Replies, b.MaxFiles FROM Originals o
+                    INNER JOIN Boards b ON b.BoardId = o.BoardId",
+                (),
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!((title.as_str(), replies, max_files), ("subject", 2, 1));
+
+        let bans: u64 = conn
+            .query_row("SELECT COUNT(*) FROM Bans", (), |row| row.get(0))
+            .unwrap();
+        assert_eq!(bans, 1);
+    }
+}
@@ -1,3 +1,4 @@
+use crate::assets::StaticAssets;
 use crate::util;
 use std::collections::HashMap;
 use std::fmt::Display;
@@ -64,12 +65,43 @@ impl Data {
         let cols = self.collections.as_mut().expect("Data has no collections");
         cols.insert(String::from(col), values);
     }
+
+    // A digest of everything that can affect a render, independent of map ordering
+    pub fn digest(&self) -> String {
+        let mut buf = String::new();
+
+        let mut values = self.values.iter().collect::<Vec<(&String, &String)>>();
+        values.sort();
+        for (k, v) in values {
+            buf.push_str(&format!("v{}:{}={}:{}\n", k.len(), k, v.len(), v));
+        }
+
+        if let Some(ref flags) = self.flags {
+            let mut flags = flags.iter().collect::<Vec<(&String, &bool)>>();
+            flags.sort();
+            for (k, f) in flags {
+                buf.push_str(&format!("f{}:{}={}\n", k.len(), k, f));
+            }
+        }
+
+        if let Some(ref collections) = self.collections {
+            let mut collections = collections.iter().collect::<Vec<(&String, &Vec<String>)>>();
+            collections.sort();
+            for (k, col) in collections {
+                buf.push_str(&format!("c{}:{}={}\n", k.len(), k, col.join(",")));
+            }
+        }
+
+        sha256::digest(buf)
+    }
 }
 
 #[derive(Debug)]
 pub enum Chunk {
     Fragment(String),
     Placeholder(String, Option<String>),
+    // Reference to a static asset, written {{@path}}
+    Asset(String),
     Condition(String, Option<String>),
     Control(String),
 }
@@ -124,6 +156,11 @@ impl Template {
                 '$' => match c {
                     '}' => {
                         let raw = mem::take(&mut buf);
+                        if let Some(asset) = raw.strip_prefix('@') {
+                            chunks.push(Chunk::Asset(asset.to_string()));
+                            state = '}';
+                            continue;
+                        }
                         let split = raw.split('.').collect::<Vec<&str>>();
                         match split.len() {
                             1 => chunks.push(Chunk::Placeholder(raw, None)),
@@ -180,6 +217,17 @@ impl Template {
         Ok(Template { chunks })
     }
 
+    // Resolve asset references to fingerprinted URLs, so that this happens only once
+    pub fn with_assets(mut self, assets: &StaticAssets) -> Template {
+        for chunk in self.chunks.iter_mut() {
+            if let Chunk::Asset(path) = chunk {
+                *chunk = Chunk::Fragment(assets.url(path));
+            }
+        }
+        self
+    }
+
+    #[allow(clippy::collapsible_if)]
     pub fn render(&self, data: &Data) -> String {
         let empty_str = String::from("");
         let mut buf = String::new();
@@ -229,6 +277,11 @@ impl Template {
                         }
                     }
                 },
+                Chunk::Asset(path) => {
+                    if skip.is_none() {
+                        buf.push_str(&format!("/static/{}", path));
+                    }
+                },
                 Chunk::Condition(name, obj) => {
                     if let Some(ref flags) = data.flags {
                         if let Some(ref s) = skip {
@@ -15,6 +15,8 @@ pub enum ErrOrigin {
     Actions,
     Template,
     Web,
+    Backup,
+    Media,
 }
 
 #[derive(Debug)]
@@ -38,6 +40,23 @@ pub fn timestamp() -> u64 {
         .as_secs()
 }
 
+// Run synchronous storage work (database queries, file rack I/O) on tokio's blocking
+// thread pool, so that a slow query cannot stall the async executor
+pub async fn blocking<F, T>(work: F) -> Result<T, PlainchantErr>
+where
+    F: FnOnce() -> Result<T, PlainchantErr> + Send + 'static,
+    T: Send + 'static,
+{
+    match tokio::task::spawn_blocking(work).await {
+        Ok(res) => res,
+        Err(err) => Err(PlainchantErr {
+            origin: ErrOrigin::Web,
+            code:   500,
+            msg:    format!("Blocking task failed: {}", err),
+        }),
+    }
+}
+
 macro_rules! unwrap_or_return {
     ( $test:expr, $ret:expr ) => {
         match $test {
@@ -0,0 +1,629 @@
+use crate::util::{ErrOrigin, PlainchantErr};
+
+// Just enough of the WebM (Matroska) and MP4 (ISO base media) containers is parsed to
+// check that an upload really is a video, and to find its dimensions and duration.
+// Frames are never decoded.
+
+const EBML_HEADER: u32 = 0x1A45_DFA3;
+const EBML_DOC_TYPE: u32 = 0x4282;
+const SEGMENT: u32 = 0x1853_8067;
+const SEGMENT_INFO: u32 = 0x1549_A966;
+const TIMESTAMP_SCALE: u32 = 0x2A_D7B1;
+const DURATION: u32 = 0x4489;
+const TRACKS: u32 = 0x1654_AE6B;
+const TRACK_ENTRY: u32 = 0xAE;
+const TRACK_TYPE: u32 = 0x83;
+const TRACK_VIDEO: u32 = 0xE0;
+const PIXEL_WIDTH: u32 = 0xB0;
+const PIXEL_HEIGHT: u32 = 0xBA;
+const CLUSTER: u32 = 0x1F43_B675;
+const TAGS: u32 = 0x1254_C367;
+const ATTACHMENTS: u32 = 0x1941_A469;
+const VOID: u8 = 0xEC;
+
+// Elements which may hold anything from GPS coordinates to cover art
+const WEBM_METADATA: &[u32] = &[TAGS, ATTACHMENTS];
+
+// XMP may also be stored in MP4 files in a uuid box with this ID
+const XMP_UUID: [u8; 16] = [
+    0xBE, 0x7A, 0xCF, 0xCB, 0x97, 0xA9, 0x42, 0xE8, 0x9C, 0x71, 0x99, 0x94, 0x91, 0xE3, 0xAF, 0xAC,
+];
+
+// Matroska track type for video tracks
+const VIDEO_TRACK: u64 = 1;
+// Timestamps are in nanoseconds unless the file says otherwise
+const DEFAULT_TIMESTAMP_SCALE: u64 = 1_000_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VideoFormat {
+    WebM,
+    Mp4,
+}
+
+impl VideoFormat {
+    pub fn name(&self) -> &'static str {
+        match self {
+            VideoFormat::WebM => "WEBM",
+            VideoFormat::Mp4 => "MP4",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<VideoFormat> {
+        [VideoFormat::WebM, VideoFormat::Mp4]
+            .into_iter()
+            .find(|format| format.name() == name)
+    }
+
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            VideoFormat::WebM => "video/webm",
+            VideoFormat::Mp4 => "video/mp4",
+        }
+    }
+
+    pub fn from_extension(ext: &str) -> Option<VideoFormat> {
+        match ext.to_lowercase().as_str() {
+            "webm" => Some(VideoFormat::WebM),
+            "mp4" | "m4v" => Some(VideoFormat::Mp4),
+            _ => None,
+        }
+    }
+}
+
+pub struct VideoInfo {
+    pub width:       u32,
+    pub height:      u32,
+    pub duration_ms: u64,
+}
+
+fn malformed(format: VideoFormat) -> PlainchantErr {
+    PlainchantErr {
+        origin: ErrOrigin::Media,
+        code:   415,
+        msg:    format!("Malformed {} video", format.name()),
+    }
+}
+
+// Judge the container from the first few bytes of a file
+// Matroska files are only WebM if their DocType says so, which is near the start
+pub fn guess_format(file: &[u8]) -> Option<VideoFormat> {
+    if file.get(4..8) == Some(b"ftyp") {
+        return Some(VideoFormat::Mp4);
+    }
+
+    if file.starts_with(&EBML_HEADER.to_be_bytes()) {
+        let (id, header) = read_element_header(file, 0)?;
+        if id != EBML_HEADER {
+            return None;
+        }
+        let end = header.end.min(file.len());
+        let doc_type = children(file, header.start, end)
+            .find(|(id, _)| *id == EBML_DOC_TYPE)
+            .and_then(|(_, child)| file.get(child.start..child.end))?;
+        if doc_type == b"webm" {
+            return Some(VideoFormat::WebM);
+        }
+    }
+
+    None
+}
+
+pub fn probe(file: &[u8], format: VideoFormat) -> Result<VideoInfo, PlainchantErr> {
+    let info = match format {
+        VideoFormat::WebM => probe_webm(file),
+        VideoFormat::Mp4 => probe_mp4(file),
+    };
+    info.filter(|info| info.width > 0 && info.height > 0)
+        .ok_or_else(|| malformed(format))
+}
+
+// Metadata is blanked out where it lies, rather than removed, so that the offsets which
+// index the frames stay valid. Matroska tags and attachments become void elements, and
+// MP4 user data, metadata and XMP boxes become free space.
+pub fn strip_metadata(file: &[u8], format: VideoFormat) -> Result<Vec<u8>, PlainchantErr> {
+    let mut out = file.to_vec();
+
+    match format {
+        VideoFormat::WebM => {
+            let (_, header) = read_element_header(file, 0).ok_or_else(|| malformed(format))?;
+            let (_, segment) =
+                read_element_header(file, header.end).ok_or_else(|| malformed(format))?;
+
+            for (id, span) in children(file, segment.start, segment.end.min(file.len())) {
+                if WEBM_METADATA.contains(&id) {
+                    void_element(&mut out, &span);
+                }
+            }
+        },
+        VideoFormat::Mp4 => blank_mp4_metadata(file, &mut out, 0, file.len()),
+    }
+
+    Ok(out)
+}
+
+fn void_element(out: &mut [u8], span: &Span) {
+    // The whole element is replaced, so its new size field can be as long as needed
+    let len = span.end - span.header;
+    let size_len = (len - 1).min(8);
+    let size = ((len - 1 - size_len) as u64).to_be_bytes();
+
+    out[span.header] = VOID;
+    out[span.header + 1..span.header + 1 + size_len].copy_from_slice(&size[8 - size_len..]);
+    out[span.header + 1] |= 0x80 >> (size_len - 1);
+    out[span.header + 1 + size_len..span.end].fill(0);
+}
+
+fn blank_mp4_metadata(file: &[u8], out: &mut [u8], start: usize, end: usize) {
+    for (box_type, span) in boxes(file, start, end) {
+        match &box_type {
+            b"udta" | b"meta" => (),
+            b"uuid" if file.get(span.start..span.start + 16) == Some(&XMP_UUID[..]) => (),
+            // Track metadata is held within each track
+            b"moov" | b"trak" => {
+                blank_mp4_metadata(file, out, span.start, span.end);
+                continue;
+            },
+            _ => continue,
+        }
+
+        out[span.header + 4..span.header + 8].copy_from_slice(b"free");
+        out[span.start..span.end].fill(0);
+    }
+}
+
+// WebM
+
+// An element or box, from its header to the end of its contents
+struct Span {
+    header: usize,
+    start:  usize,
+    end:    usize,
+}
+
+// EBML variable length integers mark their own length with the position of the first set bit
+fn read_vint(file: &[u8], pos: usize, keep_marker: bool) -> Option<(u64, usize)> {
+    let first = *file.get(pos)?;
+    let len = first.leading_zeros() as usize + 1;
+    if len > 8 {
+        return None;
+    }
+
+    let mut val = if keep_marker {
+        first as u64
+    } else {
+        (first as u64) & (0xFF >> len)
+    };
+    for byte in file.get(pos + 1..pos + len)? {
+        val = (val << 8) | *byte as u64;
+    }
+    Some((val, len))
+}
+
+fn read_element_header(file: &[u8], pos: usize) -> Option<(u32, Span)> {
+    let (id, id_len) = read_vint(file, pos, true)?;
+    let (size, size_len) = read_vint(file, pos + id_len, false)?;
+    let start = pos + id_len + size_len;
+
+    // A size of all ones means the element runs to the end of its parent
+    let unknown = size == (1 << (7 * size_len)) - 1;
+    let end = if unknown {
+        usize::MAX
+    } else {
+        start.checked_add(usize::try_from(size).ok()?)?
+    };
+
+    Some((
+        id as u32,
+        Span {
+            header: pos,
+            start,
+            end,
+        },
+    ))
+}
+
+// Iterate over the elements between `start` and `end`, stopping at anything malformed
+// An element whose header runs past the end of its parent is malformed
+fn children(file: &[u8], start: usize, end: usize) -> impl Iterator<Item = (u32, Span)> + '_ {
+    let mut pos = start;
+    std::iter::from_fn(move || {
+        if pos >= end {
+            return None;
+        }
+        let (id, span) = read_element_header(file, pos)?;
+        if span.start > end {
+            return None;
+        }
+        pos = span.end;
+        Some((
+            id,
+            Span {
+                end: span.end.min(end),
+                ..span
+            },
+        ))
+    })
+}
+
+fn read_uint(file: &[u8], span: &Span) -> Option<u64> {
+    let bytes = file.get(span.start..span.end)?;
+    if bytes.len() > 8 {
+        return None;
+    }
+    Some(bytes.iter().fold(0, |val, byte| (val << 8) | *byte as u64))
+}
+
+fn read_float(file: &[u8], span: &Span) -> Option<f64> {
+    let bytes = file.get(span.start..span.end)?;
+    match bytes.len() {
+        4 => Some(f32::from_be_bytes(bytes.try_into().ok()?) as f64),
+        8 => Some(f64::from_be_bytes(bytes.try_into().ok()?)),
+        _ => None,
+    }
+}
+
+fn probe_webm(file: &[u8]) -> Option<VideoInfo> {
+    let (id, header) = read_element_header(file, 0)?;
+    if id != EBML_HEADER {
+        return None;
+    }
+
+    let (id, segment) = read_element_header(file, header.end)?;
+    if id != SEGMENT {
+        return None;
+    }
+    let segment_end = segment.end.min(file.len());
+
+    let mut scale = DEFAULT_TIMESTAMP_SCALE;
+    let mut duration = None;
+    let mut dimensions = None;
+
+    for (id, span) in children(file, segment.start, segment_end) {
+        match id {
+            SEGMENT_INFO => {
+                for (id, field) in children(file, span.start, span.end) {
+                    match id {
+                        TIMESTAMP_SCALE => scale = read_uint(file, &field)?,
+                        DURATION => duration = read_float(file, &field),
+                        _ => (),
+                    }
+                }
+            },
+            TRACKS => {
+                dimensions = children(file, span.start, span.end)
+                    .filter(|(id, _)| *id == TRACK_ENTRY)
+                    .find_map(|(_, entry)| webm_video_track(file, &entry));
+            },
+            // Everything we need comes before the first cluster of frames
+            CLUSTER => break,
+            _ => (),
+        }
+    }
+
+    let (width, height) = dimensions?;
+    let duration_ms = duration
+        .filter(|duration| duration.is_finite() && *duration >= 0.0)
+        .map(|duration| (duration * scale as f64 / 1_000_000.0) as u64)
+        .unwrap_or(0);
+
+    Some(VideoInfo {
+        width,
+        height,
+        duration_ms,
+    })
+}
+
+fn webm_video_track(file: &[u8], entry: &Span) -> Option<(u32, u32)> {
+    let mut is_video = false;
+    let mut dimensions = None;
+
+    for (id, field) in children(file, entry.start, entry.end) {
+        match id {
+            TRACK_TYPE => is_video = read_uint(file, &field) == Some(VIDEO_TRACK),
+            TRACK_VIDEO => {
+                let mut width = None;
+                let mut height = None;
+                for (id, field) in children(file, field.start, field.end) {
+                    match id {
+                        PIXEL_WIDTH => width = read_uint(file, &field),
+                        PIXEL_HEIGHT => height = read_uint(file, &field),
+                        _ => (),
+                    }
+                }
+                dimensions = Some((u32::try_from(width?).ok()?, u32::try_from(height?).ok()?));
+            },
+            _ => (),
+        }
+    }
+
+    if is_video { dimensions } else { None }
+}
+
+// MP4
+
+fn be_u32(file: &[u8], pos: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(file.get(pos..pos + 4)?.try_into().ok()?))
+}
+
+fn be_u64(file: &[u8], pos: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(file.get(pos..pos + 8)?.try_into().ok()?))
+}
+
+// Iterate over the boxes between `start` and `end`, stopping at anything malformed
+fn boxes(file: &[u8], start: usize, end: usize) -> impl Iterator<Item = ([u8; 4], Span)> + '_ {
+    let mut pos = start;
+    std::iter::from_fn(move || {
+        if pos + 8 > end {
+            return None;
+        }
+        let size = be_u32(file, pos)? as u64;
+        let box_type: [u8; 4] = file.get(pos + 4..pos + 8)?.try_into().ok()?;
+
+        // A size of 1 means a 64 bit size follows, and 0 that the box runs to the end
+        let (header_len, size) = match size {
+            0 => (8, (end - pos) as u64),
+            1 => (16, be_u64(file, pos + 8)?),
+            _ => (8, size),
+        };
+        if size < header_len {
+            return None;
+        }
+
+        let box_end = pos.checked_add(usize::try_from(size).ok()?)?;
+        if pos + header_len as usize > end {
+            return None;
+        }
+        let span = Span {
+            header: pos,
+            start:  pos + header_len as usize,
+            end:    box_end.min(end),
+        };
+        pos = box_end;
+        Some((box_type, span))
+    })
+}
+
+fn child_box(file: &[u8], parent: &Span, box_type: &[u8; 4]) -> Option<Span> {
+    boxes(file, parent.start, parent.end)
+        .find(|(found, _)| found == box_type)
+        .map(|(_, span)| span)
+}
+
+fn probe_mp4(file: &[u8]) -> Option<VideoInfo> {
+    let end = file.len();
+    let (first, _) = boxes(file, 0, end).next()?;
+    if &first != b"ftyp" {
+        return None;
+    }
+
+    let moov = boxes(file, 0, end)
+        .find(|(box_type, _)| box_type == b"moov")
+        .map(|(_, span)| span)?;
+
+    // Version 1 headers have 64 bit times and durations
+    let mvhd = child_box(file, &moov, b"mvhd")?;
+    let (timescale, duration) = match file.get(mvhd.start)? {
+        0 => (
+            be_u32(file, mvhd.start + 12)?,
+            be_u32(file, mvhd.start + 16)? as u64,
+        ),
+        1 => (
+            be_u32(file, mvhd.start + 20)?,
+            be_u64(file, mvhd.start + 24)?,
+        ),
+        _ => return None,
+    };
+    let duration_ms = match timescale {
+        0 => 0,
+        _ => duration.saturating_mul(1000) / timescale as u64,
+    };
+
+    let (width, height) = boxes(file, moov.start, moov.end)
+        .filter(|(box_type, _)| box_type == b"trak")
+        .find_map(|(_, trak)| mp4_video_track(file, &trak))?;
+
+    Some(VideoInfo {
+        width,
+        height,
+        duration_ms,
+    })
+}
+
+fn mp4_video_track(file: &[u8], trak: &Span) -> Option<(u32, u32)> {
+    let mdia = child_box(file, trak, b"mdia")?;
+    let hdlr = child_box(file, &mdia, b"hdlr")?;
+    // Version and flags, then a predefined field, then the handler type
+    if file.get(hdlr.start + 8..hdlr.start + 12)? != b"vide" {
+        return None;
+    }
+
+    // The track header ends with its width and height, as 16.16 fixed point numbers
+    let tkhd = child_box(file, trak, b"tkhd")?;
+    if tkhd.end < tkhd.start + 8 {
+        return None;
+    }
+    Some((
+        be_u32(file, tkhd.end - 8)? >> 16,
+        be_u32(file, tkhd.end - 4)? >> 16,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An EBML element, with its size always written in eight bytes
+    fn element(id: u32, body: &[u8]) -> Vec<u8> {
+        let id_bytes = id.to_be_bytes();
+        let skip = id_bytes.iter().take_while(|byte| **byte == 0).count();
+        let mut out = id_bytes[skip..].to_vec();
+        out.push(0x01);
+        out.extend_from_slice(&(body.len() as u64).to_be_bytes()[1..]);
+        out.extend_from_slice(body);
+        out
+    }
+
+    fn webm(width: u8, height: u8) -> Vec<u8> {
+        webm_with(width, height, &[])
+    }
+
+    // A WebM file with `extra` at the end of its segment
+    fn webm_with(width: u8, height: u8, extra: &[u8]) -> Vec<u8> {
+        let header = element(EBML_HEADER, &element(EBML_DOC_TYPE, b"webm"));
+        let info = element(
+            SEGMENT_INFO,
+            &[
+                element(TIMESTAMP_SCALE, &[0x0F, 0x42, 0x40]),
+                element(DURATION, &2500.0f64.to_be_bytes()),
+            ]
+            .concat(),
+        );
+        let video = element(
+            TRACK_VIDEO,
+            &[
+                element(PIXEL_WIDTH, &[width]),
+                element(PIXEL_HEIGHT, &[height]),
+            ]
+            .concat(),
+        );
+        let entry = element(TRACK_ENTRY, &[element(TRACK_TYPE, &[1]), video].concat());
+        let tracks = element(TRACKS, &entry);
+        [header, element(SEGMENT, &[&info, &tracks, extra].concat())].concat()
+    }
+
+    fn mp4_box(box_type: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        [&(body.len() as u32 + 8).to_be_bytes()[..], box_type, body].concat()
+    }
+
+    fn mp4(width: u16, height: u16) -> Vec<u8> {
+        let mut mvhd = vec![0; 100];
+        mvhd[12..16].copy_from_slice(&1000u32.to_be_bytes());
+        mvhd[16..20].copy_from_slice(&3000u32.to_be_bytes());
+
+        let mut tkhd = vec![0; 84];
+        tkhd[76..78].copy_from_slice(&width.to_be_bytes());
+        tkhd[80..82].copy_from_slice(&height.to_be_bytes());
+
+        let hdlr = [&[0; 8][..], b"vide", &[0; 13]].concat();
+        let trak = mp4_box(
+            b"trak",
+            &[
+                mp4_box(b"tkhd", &tkhd),
+                mp4_box(b"mdia", &mp4_box(b"hdlr", &hdlr)),
+            ]
+            .concat(),
+        );
+        let moov = mp4_box(b"moov", &[mp4_box(b"mvhd", &mvhd), trak].concat());
+        [mp4_box(b"ftyp", b"isom\0\0\0\0"), moov].concat()
+    }
+
+    #[test]
+    fn probes_webm() {
+        let file = webm(64, 48);
+        assert_eq!(guess_format(&file), Some(VideoFormat::WebM));
+
+        let info = probe(&file, VideoFormat::WebM).unwrap();
+        assert_eq!((info.width, info.height, info.duration_ms), (64, 48, 2500));
+    }
+
+    #[test]
+    fn probes_mp4() {
+        let file = mp4(640, 360);
+        assert_eq!(guess_format(&file), Some(VideoFormat::Mp4));
+
+        let info = probe(&file, VideoFormat::Mp4).unwrap();
+        assert_eq!(
+            (info.width, info.height, info.duration_ms),
+            (640, 360, 3000)
+        );
+    }
+
+    #[test]
+    fn rejects_doc_type_past_its_parent() {
+        // The header claims two bytes, but its DocType claims four
+        let file = [
+            0x1A, 0x45, 0xDF, 0xA3, 0x81, 0x42, 0x82, 0x84, 0x77, 0x65, 0x62, 0x6D,
+        ];
+        assert_eq!(guess_format(&file), None);
+    }
+
+    #[test]
+    fn survives_truncated_files() {
+        // Cutting off padding the parser never reads is harmless, but anything else must
+        // be refused rather than misread
+        for (file, dimensions) in [(webm(64, 48), (64, 48)), (mp4(640, 360), (640, 360))] {
+            for len in 0..file.len() {
+                let truncated = &file[..len];
+                for format in [VideoFormat::WebM, VideoFormat::Mp4] {
+                    if let Ok(info) = probe(truncated, format) {
+                        assert_eq!((info.width, info.height), dimensions, "{} bytes", len);
+                    }
+                }
+                guess_format(truncated);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_overlapping_elements() {
+        // A track entry which claims to run past the end of the tracks containing it
+        let entry = element(TRACK_ENTRY, &[element(TRACK_TYPE, &[1])].concat());
+        let mut tracks = element(TRACKS, &entry);
+        let overlong = (entry.len() as u64 + 100).to_be_bytes();
+        tracks[5..12].copy_from_slice(&overlong[1..]);
+        let file = [
+            element(EBML_HEADER, &element(EBML_DOC_TYPE, b"webm")),
+            element(SEGMENT, &tracks),
+        ]
+        .concat();
+        assert!(probe(&file, VideoFormat::WebM).is_err());
+
+        // A box with a 64 bit size header, which is longer than its parent
+        let moov = [&16u32.to_be_bytes()[..], b"moov", &[0, 0, 0, 1], b"trak"].concat();
+        let file = [mp4_box(b"ftyp", b"isom\0\0\0\0"), moov].concat();
+        assert!(probe(&file, VideoFormat::Mp4).is_err());
+    }
+
+    #[test]
+    fn blanks_webm_metadata() {
+        let tags = element(TAGS, b"GPS 51.5N 0.1W");
+        let file = webm_with(64, 48, &tags);
+
+        let stripped = strip_metadata(&file, VideoFormat::WebM).unwrap();
+        assert_eq!(stripped.len(), file.len());
+        assert!(!stripped.windows(3).any(|window| window == b"GPS"));
+
+        let tags_start = file.len() - tags.len();
+        let (id, span) = read_element_header(&stripped, tags_start).unwrap();
+        assert_eq!((id, span.end), (VOID as u32, file.len()));
+
+        let info = probe(&stripped, VideoFormat::WebM).unwrap();
+        assert_eq!((info.width, info.height), (64, 48));
+    }
+
+    #[test]
+    fn blanks_mp4_metadata() {
+        let udta = mp4_box(b"udta", &mp4_box(b"\xA9xyz", b"+51.5-000.1/"));
+        let mut file = mp4(640, 360);
+        file.extend_from_slice(&udta);
+        // Grow the moov box, which ends the file, to contain the user data
+        let moov_len = (file.len() - 16) as u32;
+        file[16..20].copy_from_slice(&moov_len.to_be_bytes());
+
+        let stripped = strip_metadata(&file, VideoFormat::Mp4).unwrap();
+        assert_eq!(stripped.len(), file.len());
+        assert!(!stripped.windows(4).any(|window| window == b"+51."));
+        assert_eq!(&stripped[file.len() - udta.len() + 4..][..4], b"free");
+
+        let info = probe(&stripped, VideoFormat::Mp4).unwrap();
+        assert_eq!((info.width, info.height), (640, 360));
+    }
+
+    #[test]
+    fn rejects_boxes_smaller_than_their_header() {
+        let mut file = mp4(640, 360);
+        // The moov box follows the 16 byte ftyp box
+        file[16..20].copy_from_slice(&4u32.to_be_bytes());
+        assert!(probe(&file, VideoFormat::Mp4).is_err());
+    }
+}
@@ -0,0 +1,159 @@
+use bytes::{BufMut, Bytes, BytesMut};
+
+// Just enough of the zip format to bundle files for download. Entries are stored rather
+// than compressed, as images and videos are already compressed, and are streamed: the
+// header goes out before the data, and the checksum and sizes follow it in a descriptor,
+// so that an archive can be sent without holding any file in memory.
+
+const LOCAL_HEADER: u32 = 0x0403_4b50;
+const DATA_DESCRIPTOR: u32 = 0x0807_4b50;
+const CENTRAL_HEADER: u32 = 0x0201_4b50;
+const END_OF_CENTRAL_DIR: u32 = 0x0605_4b50;
+
+// Version 2.0, the first with folders, stored entries and descriptors, is all that is needed
+const VERSION: u16 = 20;
+// The checksum and sizes are in a descriptor after the data, and names are UTF-8
+const FLAGS: u16 = (1 << 3) | (1 << 11);
+const METHOD_STORED: u16 = 0;
+
+// Without the zip64 extensions, an archive can have at most this many entries, and must
+// be smaller than 4 GiB
+pub const MAX_ENTRIES: usize = u16::MAX as usize;
+
+pub struct ZipWriter {
+    central: BytesMut,
+    offset:  u64,
+    entries: u16,
+    current: Option<Entry>,
+}
+
+// The entry whose data is being written
+struct Entry {
+    name:   String,
+    time:   u16,
+    date:   u16,
+    offset: u64,
+    crc:    flate2::Crc,
+    len:    u64,
+}
+
+// Zip timestamps are in MS-DOS format, which starts in 1980 and counts in two seconds
+fn dos_time(timestamp: u64) -> (u16, u16) {
+    use chrono::{Datelike, Timelike};
+
+    let time = chrono::DateTime::from_timestamp(timestamp as i64, 0).unwrap_or_default();
+    if time.year() < 1980 {
+        return (0, (1 << 5) | 1);
+    }
+
+    let dos_time = (time.hour() << 11) | (time.minute() << 5) | (time.second() / 2);
+    let dos_date = (((time.year() - 1980) as u32) << 9) | (time.month() << 5) | time.day();
+    (dos_time as u16, dos_date.min(u16::MAX as u32) as u16)
+}
+
+impl ZipWriter {
+    pub fn new() -> ZipWriter {
+        ZipWriter {
+            central: BytesMut::new(),
+            offset:  0,
+            entries: 0,
+            current: None,
+        }
+    }
+
+    // The header of a new entry, whose data is then passed through `data` and written
+    // after it. Callers are responsible for keeping within the format's limits.
+    pub fn start_entry(&mut self, name: &str, modified: u64) -> Bytes {
+        let (time, date) = dos_time(modified);
+
+        let mut local = BytesMut::with_capacity(30 + name.len());
+        local.put_u32_le(LOCAL_HEADER);
+        local.put_u16_le(VERSION);
+        local.put_u16_le(FLAGS);
+        local.put_u16_le(METHOD_STORED);
+        local.put_u16_le(time);
+        local.put_u16_le(date);
+        // The checksum and sizes, which are not known yet
+        local.put_u32_le(0);
+        local.put_u32_le(0);
+        local.put_u32_le(0);
+        local.put_u16_le(name.len() as u16);
+        local.put_u16_le(0);
+        local.put_slice(name.as_bytes());
+
+        self.current = Some(Entry {
+            name: name.to_string(),
+            time,
+            date,
+            offset: self.offset,
+            crc: flate2::Crc::new(),
+            len: 0,
+        });
+        self.offset += local.len() as u64;
+        local.freeze()
+    }
+
+    // Record the next part of the current entry's data, which is written as it is
+    pub fn data(&mut self, data: &[u8]) {
+        if let Some(entry) = &mut self.current {
+            entry.crc.update(data);
+            entry.len += data.len() as u64;
+            self.offset += data.len() as u64;
+        }
+    }
+
+    // The descriptor which ends the current entry, to be written after its data
+    pub fn end_entry(&mut self) -> Bytes {
+        let Some(entry) = self.current.take() else {
+            return Bytes::new();
+        };
+
+        let mut descriptor = BytesMut::with_capacity(16);
+        descriptor.put_u32_le(DATA_DESCRIPTOR);
+        descriptor.put_u32_le(entry.crc.sum());
+        descriptor.put_u32_le(entry.len as u32);
+        descriptor.put_u32_le(entry.len as u32);
+
+        self.central.put_u32_le(CENTRAL_HEADER);
+        self.central.put_u16_le(VERSION);
+        self.central.put_u16_le(VERSION);
+        self.central.put_u16_le(FLAGS);
+        self.central.put_u16_le(METHOD_STORED);
+        self.central.put_u16_le(entry.time);
+        self.central.put_u16_le(entry.date);
+        self.central.put_u32_le(entry.crc.sum());
+        self.central.put_u32_le(entry.len as u32);
+        self.central.put_u32_le(entry.len as u32);
+        self.central.put_u16_le(entry.name.len() as u16);
+        // Extra field, comment, disk number, and internal and external attributes
+        self.central.put_u16_le(0);
+        self.central.put_u16_le(0);
+        self.central.put_u16_le(0);
+        self.central.put_u16_le(0);
+        self.central.put_u32_le(0);
+        self.central.put_u32_le(entry.offset as u32);
+        self.central.put_slice(entry.name.as_bytes());
+
+        self.offset += descriptor.len() as u64;
+        self.entries += 1;
+        descriptor.freeze()
+    }
+
+    // The central directory, which ends the archive
+    pub fn finish(self) -> Bytes {
+        let mut end = self.central;
+        let central_len = end.len() as u32;
+
+        end.put_u32_le(END_OF_CENTRAL_DIR);
+        // This disk, and the disk the central directory starts on
+        end.put_u16_le(0);
+        end.put_u16_le(0);
+        end.put_u16_le(self.entries);
+        end.put_u16_le(self.entries);
+        end.put_u32_le(central_len);
+        end.put_u32_le(self.offset as u32);
+        // Comment length
+        end.put_u16_le(0);
+        end.freeze()
+    }
+}